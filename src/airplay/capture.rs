@@ -0,0 +1,310 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use serde::Serialize;
+
+use super::{h264, workers::Workers};
+
+/// Header of `video.idx`, followed by `arrival_us: u64, len: u32` per `on_video` call.
+pub const VIDEO_INDEX_MAGIC: &[u8; 8] = b"KCAPVID1";
+/// Header of `audio.bin`, followed by `arrival_us: u64, timestamp: u32, len: u32, payload`
+/// per `on_audio` call. All integers are little endian.
+pub const AUDIO_MAGIC: &[u8; 8] = b"KCAPAUD1";
+
+/// Records scheduled but not yet written, beyond this the capture is stopped.
+const QUEUE_LEN: usize = 1024;
+
+/// Debug capture of the raw streams, see `VideoConsumerBuilder::capture`.
+///
+/// Every session gets a directory below `dir` containing
+/// - `video.h264`: the Annex-B stream exactly as received,
+/// - `video.idx`: arrival time and length of every video buffer,
+/// - `audio.bin`: length-prefixed audio payloads with their RTP timestamps,
+/// - `session.log`: one line per stream format with the stream info the sender
+///   announced, volume change and disconnect.
+///
+/// If the writer falls behind the capture stops at a record boundary and
+/// `session.log` ends with a `truncated` line, the files stay consistent.
+#[derive(Clone, Debug, Serialize)]
+pub struct CaptureOptions {
+    pub dir: PathBuf,
+    /// Capturing stops once a session wrote this many bytes.
+    pub max_session_bytes: u64,
+    /// Older session directories are removed when a new session starts.
+    pub max_sessions: usize,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("captures"),
+            max_session_bytes: 1024 * 1024 * 1024,
+            max_sessions: 10,
+        }
+    }
+}
+
+enum CaptureRecord {
+    Video(Duration, Vec<u8>),
+    Audio(Duration, u32, Vec<u8>),
+    Event(Duration, String),
+}
+
+struct ActiveCapture {
+    dir: PathBuf,
+    started: Instant,
+    /// `None` once the capture was truncated, the directory stays open until
+    /// the session ends so that it is not continued in a new one.
+    records: Option<Sender<CaptureRecord>>,
+    truncated: Arc<AtomicBool>,
+}
+
+pub(super) struct StreamCapture {
+    options: CaptureOptions,
+    /// Whether sessions are captured, see `VideoConsumer::start_recording`.
+    enabled: AtomicBool,
+    /// Whether `active` takes records, checked first so that streaming without a
+    /// capture takes no lock.
+    capturing: AtomicBool,
+    active: Mutex<Option<ActiveCapture>>,
+    /// Latest SPS and PPS, written first when capturing starts mid-session.
+    parameter_sets: Mutex<Vec<u8>>,
+    workers: Workers,
+}
+
+impl StreamCapture {
+    pub fn new(options: CaptureOptions, enabled: bool, workers: Workers) -> Self {
+        Self {
+            options,
+            enabled: AtomicBool::new(enabled),
+            capturing: AtomicBool::new(false),
+            active: Mutex::new(None),
+            parameter_sets: Mutex::new(Vec::new()),
+            workers,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables capturing, disabling closes the open session directory.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.end();
+        }
+    }
+
+    /// Directory of the session being captured.
+    pub fn dir(&self) -> Option<PathBuf> {
+        self.active
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|active| active.dir.clone())
+    }
+
+    /// Starts a new session directory unless one is already open or capturing
+    /// is disabled.
+    pub fn begin(&self) {
+        let mut active = self.active.lock().unwrap();
+        if active.is_some() || !self.is_enabled() {
+            return;
+        }
+        let dir = self
+            .options
+            .dir
+            .join(chrono::Local::now().format("%Y%m%d_%H%M%S").to_string());
+        if let Err(err) = prune_sessions(&self.options.dir, self.options.max_sessions) {
+            tracing::warn!("prune captures error {err}");
+        }
+        let writer = match CaptureWriter::create(&dir, self.options.max_session_bytes) {
+            Ok(writer) => writer,
+            Err(err) => {
+                tracing::error!("create capture {dir:?} error {err}");
+                return;
+            }
+        };
+        tracing::info!("capturing streams to {dir:?}");
+        let (records, rx) = crossbeam::channel::bounded(QUEUE_LEN);
+        let truncated = Arc::new(AtomicBool::new(false));
+        let writer_truncated = truncated.clone();
+        self.workers
+            .spawn("capture-writer", move || writer.run(rx, &writer_truncated));
+        *active = Some(ActiveCapture {
+            dir,
+            started: Instant::now(),
+            records: Some(records),
+            truncated,
+        });
+        self.capturing.store(true, Ordering::Relaxed);
+    }
+
+    /// Closes the session directory, the writer thread flushes and exits.
+    pub fn end(&self) {
+        let mut active = self.active.lock().unwrap();
+        self.capturing.store(false, Ordering::Relaxed);
+        active.take();
+    }
+
+    pub fn video(&self, data: &[u8]) {
+        if h264::first_nal_type(data) == Some(h264::NAL_SPS) {
+            *self.parameter_sets.lock().unwrap() = h264::parameter_sets(data);
+        }
+        self.send(|at| CaptureRecord::Video(at, data.to_vec()));
+    }
+
+    /// Writes the latest SPS and PPS, so a capture started mid-session decodes
+    /// from the next keyframe.
+    pub fn resume_video(&self) {
+        let parameter_sets = self.parameter_sets.lock().unwrap().clone();
+        if !parameter_sets.is_empty() {
+            self.send(|at| CaptureRecord::Video(at, parameter_sets));
+        }
+    }
+
+    pub fn audio(&self, timestamp: u32, data: &[u8]) {
+        self.send(|at| CaptureRecord::Audio(at, timestamp, data.to_vec()));
+    }
+
+    pub fn event(&self, event: String) {
+        self.send(|at| CaptureRecord::Event(at, event));
+    }
+
+    fn send(&self, record: impl FnOnce(Duration) -> CaptureRecord) {
+        if !self.capturing.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut active = self.active.lock().unwrap();
+        let Some(active) = active.as_mut() else {
+            return;
+        };
+        let Some(records) = &active.records else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = records.try_send(record(active.started.elapsed())) {
+            // 丢弃单条记录会使 video.h264 和 video.idx 错位，改为整体停止
+            tracing::warn!(
+                "capture writer is too slow, capture of {:?} stopped",
+                active.dir
+            );
+            active.truncated.store(true, Ordering::Relaxed);
+            active.records = None;
+            self.capturing.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Removes the oldest session directories so that at most `keep - 1` remain.
+fn prune_sessions(dir: &Path, keep: usize) -> std::io::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut sessions: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.join("session.log").is_file())
+        .collect();
+    sessions.sort();
+    let remove = (sessions.len() + 1).saturating_sub(keep.max(1));
+    for session in &sessions[..remove] {
+        std::fs::remove_dir_all(session)?;
+    }
+    Ok(())
+}
+
+struct CaptureWriter {
+    video: BufWriter<File>,
+    video_index: BufWriter<File>,
+    audio: BufWriter<File>,
+    events: BufWriter<File>,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl CaptureWriter {
+    fn create(dir: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let create = |name: &str| File::create(dir.join(name)).map(BufWriter::new);
+        let mut writer = Self {
+            video: create("video.h264")?,
+            video_index: create("video.idx")?,
+            audio: create("audio.bin")?,
+            events: create("session.log")?,
+            written: 0,
+            max_bytes,
+        };
+        writer.video_index.write_all(VIDEO_INDEX_MAGIC)?;
+        writer.audio.write_all(AUDIO_MAGIC)?;
+        Ok(writer)
+    }
+
+    /// Writes records until the sender is dropped, `truncated` is set before
+    /// that if records were lost.
+    fn run(mut self, rx: Receiver<CaptureRecord>, truncated: &AtomicBool) {
+        while let Ok(record) = rx.recv() {
+            if self.written >= self.max_bytes {
+                continue;
+            }
+            if let Err(err) = self.write(record) {
+                tracing::error!("write capture error {err}");
+                return;
+            }
+            if self.written >= self.max_bytes {
+                tracing::warn!("capture reached {} bytes, stopped", self.max_bytes);
+                let _ = writeln!(self.events, "size limit reached, capture stopped");
+            }
+        }
+        if truncated.load(Ordering::Relaxed) {
+            let _ = writeln!(self.events, "truncated, the writer fell behind");
+        }
+        for file in [
+            &mut self.video,
+            &mut self.video_index,
+            &mut self.audio,
+            &mut self.events,
+        ] {
+            if let Err(err) = file.flush() {
+                tracing::error!("flush capture error {err}");
+            }
+        }
+    }
+
+    fn write(&mut self, record: CaptureRecord) -> std::io::Result<()> {
+        match record {
+            CaptureRecord::Video(at, data) => {
+                self.video.write_all(&data)?;
+                self.video_index
+                    .write_all(&(at.as_micros() as u64).to_le_bytes())?;
+                self.video_index
+                    .write_all(&(data.len() as u32).to_le_bytes())?;
+                self.written += data.len() as u64 + 12;
+            }
+            CaptureRecord::Audio(at, timestamp, data) => {
+                self.audio
+                    .write_all(&(at.as_micros() as u64).to_le_bytes())?;
+                self.audio.write_all(&timestamp.to_le_bytes())?;
+                self.audio.write_all(&(data.len() as u32).to_le_bytes())?;
+                self.audio.write_all(&data)?;
+                self.written += data.len() as u64 + 16;
+            }
+            CaptureRecord::Event(at, event) => {
+                let line = format!("{} {event}\n", at.as_micros());
+                self.events.write_all(line.as_bytes())?;
+                // 格式记录较少，立即写入便于崩溃后排查
+                self.events.flush()?;
+                self.written += line.len() as u64;
+            }
+        }
+        Ok(())
+    }
+}
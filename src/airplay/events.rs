@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    mem::Discriminant,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use super::{
+    hooks::{HookEvent, Hooks},
+    stream::{AudioStreamFormat, VideoStreamFormat},
+};
+
+/// Identifies one casting session, from the first stream format to the last
+/// disconnect. Ids start at 1 and increase for every session.
+pub type SessionId = u64;
+
+/// Events buffered for slow subscribers, older ones are skipped (`RecvError::Lagged`).
+const EVENT_CAPACITY: usize = 256;
+
+/// The error hook runs at most once per session, failure kind and media in this
+/// interval, a decoder reports an error for every broken packet.
+const ERROR_HOOK_INTERVAL: Duration = Duration::from_secs(10);
+
+type ErrorKey = (SessionId, Discriminant<SessionEventKind>, MediaKind);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Video,
+    Audio,
+}
+
+impl MediaKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MediaKind::Video => "video",
+            MediaKind::Audio => "audio",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionEventKind {
+    /// A sender started streaming video or audio.
+    Started,
+    VideoFormat(VideoStreamFormat),
+    AudioFormat(AudioStreamFormat),
+    /// Volume from -30.0 to 0.0, -144.0 is mute. Sent by the sender or set with
+    /// `VideoConsumer::set_volume` and `set_muted`.
+    VolumeChanged(f32),
+    /// The decoded picture size, sent for the first frame and on every change.
+    ResolutionChanged {
+        width: u32,
+        height: u32,
+    },
+    DecoderError {
+        media: MediaKind,
+        message: String,
+    },
+    /// No audio output could be opened, the session plays video only.
+    AudioUnavailable {
+        message: String,
+    },
+    /// A pipeline thread failed or panicked. It is rebuilt from the last stream
+    /// format if `restart`, otherwise it stays down until the next format.
+    PipelineFailed {
+        media: MediaKind,
+        message: String,
+        restart: bool,
+    },
+    /// The video window failed or panicked, decoding goes on. It is reopened if
+    /// `restart`, otherwise decoded frames are dropped until the next format.
+    RendererFailed {
+        message: String,
+        restart: bool,
+    },
+    VideoDisconnected,
+    AudioDisconnected,
+    /// Both streams disconnected.
+    Ended,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionEvent {
+    pub session: SessionId,
+    pub kind: SessionEventKind,
+}
+
+/// Publishes session events, shared by the consumer and its pipeline threads.
+#[derive(Clone)]
+pub(crate) struct SessionEvents {
+    tx: broadcast::Sender<SessionEvent>,
+    session: Arc<AtomicU64>,
+    hooks: Arc<Hooks>,
+    /// When the error hook last ran for a failure.
+    error_hooks: Arc<Mutex<HashMap<ErrorKey, Instant>>>,
+}
+
+impl SessionEvents {
+    /// Failures emitted here run the `error` command of `hooks`.
+    pub fn new(hooks: Arc<Hooks>) -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_CAPACITY).0,
+            session: Arc::new(AtomicU64::new(0)),
+            hooks,
+            error_hooks: Arc::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.tx.subscribe()
+    }
+
+    /// The running or last session, `0` before the first one.
+    pub fn session(&self) -> SessionId {
+        self.session.load(Ordering::Relaxed)
+    }
+
+    /// Starts a new session and announces it.
+    pub fn begin(&self) -> SessionId {
+        let session = self.session.fetch_add(1, Ordering::Relaxed) + 1;
+        self.emit(SessionEventKind::Started);
+        session
+    }
+
+    /// Sends `kind` for the current session, nothing happens without subscribers.
+    /// Decoder, pipeline and audio device failures also run the error hook, at
+    /// most once per kind and media every `ERROR_HOOK_INTERVAL`.
+    pub fn emit(&self, kind: SessionEventKind) {
+        self.emit_for(self.session(), kind);
+    }
+
+    /// Sends `kind` for `session`. Pipeline threads take the id when they start,
+    /// their late events must not count for the session that followed.
+    pub fn emit_for(&self, session: SessionId, kind: SessionEventKind) {
+        if let Some(event) = HookEvent::failure(&kind) {
+            if self.error_hook_due(session, &kind) {
+                self.hooks.run(session, event);
+            }
+        }
+        let _ = self.tx.send(SessionEvent { session, kind });
+    }
+
+    /// Whether the error hook may run for the failure `kind`, see `ERROR_HOOK_INTERVAL`.
+    fn error_hook_due(&self, session: SessionId, kind: &SessionEventKind) -> bool {
+        let media = match kind {
+            SessionEventKind::DecoderError { media, .. }
+            | SessionEventKind::PipelineFailed { media, .. } => *media,
+            SessionEventKind::RendererFailed { .. } => MediaKind::Video,
+            SessionEventKind::AudioUnavailable { .. } => MediaKind::Audio,
+            _ => return true,
+        };
+        let mut last = self.error_hooks.lock().unwrap();
+        last.retain(|_, at| at.elapsed() < ERROR_HOOK_INTERVAL);
+        let key = (session, std::mem::discriminant(kind), media);
+        if last.contains_key(&key) {
+            tracing::debug!("{media:?} 错误钩子 {ERROR_HOOK_INTERVAL:?} 内已执行过，跳过");
+            return false;
+        }
+        last.insert(key, Instant::now());
+        true
+    }
+}
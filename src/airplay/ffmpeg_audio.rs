@@ -1,4 +1,3 @@
-use airplay2_protocol::airplay::{lib::audio_stream_info::AudioFormat, server::AudioPacket};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     BufferSize, Device, Sample, SampleRate, Stream, SupportedStreamConfig,
@@ -10,9 +9,20 @@ use ffmpeg::{
     format, ChannelLayout, Packet,
 };
 use ffmpeg_next::{self as ffmpeg, software::resampling};
-use std::sync::{atomic::AtomicU64, Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
 
-use crate::{audio::sample_rate::SampleRateConverter, ffp::ff_audio_codec_par};
+use super::{
+    events::{MediaKind, SessionEventKind, SessionEvents, SessionId},
+    sink::AudioSink,
+    stats::PipelineStats,
+    stream::{AudioCodec, AudioStreamFormat},
+    supervisor::{self, Pipeline},
+    workers::Workers,
+};
+use crate::{audio::sample_rate::SampleRateConverter, ffp::ff_audio_codec_par, Error, Result};
 
 type PcmSample = i16;
 
@@ -95,214 +105,464 @@ impl<T: Default + Copy, const BUFFER_LEN: usize> RingBuffer<T, BUFFER_LEN> {
     }
 }
 
-type SharedPcmBuffer = Arc<Mutex<RingBuffer<PcmSample, 65536>>>;
+const PCM_BUFFER_LEN: usize = 65536;
+
+type SharedPcmBuffer = Arc<Mutex<RingBuffer<PcmSample, PCM_BUFFER_LEN>>>;
 
 struct AudioCpal {
     device: Device,
     config: SupportedStreamConfig,
     shared_buffer: SharedPcmBuffer,
+    stats: Arc<PipelineStats>,
+}
+
+/// The default output device and its config with the highest sample rate.
+fn default_output() -> Result<(Device, SupportedStreamConfig)> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or_else(|| Error::Device("no default output device".to_string()))?;
+    let mut supported_configs_range = device.supported_output_configs().map_err(Error::device)?;
+    let config = supported_configs_range
+        .next()
+        .ok_or_else(|| Error::Device("output device has no supported config".to_string()))?
+        .with_max_sample_rate();
+    Ok((device, config))
 }
 
 impl AudioCpal {
-    fn new(buffer: SharedPcmBuffer) -> Self {
-        let host = cpal::default_host();
-        let device = host.default_output_device().unwrap();
-        let mut supported_configs_range = device.supported_output_configs().unwrap();
-        let config = supported_configs_range
-            .next()
-            .unwrap()
-            .with_max_sample_rate();
+    fn new(
+        (device, config): (Device, SupportedStreamConfig),
+        buffer: SharedPcmBuffer,
+        stats: Arc<PipelineStats>,
+    ) -> Self {
         Self {
             device,
             config,
             shared_buffer: buffer, // channel: crossbeam::channel::bounded(32),
+            stats,
         }
     }
 }
 
 impl AudioCpal {
-    pub fn play(&self) -> anyhow::Result<Stream> {
+    pub fn play(&self) -> Result<Stream> {
         let ring_buf = self.shared_buffer.clone();
+        let stats = self.stats.clone();
         let mut config = self.config.config();
         config.buffer_size = BufferSize::Fixed(512);
-        let stream = self.device.build_output_stream(
-            &config,
-            move |data: &mut [PcmSample], _info| {
-                let mut buf = ring_buf.lock().unwrap();
-                let filled = buf.pop_slice(data);
-                data[filled..].fill(Sample::EQUILIBRIUM);
-            },
-            |err| {
-                tracing::error!("stream error {err:?}");
-            },
-            None,
-        )?;
-        stream.play()?;
+        let stream = self
+            .device
+            .build_output_stream(
+                &config,
+                move |data: &mut [PcmSample], _info| {
+                    let mut buf = ring_buf.lock().unwrap();
+                    let filled = buf.pop_slice(data);
+                    if filled < data.len() {
+                        PipelineStats::add(&stats.audio_underruns, 1);
+                    }
+                    data[filled..].fill(Sample::EQUILIBRIUM);
+                },
+                |err| {
+                    tracing::error!("stream error {err:?}");
+                },
+                None,
+            )
+            .map_err(Error::device)?;
+        stream.play().map_err(Error::device)?;
         Ok(stream)
     }
 }
 
+/// Codec id and extradata needed to decode (or mux) an AirPlay audio format.
+#[derive(Clone, Debug)]
+pub(super) struct AudioCodecConfig {
+    pub codec_id: Id,
+    pub codec_data: Vec<u8>,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+impl AudioCodecConfig {
+    pub fn new(format: &AudioStreamFormat) -> Self {
+        let (codec_id, codec_data) = match format.codec {
+            AudioCodec::Alac => (Id::ALAC, alac_cookie(format)),
+            // codec_data from MPEG v4 ISO 14996-3 Section 1.6.2.1: AAC_ELD 44100/2  spf = 480
+            AudioCodec::AacEld => (Id::AAC, hex_to_buf("f8e85000")),
+            // codec_data from MPEG v4 ISO 14996-3 Section 1.6.2.1:  AAC-LC 44100/2 spf = 1024
+            AudioCodec::AacLc => (Id::AAC, hex_to_buf("1210")),
+        };
+        Self {
+            codec_id,
+            codec_data,
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+        }
+    }
+
+    pub fn parameters(&self) -> Parameters {
+        unsafe {
+            Parameters::wrap(
+                ff_audio_codec_par(
+                    self.codec_id.into(),
+                    self.codec_data.as_ptr(),
+                    self.codec_data.len(),
+                    self.sample_rate as i32,
+                    self.channels as i32,
+                ),
+                None,
+            )
+        }
+    }
+}
+
 pub(super) struct FfMpegAudio {
     audio_channel: (Sender<AudioFrame>, Receiver<AudioFrame>),
     samples_per_frame: AtomicU64,
+    stats: Arc<PipelineStats>,
+    sink: Option<Arc<dyn AudioSink>>,
+    events: SessionEvents,
+    workers: Workers,
+    /// Whether an audio thread takes packets, see `start` and `stop`.
+    running: AtomicBool,
 }
 
-impl Default for FfMpegAudio {
-    fn default() -> Self {
+impl FfMpegAudio {
+    pub fn new(
+        stats: Arc<PipelineStats>,
+        sink: Option<Arc<dyn AudioSink>>,
+        events: SessionEvents,
+        workers: Workers,
+    ) -> Self {
         Self {
             samples_per_frame: 0.into(),
             audio_channel: crossbeam::channel::unbounded(),
+            stats,
+            sink,
+            events,
+            workers,
+            running: AtomicBool::new(false),
         }
     }
-}
 
-impl FfMpegAudio {
     pub fn set_samples_per_frame(&self, samples_per_frame: u64) {
         self.samples_per_frame
-            .store(samples_per_frame, std::sync::atomic::Ordering::Relaxed);
+            .store(samples_per_frame, Ordering::Relaxed);
     }
 
-    pub fn start(&self, audio_format: AudioFormat) -> anyhow::Result<()> {
-        let (sample_rate, channels) = audio_format.rate_channel();
-        let (codec_id, codec_data) = match audio_format {
-            // codec_data is ALAC magic cookie:  44100/16/2 spf = 352
-            AudioFormat::Alac44100_16_2 => (
-                Id::ALAC,
-                "00000024616c616300000000000001600010280a0e0200ff00000000000000000000ac44",
-            ),
-            // codec_data from MPEG v4 ISO 14996-3 Section 1.6.2.1: AAC_ELD 44100/2  spf = 480
-            AudioFormat::AacEld44100_2 => (Id::AAC, "f8e85000"),
-            // codec_data from MPEG v4 ISO 14996-3 Section 1.6.2.1:  AAC-LC 44100/2 spf = 1024
-            _ => (Id::AAC, "1210"),
-        };
-        let codec = ffmpeg::codec::decoder::find(codec_id).unwrap();
-        let mut ctx = ffmpeg::decoder::new();
-        let codec_data = hex_to_buf(codec_data);
-        let par = unsafe {
-            Parameters::wrap(
-                ff_audio_codec_par(
-                    codec_id.into(),
-                    codec_data.as_ptr(),
-                    codec_data.len(),
-                    sample_rate as i32,
-                    channels as i32,
-                ),
-                None,
-            )
+    /// Starts an audio thread for a new stream. Fails with `Error::Device` if
+    /// there is no output device or the sink refused it, the stream is then dropped.
+    pub fn start(&self, config: &AudioCodecConfig) -> Result<()> {
+        // 先打开一次解码器和输出设备，失败时直接报错，重建时再按同一格式打开
+        let decoder = open_decoder(config)?;
+        let output = match &self.sink {
+            Some(sink) => {
+                sink.on_start()?;
+                None
+            }
+            None => Some(default_output()?),
         };
-        ctx.set_parameters(par)?;
-        let decoder = ctx.open_as(codec)?.audio()?;
-        self.play_audio(decoder);
-        Ok(())
-    }
-
-    pub fn stop(&self) {
-        self.audio_channel.0.send(AudioFrame::End).unwrap();
-    }
-
-    fn play_audio(&self, mut decoder: Audio) {
+        self.stats.set_audio_format(format!(
+            "{:?} {}HZ {}CH",
+            config.codec_id, config.sample_rate, config.channels
+        ));
         let rx = self.audio_channel.1.clone();
-        let decoder_rate = decoder.rate();
-        let (max_len, _min_len) = if decoder.codec().unwrap().id() == Id::ALAC {
-            (decoder_rate as usize / 3, decoder_rate as usize / 6)
-        } else {
-            (decoder_rate as usize / 6, decoder_rate as usize / 12)
-        };
-        std::thread::spawn(move || {
-            let shared_buffer = Arc::new(Mutex::new(RingBuffer::new()));
-            let audio_cpal = AudioCpal::new(shared_buffer.clone());
-            let sample_rate = audio_cpal.config.sample_rate().0;
-            let channels = audio_cpal.config.channels() as u32;
+        let stats = self.stats.clone();
+        let events = self.events.clone();
+        let session = events.session();
+        let config = config.clone();
+        let sink = self.sink.clone();
+        self.workers.spawn("audio", move || {
+            let output = if sink.is_some() { "sink" } else { "cpal" };
+            let _span = tracing::info_span!("audio_decoder", output).entered();
+            let mut decoder = Some(decoder);
+            let mut output = output;
             let mut volume = 0.5;
-            if let Ok(_stream) = audio_cpal.play() {
-                let mut audio = ffmpeg::frame::Audio::empty();
-                let mut audio_convert_frame = ffmpeg::frame::Audio::empty();
-                let mut rate = decoder_rate;
-                let max_rate = decoder_rate + 604;
-                let mut sample_convert = resampling::Context::get(
-                    decoder.format(),
-                    decoder.channel_layout(),
-                    decoder_rate,
-                    format::Sample::I16(format::sample::Type::Packed),
-                    ChannelLayout::default(channels as i32),
-                    decoder_rate,
-                )
-                .unwrap();
-                while let Ok(audio_frame) = rx.recv() {
-                    match audio_frame {
-                        AudioFrame::Audio(packet, pts) => {
-                            match decoder.send_packet(&packet) {
-                                Ok(_) => {
-                                    if decoder.receive_frame(&mut audio).is_err() {
-                                        continue;
-                                    }
-                                }
-                                Err(err) => {
-                                    tracing::error!("audio send packet error! {:?}", err);
-                                    continue;
-                                }
+            let supervised = supervisor::supervise(
+                Pipeline::Decoder(MediaKind::Audio),
+                Some(session),
+                &stats,
+                &events,
+                || {
+                    let decoder = match decoder.take() {
+                        Some(decoder) => decoder,
+                        None => open_decoder(&config)?,
+                    };
+                    match &sink {
+                        Some(sink) => decode_to_sink(
+                            decoder,
+                            &rx,
+                            &stats,
+                            &events,
+                            session,
+                            sink,
+                            &mut volume,
+                        ),
+                        None => {
+                            let output = match output.take() {
+                                Some(output) => output,
+                                None => default_output()?,
                             };
-                            sample_convert
-                                .run(&audio, &mut audio_convert_frame)
-                                .unwrap();
-                            audio_convert_frame.set_pts(Some(pts as i64));
-                            let buffer_len = shared_buffer.lock().unwrap().len();
-                            if buffer_len > max_len {
-                                if rate < max_rate {
-                                    rate += channels;
-                                    // tracing::info!("采样率提高 {}", rate);
-                                }
-                            } else if rate > decoder_rate {
-                                rate -= channels;
-                                // tracing::info!("采样率降低 {}", rate);
-                            }
-                            audio_convert_frame.set_rate(rate);
-                            let pcm_samples =
-                                audio_convert_frame.data(0).chunks_exact(2).map(|buf| {
-                                    (PcmSample::from_le_bytes(buf.try_into().unwrap()) as f32
-                                        * volume) as PcmSample
-                                });
-                            let convert = SampleRateConverter::new(
-                                pcm_samples,
-                                SampleRate(audio_convert_frame.rate()),
-                                SampleRate(sample_rate),
-                                channels as u16,
-                            );
-                            let mut buffer = shared_buffer.lock().unwrap();
-                            for v in convert {
-                                if buffer.is_full() {
-                                    tracing::warn!("超出缓冲区大小..");
-                                    break;
-                                }
-                                buffer.push(v);
-                            }
-                        }
-                        AudioFrame::End => {
-                            break;
-                        }
-                        AudioFrame::Volume(vol) => {
-                            volume = vol;
+                            play_audio(decoder, output, &rx, &stats, &events, session, &mut volume)
                         }
                     }
+                },
+            );
+            if !supervised {
+                while let Ok(frame) = rx.recv() {
+                    if matches!(frame, AudioFrame::End) {
+                        break;
+                    }
                 }
-                while rx.try_recv().is_ok() {}
             }
-            tracing::info!("Stop Cpal Audio...");
+            match &sink {
+                Some(sink) => sink.on_end(),
+                None => tracing::info!("Stop Cpal Audio..."),
+            }
         });
+        self.running.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if self.running.swap(false, Ordering::Relaxed) {
+            let _ = self.audio_channel.0.send(AudioFrame::End);
+        }
+    }
+
+    /// Queues a packet for the decoder, dropped if no audio thread runs.
+    pub fn push_buffer(&self, timestamp: u32, payload: &[u8]) -> Result<()> {
+        if !self.running.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let packet = Packet::copy(payload);
+        self.send(AudioFrame::Audio(packet, timestamp))
     }
 
-    pub fn push_buffer(&self, buf: &AudioPacket) -> anyhow::Result<()> {
-        let packet = Packet::copy(buf.audio_buf());
+    /// Sets the gain of the running audio thread, new threads start at the default.
+    pub fn set_volume(&self, vol: f32) -> Result<()> {
+        if !self.running.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.send(AudioFrame::Volume(vol))
+    }
+
+    fn send(&self, frame: AudioFrame) -> Result<()> {
         self.audio_channel
             .0
-            .send(AudioFrame::Audio(packet, buf.timestamp()))?;
-        Ok(())
+            .send(frame)
+            .map_err(|_| Error::ChannelClosed(MediaKind::Audio))
     }
+}
 
-    pub fn set_volume(&self, vol: f32) -> anyhow::Result<()> {
-        self.audio_channel.0.send(AudioFrame::Volume(vol))?;
-        Ok(())
+fn open_decoder(config: &AudioCodecConfig) -> Result<Audio> {
+    let codec = ffmpeg::codec::decoder::find(config.codec_id)
+        .ok_or(Error::DecoderInit(ffmpeg::Error::DecoderNotFound))?;
+    let mut ctx = ffmpeg::decoder::new();
+    ctx.set_parameters(config.parameters())
+        .map_err(Error::DecoderInit)?;
+    ctx.open_as(codec)
+        .and_then(|decoder| decoder.audio())
+        .map_err(Error::DecoderInit)
+}
+
+/// Decodes into the default output device until `AudioFrame::End`.
+fn play_audio(
+    mut decoder: Audio,
+    output: (Device, SupportedStreamConfig),
+    rx: &Receiver<AudioFrame>,
+    stats: &Arc<PipelineStats>,
+    events: &SessionEvents,
+    session: SessionId,
+    volume: &mut f32,
+) -> Result<()> {
+    let decoder_rate = decoder.rate();
+    let (max_len, _min_len) = if decoder.id() == Id::ALAC {
+        (decoder_rate as usize / 3, decoder_rate as usize / 6)
+    } else {
+        (decoder_rate as usize / 6, decoder_rate as usize / 12)
+    };
+    let shared_buffer = Arc::new(Mutex::new(RingBuffer::new()));
+    let audio_cpal = AudioCpal::new(output, shared_buffer.clone(), stats.clone());
+    let sample_rate = audio_cpal.config.sample_rate().0;
+    let channels = audio_cpal.config.channels() as u32;
+    stats
+        .ring_capacity
+        .store(PCM_BUFFER_LEN - 1, Ordering::Relaxed);
+    let _stream = audio_cpal.play()?;
+    let mut audio = ffmpeg::frame::Audio::empty();
+    let mut audio_convert_frame = ffmpeg::frame::Audio::empty();
+    let mut rate = decoder_rate;
+    let max_rate = decoder_rate + 604;
+    let mut sample_convert = resampling::Context::get(
+        decoder.format(),
+        decoder.channel_layout(),
+        decoder_rate,
+        format::Sample::I16(format::sample::Type::Packed),
+        ChannelLayout::default(channels as i32),
+        decoder_rate,
+    )
+    .map_err(Error::DecoderInit)?;
+    while let Ok(audio_frame) = rx.recv() {
+        match audio_frame {
+            AudioFrame::Audio(packet, pts) => {
+                match decoder.send_packet(&packet) {
+                    Ok(_) => {
+                        if decoder.receive_frame(&mut audio).is_err() {
+                            continue;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!("audio send packet error! {:?}", err);
+                        decoder_error(stats, events, session, err);
+                        continue;
+                    }
+                };
+                sample_convert
+                    .run(&audio, &mut audio_convert_frame)
+                    .map_err(Error::Decode)?;
+                audio_convert_frame.set_pts(Some(pts as i64));
+                let buffer_len = shared_buffer.lock().unwrap().len();
+                if buffer_len > max_len {
+                    if rate < max_rate {
+                        rate += channels;
+                        // tracing::info!("采样率提高 {}", rate);
+                    }
+                } else if rate > decoder_rate {
+                    rate -= channels;
+                    // tracing::info!("采样率降低 {}", rate);
+                }
+                audio_convert_frame.set_rate(rate);
+                let pcm_samples = audio_convert_frame.data(0).chunks_exact(2).map(|buf| {
+                    (PcmSample::from_le_bytes(buf.try_into().unwrap()) as f32 * *volume)
+                        as PcmSample
+                });
+                let convert = SampleRateConverter::new(
+                    pcm_samples,
+                    SampleRate(audio_convert_frame.rate()),
+                    SampleRate(sample_rate),
+                    channels as u16,
+                );
+                let mut buffer = shared_buffer.lock().unwrap();
+                for v in convert {
+                    if buffer.is_full() {
+                        tracing::warn!("超出缓冲区大小..");
+                        PipelineStats::add(&stats.audio_overruns, 1);
+                        break;
+                    }
+                    buffer.push(v);
+                }
+                let buffer_len = buffer.len();
+                drop(buffer);
+                stats.ring_fill.store(buffer_len, Ordering::Relaxed);
+                stats.output_rate.store(rate, Ordering::Relaxed);
+                stats.audio_buffered_us.store(
+                    buffer_len as u64 * 1_000_000 / (sample_rate * channels) as u64,
+                    Ordering::Relaxed,
+                );
+            }
+            AudioFrame::End => {
+                break;
+            }
+            AudioFrame::Volume(vol) => {
+                *volume = vol;
+            }
+        }
+    }
+    while rx.try_recv().is_ok() {}
+    Ok(())
+}
+
+/// Decodes into `sink` at the stream sample rate, without an output device.
+fn decode_to_sink(
+    mut decoder: Audio,
+    rx: &Receiver<AudioFrame>,
+    stats: &PipelineStats,
+    events: &SessionEvents,
+    session: SessionId,
+    sink: &Arc<dyn AudioSink>,
+    volume: &mut f32,
+) -> Result<()> {
+    let channels = decoder.channels();
+    let mut sample_convert = resampling::Context::get(
+        decoder.format(),
+        decoder.channel_layout(),
+        decoder.rate(),
+        format::Sample::I16(format::sample::Type::Packed),
+        ChannelLayout::default(channels as i32),
+        decoder.rate(),
+    )
+    .map_err(Error::DecoderInit)?;
+    let mut audio = ffmpeg::frame::Audio::empty();
+    let mut audio_convert_frame = ffmpeg::frame::Audio::empty();
+    let mut pcm = Vec::new();
+    while let Ok(audio_frame) = rx.recv() {
+        match audio_frame {
+            AudioFrame::Audio(packet, _) => {
+                if let Err(err) = decoder.send_packet(&packet) {
+                    tracing::error!("audio send packet error! {:?}", err);
+                    decoder_error(stats, events, session, err);
+                    continue;
+                }
+                while decoder.receive_frame(&mut audio).is_ok() {
+                    if let Err(err) = sample_convert.run(&audio, &mut audio_convert_frame) {
+                        tracing::error!("audio convert error! {:?}", err);
+                        continue;
+                    }
+                    let len = audio_convert_frame.samples() * channels as usize;
+                    pcm.clear();
+                    pcm.extend(
+                        audio_convert_frame.data(0)[..len * 2]
+                            .chunks_exact(2)
+                            .map(|buf| {
+                                (PcmSample::from_le_bytes(buf.try_into().unwrap()) as f32 * *volume)
+                                    as PcmSample
+                            }),
+                    );
+                    sink.on_pcm(&pcm, decoder.rate(), channels);
+                }
+            }
+            AudioFrame::Volume(vol) => {
+                *volume = vol;
+            }
+            AudioFrame::End => {
+                break;
+            }
+        }
     }
+    Ok(())
+}
+
+/// ALAC magic cookie, AirPlay senders use 44100/16/2 spf = 352:
+/// `00000024616c616300000000000001600010280a0e0200ff00000000000000000000ac44`
+fn alac_cookie(format: &AudioStreamFormat) -> Vec<u8> {
+    let mut cookie = Vec::with_capacity(36);
+    cookie.extend_from_slice(&36u32.to_be_bytes());
+    cookie.extend_from_slice(b"alac");
+    cookie.extend_from_slice(&0u32.to_be_bytes());
+    cookie.extend_from_slice(&(format.samples_per_frame as u32).to_be_bytes());
+    // compatible version, bit depth, pb, mb, kb, channels
+    cookie.extend_from_slice(&[0, 16, 40, 10, 14, format.channels as u8]);
+    // max run, max frame bytes, average bit rate
+    cookie.extend_from_slice(&[0x00, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+    cookie.extend_from_slice(&format.sample_rate.to_be_bytes());
+    cookie
+}
+
+fn decoder_error(
+    stats: &PipelineStats,
+    events: &SessionEvents,
+    session: SessionId,
+    err: ffmpeg::Error,
+) {
+    PipelineStats::add(&stats.audio_decoder_errors, 1);
+    events.emit_for(
+        session,
+        SessionEventKind::DecoderError {
+            media: MediaKind::Audio,
+            message: err.to_string(),
+        },
+    );
 }
 
 fn hex_to_buf(hex: &str) -> Vec<u8> {
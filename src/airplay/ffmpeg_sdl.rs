@@ -1,174 +1,801 @@
 use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use crossbeam::channel::{Receiver, Sender, TryRecvError};
-use ffmpeg::{format::Pixel, Packet};
+use ffmpeg::{format::Pixel, software::scaling::Context as Scaler, Packet};
 use ffmpeg_next::{self as ffmpeg, codec::Id};
 use sdl2::{
-    event::Event,
+    event::{Event, EventSender},
     keyboard::Keycode,
     pixels::{Color, PixelFormatEnum},
     rect::Rect,
-    render::TextureAccess,
+    render::{Canvas, Texture, TextureAccess},
+    video::{FullscreenType, Window},
 };
 
+use super::{
+    events::{MediaKind, SessionEventKind, SessionEvents, SessionId},
+    h264,
+    idle::{self, IdleScreen},
+    picture::{self, SnapshotOptions},
+    replay::ReplayBuffer,
+    sink::VideoSink,
+    slides::{SlideCapture, SlideCaptureOptions},
+    stats::{PipelineStats, RateMeter},
+    supervisor::{self, Pipeline},
+    triple_buffer::{triple_buffer, BufferReader, BufferWriter},
+    workers::{Worker, Workers},
+};
+use crate::{osd, Error, Result};
+
+/// How often the idle clock and the stats overlay are refreshed without new frames.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+pub(super) type SnapshotReply = Sender<Result<PathBuf>>;
+
 enum Frame {
     Pakcet(Packet),
+    /// Saves the newest decoded frame, the result is sent back or only logged.
+    Snapshot(Option<SnapshotReply>),
     End,
 }
 
+enum RenderEvent {
+    /// A session started, its frames are published through the reader.
+    Start(BufferReader<ffmpeg::frame::Video>),
+    /// A new frame, decoded at the given instant, has been published.
+    Frame(Instant),
+    /// The session ended, show the idle screen or close the window.
+    Idle,
+    /// Close the window, also the persistent one.
+    Quit,
+}
+
+/// Wakes the render loop blocked in `wait_event_timeout` by pushing an SDL user event.
+#[derive(Clone, Default)]
+struct RenderWaker(Arc<Mutex<Option<(EventSender, u32)>>>);
+
+impl RenderWaker {
+    /// Wakes through `sender` until the returned guard drops with the SDL context.
+    fn set(&self, sender: EventSender, event_type: u32) -> RenderWakerGuard<'_> {
+        *self.0.lock().unwrap() = Some((sender, event_type));
+        RenderWakerGuard(self)
+    }
+
+    fn wake(&self) {
+        if let Some((sender, event_type)) = self.0.lock().unwrap().as_ref() {
+            let _ = sender.push_event(Event::User {
+                timestamp: 0,
+                window_id: 0,
+                type_: *event_type,
+                code: 0,
+                data1: std::ptr::null_mut(),
+                data2: std::ptr::null_mut(),
+            });
+        }
+    }
+}
+
+struct RenderWakerGuard<'a>(&'a RenderWaker);
+
+impl Drop for RenderWakerGuard<'_> {
+    fn drop(&mut self) {
+        *self.0 .0.lock().unwrap() = None;
+    }
+}
+
+#[derive(Clone)]
+struct RenderSender {
+    tx: Sender<RenderEvent>,
+    waker: RenderWaker,
+}
+
+impl RenderSender {
+    /// Fails silently when the window has been closed by the user.
+    fn send(&self, event: RenderEvent) {
+        if self.tx.send(event).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+struct Renderer {
+    sender: RenderSender,
+    worker: Worker,
+}
+
+pub(super) struct WindowOptions {
+    pub width: u32,
+    pub height: u32,
+    /// Upper bound of presented frames per second, `0` for no limit.
+    pub fps: u32,
+    pub vsync: bool,
+    pub idle_screen: Option<IdleScreen>,
+}
+
+/// What the decoder thread does with decoded frames besides showing them.
+#[derive(Default)]
+pub(super) struct DecoderOptions {
+    pub snapshot: SnapshotOptions,
+    pub slides: Option<SlideCaptureOptions>,
+    pub replay: Option<Arc<ReplayBuffer>>,
+    /// Replaces the window, see `VideoSink`.
+    pub sink: Option<Arc<dyn VideoSink>>,
+    pub workers: Workers,
+}
+
+/// Where the decoder thread hands decoded frames to.
+enum VideoOutput {
+    Window(RenderSender, BufferWriter<ffmpeg::frame::Video>),
+    Sink(Arc<dyn VideoSink>),
+}
+
 pub(super) struct SdlFfmpeg {
-    width: u32,
-    height: u32,
+    options: Arc<WindowOptions>,
+    decoder_options: Arc<DecoderOptions>,
     video_packet_channel: (Sender<Frame>, Receiver<Frame>),
-    video: Arc<Mutex<ffmpeg::frame::Video>>,
+    stats: Arc<PipelineStats>,
+    events: SessionEvents,
+    show_stats: Arc<AtomicBool>,
+    fullscreen: Arc<AtomicBool>,
+    renderer: Mutex<Option<Renderer>>,
+    /// Whether a decoder thread takes packets, see `start` and `stop`.
+    running: AtomicBool,
 }
 
 impl SdlFfmpeg {
-    pub fn new(width: u32, height: u32) -> Self {
+    pub fn new(
+        options: WindowOptions,
+        decoder_options: DecoderOptions,
+        stats: Arc<PipelineStats>,
+        events: SessionEvents,
+    ) -> Self {
         Self {
-            width,
-            height,
+            options: Arc::new(options),
+            decoder_options: Arc::new(decoder_options),
             video_packet_channel: crossbeam::channel::unbounded(),
-            video: Arc::new(Mutex::new(ffmpeg::frame::Video::empty())),
-        }
-    }
-
-    fn create_video_decoder(&self, tx: Sender<()>) {
-        let rx = self.video_packet_channel.1.clone();
-        let out_video = self.video.clone();
-        std::thread::spawn(move || {
-            let codec = ffmpeg::codec::decoder::find(Id::H264).unwrap();
-            let mut decoder = ffmpeg::decoder::new()
-                .open_as(codec)
-                .unwrap()
-                .video()
-                .unwrap();
-            let mut wscaler = None;
-            let mut video_frame = ffmpeg::frame::Video::empty();
-            while let Ok(frame) = rx.recv() {
-                match frame {
-                    Frame::Pakcet(packet) => {
-                        if let Err(err) = decoder.send_packet(&packet) {
-                            tracing::error!("send packet error! {:?}", err);
-                            video_frame = ffmpeg::frame::Video::empty();
-                        } else {
-                            while decoder.receive_frame(&mut video_frame).is_ok() {
-                                let scaler = match &mut wscaler {
-                                    Some(scaler) => scaler,
-                                    _ => {
-                                        wscaler =
-                                            Some(video_frame.converter(Pixel::RGB24).unwrap());
-                                        wscaler.as_mut().unwrap()
-                                    }
-                                };
-                                let mut rgb_frame = out_video.lock().unwrap();
-                                if scaler.run(&video_frame, &mut rgb_frame).is_err() {
-                                    *rgb_frame = ffmpeg::frame::Video::empty();
-                                    wscaler = Some(video_frame.converter(Pixel::RGB24).unwrap());
-                                } else {
-                                    tx.send(()).unwrap();
-                                };
-                            }
-                        }
-                    }
-                    Frame::End => {
-                        break;
-                    }
-                }
-            }
-        });
+            stats,
+            events,
+            show_stats: Arc::new(AtomicBool::new(false)),
+            fullscreen: Arc::new(AtomicBool::new(false)),
+            renderer: Mutex::new(None),
+            running: AtomicBool::new(false),
+        }
     }
 
-    pub fn start(&self) -> Result<(), String> {
-        let width = self.width;
-        let height = self.height;
-        let (tx, rx) = crossbeam::channel::unbounded();
-        self.create_video_decoder(tx);
-        let update_video = self.video.clone();
-        std::thread::spawn(move || {
-            let sdl_context = sdl2::init().expect("sdl init error");
-            let video_subsystem = sdl_context.video().expect("sdl video error");
-            let window = video_subsystem
-                .window("airplay", width, height)
-                .position_centered()
-                .build()
-                .unwrap();
-
-            let mut canvas = window.into_canvas().build().unwrap();
-            let texture_creator = canvas.texture_creator();
-
-            let mut texture = texture_creator
-                .create_texture(PixelFormatEnum::RGB24, TextureAccess::Target, width, height)
-                .unwrap();
-            let mut event_pump = sdl_context.event_pump().unwrap();
-
-            'running: loop {
-                for event in event_pump.poll_iter() {
-                    match event {
-                        Event::Quit { .. }
-                        | Event::KeyDown {
-                            keycode: Some(Keycode::Escape),
-                            ..
-                        } => break 'running,
-                        _ => {}
-                    }
+    /// Shows or hides the statistics overlay, also toggled with F1 in the window.
+    pub fn set_show_stats(&self, show: bool) {
+        self.show_stats.store(show, Ordering::Relaxed);
+    }
+
+    /// Switches the window to desktop fullscreen, also for windows opened later.
+    pub fn set_fullscreen(&self, fullscreen: bool) {
+        self.fullscreen.store(fullscreen, Ordering::Relaxed);
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.fullscreen.load(Ordering::Relaxed)
+    }
+
+    fn create_video_decoder(&self, decoder: ffmpeg::decoder::Video) {
+        let decoder_options = self.decoder_options.clone();
+        let output = match &decoder_options.sink {
+            Some(sink) => VideoOutput::Sink(sink.clone()),
+            None => {
+                let tx = self.renderer_sender();
+                let (frames, reader) = triple_buffer(ffmpeg::frame::Video::empty);
+                tx.send(RenderEvent::Start(reader));
+                VideoOutput::Window(tx, frames)
+            }
+        };
+        let mut pipeline = VideoPipeline {
+            rx: self.video_packet_channel.1.clone(),
+            stats: self.stats.clone(),
+            events: self.events.clone(),
+            session: self.events.session(),
+            slides: None,
+            decoder_options,
+            output,
+            last_frame: ffmpeg::frame::Video::empty(),
+            parameter_sets: None,
+            resolution: (0, 0),
+        };
+        self.decoder_options
+            .workers
+            .spawn("video-decoder", move || {
+                let _span = tracing::info_span!("video_decoder").entered();
+                pipeline.slides =
+                    pipeline.decoder_options.slides.as_ref().map(|options| {
+                        SlideCapture::new(options, &pipeline.decoder_options.workers)
+                    });
+                let stats = pipeline.stats.clone();
+                let events = pipeline.events.clone();
+                let mut decoder = Some(decoder);
+                let session = Some(pipeline.session);
+                let kind = Pipeline::Decoder(MediaKind::Video);
+                if !supervisor::supervise(kind, session, &stats, &events, || {
+                    pipeline.run(decoder.take())
+                }) {
+                    pipeline.drain();
                 }
-                match rx.try_recv() {
-                    Ok(()) => {
-                        let rgb_frame = update_video.lock().unwrap();
-                        if !unsafe { rgb_frame.is_empty() } {
-                            canvas
-                                .with_texture_canvas(&mut texture, |texture_canvas| {
-                                    texture_canvas.set_draw_color(Color::RGB(0, 0, 0));
-                                    texture_canvas.clear();
-                                })
-                                .expect("clear texture error!");
-                            texture
-                                .update(
-                                    Rect::new(
-                                        ((width - rgb_frame.width()) / 2) as i32,
-                                        ((height - rgb_frame.height()) / 2) as i32,
-                                        rgb_frame.width(),
-                                        rgb_frame.height(),
-                                    ),
-                                    rgb_frame.data(0),
-                                    rgb_frame.stride(0),
-                                )
-                                .unwrap();
-                            canvas.copy(&texture, None, None).unwrap();
-                            canvas.present();
-                        }
-                    }
-                    Err(TryRecvError::Disconnected) => {
-                        break;
-                    }
-                    _ => (),
+                match pipeline.output {
+                    VideoOutput::Window(tx, _) => tx.send(RenderEvent::Idle),
+                    VideoOutput::Sink(sink) => sink.on_end(),
                 }
-                ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
-            }
-        });
+            });
+    }
 
+    /// Starts a decoder thread for a new video stream.
+    pub fn start(&self) -> Result<()> {
+        // 先打开一次解码器，失败时直接报告给调用方
+        let decoder = open_decoder()?;
+        self.create_video_decoder(decoder);
+        self.running.store(true, Ordering::Relaxed);
         Ok(())
     }
 
-    pub fn push_buffer(&self, buf: &[u8]) -> anyhow::Result<()> {
+    /// Opens the persistent window on the idle screen, if one is configured.
+    pub fn show_idle(&self) {
+        if self.options.idle_screen.is_some() && self.decoder_options.sink.is_none() {
+            self.renderer_sender().send(RenderEvent::Idle);
+        }
+    }
+
+    /// Returns the sender of the running window, spawning a new one if needed.
+    ///
+    /// Without an idle screen every session gets its own window which closes
+    /// when the session ends, otherwise one window is kept for all sessions.
+    fn renderer_sender(&self) -> RenderSender {
+        let mut renderer = self.renderer.lock().unwrap();
+        if let Some(renderer) = renderer.as_ref().filter(|r| !r.worker.is_finished()) {
+            return renderer.sender.clone();
+        }
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let sender = RenderSender {
+            tx,
+            waker: RenderWaker::default(),
+        };
+        let context = RenderContext {
+            options: self.options.clone(),
+            packets: self.video_packet_channel.0.clone(),
+            stats: self.stats.clone(),
+            show_stats: self.show_stats.clone(),
+            fullscreen: self.fullscreen.clone(),
+            waker: sender.waker.clone(),
+            replay: self.decoder_options.replay.clone(),
+            workers: self.decoder_options.workers.clone(),
+        };
+        // 常驻窗口跨越多个会话，不挂在当前会话下
+        let span = match self.options.idle_screen {
+            Some(_) => tracing::info_span!(parent: None, "renderer"),
+            None => tracing::info_span!("renderer"),
+        };
+        let events = self.events.clone();
+        // 常驻窗口的错误属于出错时的会话
+        let session = match self.options.idle_screen {
+            Some(_) => None,
+            None => Some(self.events.session()),
+        };
+        let worker = span.in_scope(|| {
+            self.decoder_options.workers.spawn("renderer", move || {
+                // 窗口出错时重建，放弃后解码继续，帧被丢弃
+                let mut frames = None;
+                supervisor::supervise(Pipeline::Renderer, session, &context.stats, &events, || {
+                    context.run(&rx, &mut frames)
+                });
+            })
+        });
+        if self.options.idle_screen.is_some() {
+            *renderer = Some(Renderer {
+                sender: sender.clone(),
+                worker,
+            });
+        }
+        sender
+    }
+
+    /// Queues a packet for the decoder, dropped if no decoder thread runs.
+    pub fn push_buffer(&self, buf: &[u8]) -> Result<()> {
         // TODO: 主动退出sdl窗口，会导致消息一直积累
+        self.stats.count_video_packet(buf.len());
+        if !self.running.load(Ordering::Relaxed) {
+            return Ok(());
+        }
         let packet = Packet::copy(buf);
-        if self
-            .video_packet_channel
+        self.video_packet_channel
             .0
             .send(Frame::Pakcet(packet))
-            .is_err()
-        {
-            self.stop();
+            .map_err(|_| Error::ChannelClosed(MediaKind::Video))
+    }
+
+    pub fn stop(&self) {
+        if self.running.swap(false, Ordering::Relaxed) {
+            let _ = self.video_packet_channel.0.send(Frame::End);
+        }
+    }
+
+    /// Closes the persistent idle window, session windows close with their session.
+    pub fn close_window(&self) {
+        if let Some(renderer) = self.renderer.lock().unwrap().take() {
+            renderer.sender.send(RenderEvent::Quit);
+        }
+    }
+
+    /// Asks the running decoder to save its newest frame.
+    pub fn snapshot(&self, reply: Option<SnapshotReply>) {
+        if self.running.load(Ordering::Relaxed) {
+            let _ = self.video_packet_channel.0.send(Frame::Snapshot(reply));
+        } else if let Some(reply) = reply {
+            let _ = reply.send(Err(Error::Unavailable("no running video decoder")));
+        }
+    }
+}
+
+fn take_snapshot(
+    frame: &ffmpeg::frame::Video,
+    options: Arc<DecoderOptions>,
+    reply: Option<SnapshotReply>,
+) {
+    let frame = (!unsafe { frame.is_empty() }).then(|| frame.clone());
+    // 编码较慢，放到单独线程避免阻塞解码
+    let workers = options.workers.clone();
+    workers.spawn("snapshot", move || {
+        let result = match frame {
+            Some(frame) => picture::save_image(
+                &frame,
+                &options.snapshot.dir,
+                "snapshot",
+                options.snapshot.format,
+            ),
+            None => Err(Error::Unavailable("no decoded frame yet")),
+        };
+        match &result {
+            Ok(path) => tracing::info!("snapshot saved to {path:?}"),
+            Err(err) => tracing::error!("snapshot error {err}"),
+        }
+        if let Some(reply) = reply {
+            let _ = reply.send(result);
+        }
+    });
+}
+
+/// State of the decoder thread that outlives a decoder rebuilt by `supervise`.
+struct VideoPipeline {
+    rx: Receiver<Frame>,
+    stats: Arc<PipelineStats>,
+    events: SessionEvents,
+    /// The session the thread was started for.
+    session: SessionId,
+    decoder_options: Arc<DecoderOptions>,
+    output: VideoOutput,
+    /// 最新解码的一帧，用于截图
+    last_frame: ffmpeg::frame::Video,
+    slides: Option<SlideCapture>,
+    /// SPS and PPS of the stream, fed to a rebuilt decoder before the next packet.
+    parameter_sets: Option<Packet>,
+    resolution: (u32, u32),
+}
+
+impl VideoPipeline {
+    /// Decodes until `Frame::End` or until the channel closes, with `decoder` or
+    /// a new one.
+    fn run(&mut self, decoder: Option<ffmpeg::decoder::Video>) -> Result<()> {
+        let mut decoder = match decoder {
+            Some(decoder) => decoder,
+            None => open_decoder()?,
+        };
+        if let Some(parameter_sets) = &self.parameter_sets {
+            decoder.send_packet(parameter_sets).map_err(Error::Decode)?;
+        }
+        let mut wscaler = None;
+        let mut video_frame = ffmpeg::frame::Video::empty();
+        while let Ok(frame) = self.rx.recv() {
+            match frame {
+                Frame::Pakcet(packet) => {
+                    self.stats
+                        .queue_depth
+                        .store(self.rx.len(), Ordering::Relaxed);
+                    let data = packet.data().unwrap_or_default();
+                    if h264::first_nal_type(data) == Some(h264::NAL_SPS) {
+                        self.parameter_sets = Some(Packet::copy(&h264::parameter_sets(data)));
+                    }
+                    if let Err(err) = decoder.send_packet(&packet) {
+                        tracing::error!("send packet error! {:?}", err);
+                        PipelineStats::add(&self.stats.corrupt_frames, 1);
+                        PipelineStats::add(&self.stats.video_decoder_errors, 1);
+                        self.events.emit_for(
+                            self.session,
+                            SessionEventKind::DecoderError {
+                                media: MediaKind::Video,
+                                message: err.to_string(),
+                            },
+                        );
+                        video_frame = ffmpeg::frame::Video::empty();
+                        continue;
+                    }
+                    while decoder.receive_frame(&mut video_frame).is_ok() {
+                        self.on_frame(&video_frame, &mut wscaler)?;
+                        std::mem::swap(&mut video_frame, &mut self.last_frame);
+                    }
+                }
+                Frame::Snapshot(reply) => {
+                    take_snapshot(&self.last_frame, self.decoder_options.clone(), reply);
+                }
+                Frame::End => {
+                    break;
+                }
+            }
         }
         Ok(())
     }
 
-    pub fn stop(&self) {
-        self.video_packet_channel.0.send(Frame::End).unwrap();
+    fn on_frame(
+        &mut self,
+        video_frame: &ffmpeg::frame::Video,
+        wscaler: &mut Option<Scaler>,
+    ) -> Result<()> {
+        let stats = &self.stats;
+        PipelineStats::add(&stats.decoded_frames, 1);
+        if video_frame.is_corrupt() {
+            PipelineStats::add(&stats.corrupt_frames, 1);
+        }
+        stats
+            .stream_width
+            .store(video_frame.width(), Ordering::Relaxed);
+        stats
+            .stream_height
+            .store(video_frame.height(), Ordering::Relaxed);
+        let size = (video_frame.width(), video_frame.height());
+        if size != self.resolution {
+            self.resolution = size;
+            self.events.emit_for(
+                self.session,
+                SessionEventKind::ResolutionChanged {
+                    width: size.0,
+                    height: size.1,
+                },
+            );
+        }
+        if let Some(slides) = &mut self.slides {
+            slides.on_frame(video_frame);
+        }
+        let (tx, frames) = match &mut self.output {
+            VideoOutput::Window(tx, frames) => (tx, frames),
+            VideoOutput::Sink(sink) => {
+                sink.on_frame(video_frame);
+                return Ok(());
+            }
+        };
+        let scaler = match wscaler {
+            Some(scaler) => scaler,
+            None => wscaler.insert(
+                video_frame
+                    .converter(Pixel::RGB24)
+                    .map_err(Error::DecoderInit)?,
+            ),
+        };
+        let rgb_frame = frames.back();
+        if scaler.run(video_frame, rgb_frame).is_err() {
+            // 分辨率变化，重建转换器后重试
+            *rgb_frame = ffmpeg::frame::Video::empty();
+            let scaler = wscaler.insert(
+                video_frame
+                    .converter(Pixel::RGB24)
+                    .map_err(Error::DecoderInit)?,
+            );
+            if scaler.run(video_frame, rgb_frame).is_err() {
+                *rgb_frame = ffmpeg::frame::Video::empty();
+                return Ok(());
+            }
+        }
+        frames.publish();
+        tx.send(RenderEvent::Frame(Instant::now()));
+        Ok(())
+    }
+
+    /// Discards packets of a pipeline that gave up until the session ends.
+    fn drain(&self) {
+        while let Ok(frame) = self.rx.recv() {
+            match frame {
+                Frame::Pakcet(_) => {}
+                Frame::Snapshot(reply) => {
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Err(Error::ChannelClosed(MediaKind::Video)));
+                    }
+                }
+                Frame::End => break,
+            }
+        }
+    }
+}
+
+/// Clears `texture` and draws the RGB24 `rgb_frame` centered, frames larger
+/// than the texture are cropped to their center.
+fn update_texture(
+    canvas: &mut Canvas<Window>,
+    texture: &mut Texture,
+    rgb_frame: &ffmpeg::frame::Video,
+    width: u32,
+    height: u32,
+) -> Result<()> {
+    canvas
+        .with_texture_canvas(texture, |texture_canvas| {
+            texture_canvas.set_draw_color(Color::RGB(0, 0, 0));
+            texture_canvas.clear();
+        })
+        .map_err(Error::render)?;
+    const BYTES_PER_PIXEL: usize = 3;
+    let frame_width = rgb_frame.width().min(width);
+    let frame_height = rgb_frame.height().min(height);
+    // 超出纹理的部分两边各裁掉一半
+    let stride = rgb_frame.stride(0);
+    let crop_x = (rgb_frame.width() - frame_width) / 2;
+    let crop_y = (rgb_frame.height() - frame_height) / 2;
+    let offset = crop_y as usize * stride + crop_x as usize * BYTES_PER_PIXEL;
+    texture
+        .update(
+            Rect::new(
+                ((width - frame_width) / 2) as i32,
+                ((height - frame_height) / 2) as i32,
+                frame_width,
+                frame_height,
+            ),
+            &rgb_frame.data(0)[offset..],
+            stride,
+        )
+        .map_err(Error::render)
+}
+
+fn open_decoder() -> Result<ffmpeg::decoder::Video> {
+    let codec = ffmpeg::codec::decoder::find(Id::H264)
+        .ok_or(Error::DecoderInit(ffmpeg::Error::DecoderNotFound))?;
+    ffmpeg::decoder::new()
+        .open_as(codec)
+        .and_then(|decoder| decoder.video())
+        .map_err(Error::DecoderInit)
+}
+
+struct RenderContext {
+    options: Arc<WindowOptions>,
+    packets: Sender<Frame>,
+    stats: Arc<PipelineStats>,
+    show_stats: Arc<AtomicBool>,
+    fullscreen: Arc<AtomicBool>,
+    waker: RenderWaker,
+    replay: Option<Arc<ReplayBuffer>>,
+    workers: Workers,
+}
+
+impl RenderContext {
+    /// Handles one window event, returns `false` when the window should close.
+    fn handle_event(&self, event: Event, idle: bool) -> bool {
+        match event {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => return false,
+            Event::KeyDown {
+                keycode: Some(Keycode::F1),
+                ..
+            } => {
+                self.show_stats.fetch_xor(true, Ordering::Relaxed);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F12),
+                ..
+            } if !idle => {
+                let _ = self.packets.send(Frame::Snapshot(None));
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F11),
+                ..
+            } => {
+                if let Some(replay) = self.replay.clone() {
+                    self.workers.spawn("replay-save", move || {
+                        if let Err(err) = replay.save() {
+                            tracing::error!("save replay error {err}");
+                        }
+                    });
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Shows the window until it is closed. `frames` is the reader of the running
+    /// session, kept when the window is rebuilt after an error.
+    fn run(
+        &self,
+        rx: &Receiver<RenderEvent>,
+        frames: &mut Option<BufferReader<ffmpeg::frame::Video>>,
+    ) -> Result<()> {
+        let width = self.options.width;
+        let height = self.options.height;
+        let idle_screen = self.options.idle_screen.as_ref();
+        let frame_interval = if self.options.fps > 0 {
+            Duration::from_secs(1) / self.options.fps
+        } else {
+            Duration::ZERO
+        };
+        let sdl_context = sdl2::init().map_err(Error::render)?;
+        let video_subsystem = sdl_context.video().map_err(Error::render)?;
+        let event_subsystem = sdl_context.event().map_err(Error::render)?;
+        let wake_event = unsafe { event_subsystem.register_event() }.map_err(Error::render)?;
+        let _waker = self.waker.set(event_subsystem.event_sender(), wake_event);
+        let window = video_subsystem
+            .window("airplay", width, height)
+            .position_centered()
+            .build()
+            .map_err(Error::render)?;
+
+        let mut canvas_builder = window.into_canvas();
+        if self.options.vsync {
+            canvas_builder = canvas_builder.present_vsync();
+        }
+        let mut canvas = canvas_builder.build().map_err(Error::render)?;
+        let texture_creator = canvas.texture_creator();
+
+        let mut texture = texture_creator
+            .create_texture(PixelFormatEnum::RGB24, TextureAccess::Target, width, height)
+            .map_err(Error::render)?;
+        let background = idle_screen
+            .and_then(|idle| idle.background.as_ref())
+            .and_then(|path| match picture::load_rgb(path, width, height) {
+                Ok(frame) => Some(frame),
+                Err(err) => {
+                    tracing::error!("load idle background {path:?} error {err}");
+                    None
+                }
+            })
+            .and_then(|frame| {
+                let mut background = texture_creator
+                    .create_texture_static(PixelFormatEnum::RGB24, width, height)
+                    .ok()?;
+                background
+                    .update(None, frame.data(0), frame.stride(0))
+                    .ok()?;
+                Some(background)
+            });
+        let mut event_pump = sdl_context.event_pump().map_err(Error::render)?;
+        let mut rate_meter = RateMeter::new();
+        let mut has_frame = false;
+        let mut hud_shown = false;
+        let mut idle = idle_screen.is_some();
+        let mut idle_drawn_at: Option<Instant> = None;
+        let mut idle_pin = None;
+        let mut fullscreen = false;
+        // 最新一帧的解码时间，等待按帧率呈现
+        let mut pending_frame: Option<Instant> = None;
+        let mut pending_count = 0;
+        let mut last_present = Instant::now()
+            .checked_sub(frame_interval)
+            .unwrap_or_else(Instant::now);
+
+        'running: loop {
+            let timeout = match pending_frame {
+                Some(_) => frame_interval.saturating_sub(last_present.elapsed()),
+                None => REFRESH_INTERVAL,
+            };
+            if let Some(event) = event_pump.wait_event_timeout(timeout.as_millis() as u32) {
+                if !self.handle_event(event, idle) {
+                    break;
+                }
+            }
+            for event in event_pump.poll_iter() {
+                if !self.handle_event(event, idle) {
+                    break 'running;
+                }
+            }
+            loop {
+                match rx.try_recv() {
+                    Ok(RenderEvent::Start(reader)) => {
+                        *frames = Some(reader);
+                    }
+                    Ok(RenderEvent::Frame(decoded_at)) => {
+                        pending_frame = Some(decoded_at);
+                        pending_count += 1;
+                        idle = false;
+                    }
+                    Ok(RenderEvent::Idle) => {
+                        if idle_screen.is_none() {
+                            break 'running;
+                        }
+                        pending_frame = None;
+                        pending_count = 0;
+                        idle = true;
+                        has_frame = false;
+                        idle_drawn_at = None;
+                    }
+                    Ok(RenderEvent::Quit) | Err(TryRecvError::Disconnected) => break 'running,
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+            if fullscreen != self.fullscreen.load(Ordering::Relaxed) {
+                fullscreen = !fullscreen;
+                let mode = if fullscreen {
+                    FullscreenType::Desktop
+                } else {
+                    FullscreenType::Off
+                };
+                if let Err(err) = canvas.window_mut().set_fullscreen(mode) {
+                    tracing::error!("set fullscreen error {err}");
+                }
+                idle_drawn_at = None;
+            }
+            if idle {
+                if let Some(idle_screen) = idle_screen {
+                    let pin = idle_screen.pin_text();
+                    let redraw = match idle_drawn_at {
+                        Some(drawn_at) => {
+                            (idle_screen.show_clock && drawn_at.elapsed() >= Duration::from_secs(1))
+                                || pin != idle_pin
+                        }
+                        None => true,
+                    };
+                    if redraw {
+                        if let Err(err) =
+                            idle::draw_idle(&mut canvas, background.as_ref(), idle_screen)
+                        {
+                            tracing::error!("draw idle screen error {err}");
+                        }
+                        canvas.present();
+                        idle_drawn_at = Some(Instant::now());
+                        idle_pin = pin;
+                    }
+                }
+                continue;
+            }
+            let show_hud = self.show_stats.load(Ordering::Relaxed);
+            let hud_changed = rate_meter.update(&self.stats) || show_hud != hud_shown;
+            let frame_due = pending_frame.is_some() && last_present.elapsed() >= frame_interval;
+            if frame_due {
+                // 只显示最新的一帧，其余的计为丢帧
+                PipelineStats::add(&self.stats.dropped_frames, pending_count - 1);
+                pending_count = 0;
+                let rgb_frame = frames.as_mut().map(|frames| {
+                    frames.update();
+                    frames.front()
+                });
+                if let Some(rgb_frame) = rgb_frame.filter(|frame| !unsafe { frame.is_empty() }) {
+                    // 单帧绘制失败只跳过这一帧
+                    match update_texture(&mut canvas, &mut texture, rgb_frame, width, height) {
+                        Ok(()) => has_frame = true,
+                        Err(err) => tracing::error!("update texture error {err}"),
+                    }
+                }
+            }
+            if has_frame && (frame_due || (hud_changed && (show_hud || hud_shown))) {
+                if let Err(err) = canvas.copy(&texture, None, None) {
+                    tracing::error!("copy texture error {err}");
+                }
+                if show_hud {
+                    if let Err(err) =
+                        osd::draw_panel(&mut canvas, 8, 8, 2, &rate_meter.lines(&self.stats))
+                    {
+                        tracing::error!("draw stats overlay error {err}");
+                    }
+                }
+                canvas.present();
+                last_present = Instant::now();
+                hud_shown = show_hud;
+                if let Some(decoded_at) = pending_frame.take().filter(|_| frame_due) {
+                    PipelineStats::add(&self.stats.presented_frames, 1);
+                    self.stats
+                        .present_latency_us
+                        .store(decoded_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+                }
+            } else if frame_due {
+                pending_frame = None;
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,52 @@
+/// NAL unit types used when inspecting the mirrored H.264 stream.
+pub(super) const NAL_IDR: u8 = 5;
+pub(super) const NAL_SPS: u8 = 7;
+pub(super) const NAL_PPS: u8 = 8;
+
+/// Splits an Annex-B buffer into `(nal type, unit including its start code)`.
+pub(super) fn nal_units(buf: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 < buf.len() {
+        if buf[i] == 0 && buf[i + 1] == 0 && buf[i + 2] == 1 {
+            let start = if i > 0 && buf[i - 1] == 0 { i - 1 } else { i };
+            starts.push((start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, (start, payload))| {
+            let end = starts.get(n + 1).map_or(buf.len(), |next| next.0);
+            (buf[*payload] & 0x1F, &buf[*start..end])
+        })
+        .collect()
+}
+
+/// Type of the first NAL unit, without scanning the whole buffer.
+pub(super) fn first_nal_type(buf: &[u8]) -> Option<u8> {
+    let payload = match buf {
+        [0, 0, 0, 1, ..] => 4,
+        [0, 0, 1, ..] => 3,
+        _ => return None,
+    };
+    buf.get(payload).map(|header| header & 0x1F)
+}
+
+pub(super) fn is_keyframe(buf: &[u8]) -> bool {
+    nal_units(buf)
+        .iter()
+        .any(|(nal_type, _)| *nal_type == NAL_IDR)
+}
+
+/// SPS and PPS units of `buf`, concatenated with their start codes.
+pub(super) fn parameter_sets(buf: &[u8]) -> Vec<u8> {
+    nal_units(buf)
+        .into_iter()
+        .filter(|(nal_type, _)| matches!(*nal_type, NAL_SPS | NAL_PPS))
+        .flat_map(|(_, unit)| unit.iter().copied())
+        .collect()
+}
@@ -0,0 +1,413 @@
+//! External commands run on session events, e.g. to switch the TV input when a
+//! sender connects and switch it back afterwards.
+//!
+//! Every command gets the event as `KIRCAST_*` environment variables or, with
+//! `HookInput::Json`, as one JSON object on stdin:
+//!
+//! | Event           | Environment                                              |
+//! |-----------------|----------------------------------------------------------|
+//! | `session_start` | `KIRCAST_EVENT`, `KIRCAST_SESSION`                       |
+//! | `video_format`  | and `KIRCAST_VIDEO_CONNECTION`                           |
+//! | `audio_format`  | and `KIRCAST_AUDIO_CODEC`, `_SAMPLE_RATE`, `_CHANNELS`   |
+//! | `disconnect`    | and `KIRCAST_DURATION_MS`                                |
+//! | `error`         | and `KIRCAST_MEDIA`, `KIRCAST_STAGE`, `KIRCAST_MESSAGE`  |
+//!
+//! Commands run on `hook` worker threads, at most `HookOptions::max_concurrent`
+//! at a time, the others wait in order. A command still running after
+//! `HookOptions::timeout` is killed, on Unix with the processes it started.
+//! Their stdout and stderr are not passed through, the start of it is logged.
+
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    io::{Read, Write},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Receiver;
+use serde::Serialize;
+
+use super::{
+    events::{SessionEventKind, SessionId},
+    workers::Workers,
+    AudioStreamFormat, MediaKind, VideoStreamFormat,
+};
+
+/// Events waiting for a free worker, later ones are dropped.
+const QUEUE_LIMIT: usize = 64;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Bytes of a command's output kept for the log.
+const OUTPUT_EXCERPT: usize = 512;
+
+/// How long the output is awaited after the command exited, processes it left
+/// in the background may keep the pipes open.
+const OUTPUT_WAIT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HookInput {
+    /// Event details in `KIRCAST_*` environment variables, stdin is empty.
+    #[default]
+    Env,
+    /// Event details as JSON on stdin, e.g.
+    /// `{"session":1,"event":"error","media":"video","stage":"decoder","message":"..."}`.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct HookCommand {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    pub input: HookInput,
+}
+
+impl HookCommand {
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            input: HookInput::Env,
+        }
+    }
+
+    /// Runs `command` with `sh -c`, `cmd /C` on Windows.
+    pub fn shell(command: impl Into<OsString>) -> Self {
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        Self::new(shell).arg(flag).arg(command)
+    }
+
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn input(mut self, input: HookInput) -> Self {
+        self.input = input;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HookOptions {
+    /// A sender started streaming video or audio.
+    pub session_start: Option<HookCommand>,
+    pub video_format: Option<HookCommand>,
+    pub audio_format: Option<HookCommand>,
+    /// Both streams disconnected.
+    pub disconnect: Option<HookCommand>,
+    /// A decoder or the audio device failed, at start or while running, see
+    /// `SessionEventKind::DecoderError`, `PipelineFailed`, `RendererFailed` and
+    /// `AudioUnavailable`, told apart by `ErrorStage`.
+    /// Runs at most once every 10 seconds for the same kind of failure and media
+    /// in a session, the others are only logged.
+    pub error: Option<HookCommand>,
+    /// Commands running longer are killed.
+    pub timeout: Duration,
+    /// Commands running at once. With 1 they run in event order.
+    pub max_concurrent: usize,
+}
+
+impl Default for HookOptions {
+    fn default() -> Self {
+        Self {
+            session_start: None,
+            video_format: None,
+            audio_format: None,
+            disconnect: None,
+            error: None,
+            timeout: Duration::from_secs(10),
+            max_concurrent: 1,
+        }
+    }
+}
+
+/// The part of the pipeline an `error` event comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ErrorStage {
+    Decoder,
+    Renderer,
+    /// The audio output device.
+    Output,
+}
+
+impl ErrorStage {
+    fn name(self) -> &'static str {
+        match self {
+            ErrorStage::Decoder => "decoder",
+            ErrorStage::Renderer => "renderer",
+            ErrorStage::Output => "output",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum HookEvent {
+    SessionStart,
+    VideoFormat {
+        format: VideoStreamFormat,
+    },
+    AudioFormat {
+        format: AudioStreamFormat,
+    },
+    Disconnect {
+        duration_ms: u64,
+    },
+    Error {
+        media: MediaKind,
+        stage: ErrorStage,
+        message: String,
+    },
+}
+
+impl HookEvent {
+    /// The `error` event of a failure reported as a session event.
+    pub fn failure(kind: &SessionEventKind) -> Option<Self> {
+        let (media, stage, message) = match kind {
+            SessionEventKind::DecoderError { media, message }
+            | SessionEventKind::PipelineFailed { media, message, .. } => {
+                (*media, ErrorStage::Decoder, message)
+            }
+            SessionEventKind::RendererFailed { message, .. } => {
+                (MediaKind::Video, ErrorStage::Renderer, message)
+            }
+            SessionEventKind::AudioUnavailable { message } => {
+                (MediaKind::Audio, ErrorStage::Output, message)
+            }
+            _ => return None,
+        };
+        Some(HookEvent::Error {
+            media,
+            stage,
+            message: message.clone(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::SessionStart => "session_start",
+            HookEvent::VideoFormat { .. } => "video_format",
+            HookEvent::AudioFormat { .. } => "audio_format",
+            HookEvent::Disconnect { .. } => "disconnect",
+            HookEvent::Error { .. } => "error",
+        }
+    }
+
+    fn env(&self, session: SessionId) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("KIRCAST_EVENT", self.name().to_string()),
+            ("KIRCAST_SESSION", session.to_string()),
+        ];
+        match self {
+            HookEvent::SessionStart => {}
+            HookEvent::VideoFormat { format } => {
+                env.push(("KIRCAST_VIDEO_CONNECTION", format.connection_id.clone()));
+            }
+            HookEvent::AudioFormat { format } => {
+                env.push(("KIRCAST_AUDIO_CODEC", format.codec.name().to_string()));
+                env.push(("KIRCAST_AUDIO_SAMPLE_RATE", format.sample_rate.to_string()));
+                env.push(("KIRCAST_AUDIO_CHANNELS", format.channels.to_string()));
+            }
+            HookEvent::Disconnect { duration_ms } => {
+                env.push(("KIRCAST_DURATION_MS", duration_ms.to_string()));
+            }
+            HookEvent::Error {
+                media,
+                stage,
+                message,
+            } => {
+                env.push(("KIRCAST_MEDIA", media.name().to_string()));
+                env.push(("KIRCAST_STAGE", stage.name().to_string()));
+                env.push(("KIRCAST_MESSAGE", message.clone()));
+            }
+        }
+        env
+    }
+}
+
+#[derive(Serialize)]
+struct Job {
+    session: SessionId,
+    #[serde(flatten)]
+    event: HookEvent,
+    #[serde(skip)]
+    command: HookCommand,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    workers: usize,
+}
+
+/// Runs the configured commands without blocking the caller.
+pub(crate) struct Hooks {
+    options: HookOptions,
+    queue: Arc<Mutex<Queue>>,
+    workers: Workers,
+}
+
+impl Hooks {
+    pub fn new(options: HookOptions, workers: Workers) -> Self {
+        Self {
+            options,
+            queue: Arc::default(),
+            workers,
+        }
+    }
+
+    /// Queues the command of `event`, if one is configured.
+    pub fn run(&self, session: SessionId, event: HookEvent) {
+        let options = &self.options;
+        let command = match event {
+            HookEvent::SessionStart => &options.session_start,
+            HookEvent::VideoFormat { .. } => &options.video_format,
+            HookEvent::AudioFormat { .. } => &options.audio_format,
+            HookEvent::Disconnect { .. } => &options.disconnect,
+            HookEvent::Error { .. } => &options.error,
+        };
+        let Some(command) = command.clone() else {
+            return;
+        };
+        let mut queue = self.queue.lock().unwrap();
+        if queue.jobs.len() >= QUEUE_LIMIT {
+            tracing::warn!("钩子命令排队过多，忽略 {} 事件", event.name());
+            return;
+        }
+        queue.jobs.push_back(Job {
+            session,
+            event,
+            command,
+        });
+        if queue.workers < options.max_concurrent.max(1) {
+            queue.workers += 1;
+            let queue = self.queue.clone();
+            let timeout = options.timeout;
+            // 关闭时 Workers::join_all 会等待断开连接的命令执行完
+            self.workers.spawn("hook", move || work(&queue, timeout));
+        }
+    }
+}
+
+/// Runs queued jobs until the queue is empty.
+fn work(queue: &Mutex<Queue>, timeout: Duration) {
+    loop {
+        let job = {
+            let mut queue = queue.lock().unwrap();
+            match queue.jobs.pop_front() {
+                Some(job) => job,
+                None => {
+                    queue.workers -= 1;
+                    return;
+                }
+            }
+        };
+        let event = job.event.name();
+        let started = Instant::now();
+        match execute(&job, timeout) {
+            Ok((Some(status), output)) if status.success() => {
+                tracing::info!("钩子命令 {event} 执行完成，用时 {:?}", started.elapsed());
+                if !output.is_empty() {
+                    tracing::debug!("钩子命令 {event} 输出： {output}");
+                }
+            }
+            Ok((Some(status), output)) => match status.code() {
+                Some(code) => tracing::warn!("钩子命令 {event} 退出码 {code}，输出： {output}"),
+                None => tracing::warn!("钩子命令 {event} 异常退出： {status}，输出： {output}"),
+            },
+            Ok((None, output)) => {
+                tracing::warn!("钩子命令 {event} 超过 {timeout:?} 未结束，已终止，输出： {output}")
+            }
+            Err(err) => tracing::warn!("无法执行钩子命令 {event}： {err}"),
+        }
+    }
+}
+
+/// Returns the exit status, `None` if the command was killed at the timeout, and
+/// the start of its stdout and stderr.
+fn execute(job: &Job, timeout: Duration) -> std::io::Result<(Option<ExitStatus>, String)> {
+    let command = &job.command;
+    let json = command.input == HookInput::Json;
+    let mut process = Command::new(&command.program);
+    process
+        .args(&command.args)
+        .envs(job.event.env(job.session))
+        .stdin(if json { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // 放进单独的进程组，超时时连同子进程一起终止
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut process, 0);
+    let mut child = process.spawn()?;
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = serde_json::to_vec(job).map_err(std::io::Error::other)?;
+        // 命令不读取 stdin 时忽略写入失败
+        let _ = stdin.write_all(&input);
+    }
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            // 可能刚好退出，以 wait 的结果为准
+            kill(&mut child);
+            child.wait()?;
+            break None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    let mut output: Vec<u8> = [stdout, stderr]
+        .into_iter()
+        .flatten()
+        .flat_map(|rx| rx.recv_timeout(OUTPUT_WAIT).unwrap_or_default())
+        .collect();
+    let truncated = output.len() > OUTPUT_EXCERPT;
+    output.truncate(OUTPUT_EXCERPT);
+    let mut output = String::from_utf8_lossy(&output).trim().to_string();
+    if truncated {
+        output.push_str("...");
+    }
+    Ok((status, output))
+}
+
+/// Reads `pipe` to the end on its own thread, keeping the first bytes.
+fn drain(mut pipe: impl Read + Send + 'static) -> Receiver<Vec<u8>> {
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    std::thread::Builder::new()
+        .name("hook-output".to_string())
+        .spawn(move || {
+            let mut kept = Vec::new();
+            let mut buf = [0; 4096];
+            while let Ok(read @ 1..) = pipe.read(&mut buf) {
+                let keep = read.min((OUTPUT_EXCERPT + 1).saturating_sub(kept.len()));
+                kept.extend_from_slice(&buf[..keep]);
+            }
+            let _ = tx.send(kept);
+        })
+        .expect("spawn thread error");
+    rx
+}
+
+/// Kills the command with the processes it started, they share its process group.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // SAFETY: 只发送信号，进程组 id 就是命令的 pid，命令还没有被 wait 回收
+    unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+    let _ = child.kill();
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
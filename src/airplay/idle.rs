@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use sdl2::{
+    pixels::Color,
+    render::{Canvas, RenderTarget, Texture},
+};
+
+use crate::{osd, pin::CurrentPin};
+
+/// Splash shown in a persistent window while no sender is casting.
+///
+/// Text is drawn with the built-in bitmap font, characters it does not know
+/// (e.g. CJK receiver names) are rendered as `?`.
+#[derive(Clone, Debug, Default)]
+pub struct IdleScreen {
+    pub name: String,
+    pub pin: Option<CurrentPin>,
+    pub show_clock: bool,
+    pub background: Option<PathBuf>,
+}
+
+impl IdleScreen {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn pin(mut self, pin: impl Into<String>) -> Self {
+        self.pin = Some(CurrentPin::new(pin));
+        self
+    }
+
+    /// Shows a PIN that may change at runtime, see `PinManager::current`.
+    pub fn current_pin(mut self, pin: CurrentPin) -> Self {
+        self.pin = Some(pin);
+        self
+    }
+
+    pub(super) fn pin_text(&self) -> Option<String> {
+        self.pin.as_ref().map(CurrentPin::get)
+    }
+
+    pub fn show_clock(mut self, show_clock: bool) -> Self {
+        self.show_clock = show_clock;
+        self
+    }
+
+    pub fn background(mut self, background: impl Into<PathBuf>) -> Self {
+        self.background = Some(background.into());
+        self
+    }
+}
+
+fn draw_centered<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    width: u32,
+    y: i32,
+    scale: u32,
+    color: Color,
+    text: &str,
+) -> Result<(), String> {
+    let x = (width as i32 - osd::text_width(text, scale) as i32) / 2;
+    osd::draw_text(canvas, x, y, scale, color, text)
+}
+
+pub(super) fn draw_idle<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    background: Option<&Texture>,
+    idle: &IdleScreen,
+) -> Result<(), String> {
+    let (width, height) = canvas.output_size()?;
+    canvas.set_draw_color(Color::RGB(16, 16, 16));
+    canvas.clear();
+    if let Some(background) = background {
+        canvas.copy(background, None, None)?;
+    }
+    let title_scale = (width / 160).max(2);
+    let text_scale = (title_scale / 2).max(1);
+    let mut y = height as i32 / 3;
+    draw_centered(canvas, width, y, title_scale, Color::WHITE, &idle.name)?;
+    y += osd::line_height(title_scale) as i32 * 2;
+    draw_centered(
+        canvas,
+        width,
+        y,
+        text_scale,
+        Color::RGB(200, 200, 200),
+        "SCREEN MIRRORING VIA AIRPLAY",
+    )?;
+    if let Some(pin) = idle.pin_text() {
+        y += osd::line_height(text_scale) as i32 * 2;
+        draw_centered(
+            canvas,
+            width,
+            y,
+            title_scale,
+            Color::RGB(255, 200, 0),
+            &format!("PIN {pin}"),
+        )?;
+    }
+    if idle.show_clock {
+        let clock = chrono::Local::now().format("%H:%M:%S").to_string();
+        let x = width as i32 - osd::text_width(&clock, text_scale) as i32 - 16;
+        let y = height as i32 - osd::line_height(text_scale) as i32 - 16;
+        osd::draw_text(canvas, x, y, text_scale, Color::WHITE, &clock)?;
+    }
+    Ok(())
+}
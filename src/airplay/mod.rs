@@ -1,67 +1,698 @@
+mod capture;
+mod events;
 mod ffmpeg_audio;
 mod ffmpeg_sdl;
+mod h264;
+mod hooks;
+mod idle;
+mod picture;
+mod playback;
+mod replay;
+mod sink;
+mod slides;
+mod stats;
+mod status;
+mod stream;
+mod supervisor;
+mod synthetic;
+mod triple_buffer;
+pub(crate) mod workers;
 
-use std::cell::UnsafeCell;
+use std::{
+    cell::UnsafeCell,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use airplay2_protocol::airplay::airplay_consumer::AirPlayConsumer;
 use airplay2_protocol::airplay::lib::audio_stream_info::CompressionType;
 use airplay2_protocol::airplay::server::AudioPacket;
-#[cfg(windows)]
-use windows_sys::Win32::System::Power::{
-    SetThreadExecutionState, ES_CONTINUOUS, ES_DISPLAY_REQUIRED,
+use tokio::sync::{broadcast, watch};
+use tracing::Span;
+
+pub use self::capture::{CaptureOptions, AUDIO_MAGIC, VIDEO_INDEX_MAGIC};
+pub use self::events::{MediaKind, SessionEvent, SessionEventKind, SessionId};
+pub use self::hooks::{HookCommand, HookInput, HookOptions};
+pub use self::idle::IdleScreen;
+pub use self::picture::{ImageFormat, SnapshotOptions};
+pub use self::playback::{Capture, CaptureEvent, Pacing};
+pub use self::replay::ReplayOptions;
+pub use self::sink::{AudioSink, VideoSink};
+pub use self::slides::{luma_thumbnail, SlideCaptureOptions, SlideDetector, SlideOutput};
+pub use self::stats::StatsSnapshot;
+pub use self::status::{ConsumerConfig, ConsumerStatus, SessionStatus};
+pub use self::stream::{AudioCodec, AudioStreamFormat, StreamConsumer, VideoStreamFormat};
+pub use self::synthetic::{SyntheticStream, VideoSegment};
+use self::{
+    capture::StreamCapture,
+    events::SessionEvents,
+    ffmpeg_audio::{AudioCodecConfig, FfMpegAudio},
+    ffmpeg_sdl::{DecoderOptions, SdlFfmpeg, WindowOptions},
+    hooks::{HookEvent, Hooks},
+    replay::ReplayBuffer,
+    stats::{MetricsWriter, PipelineStats},
+    workers::Workers,
+};
+use crate::{
+    power::{PowerInhibitor, PowerOptions},
+    Error, Result,
 };
 
-use self::{ffmpeg_audio::FfMpegAudio, ffmpeg_sdl::SdlFfmpeg};
+/// Output gain until the sender reports its volume, the audio threads start with it.
+const DEFAULT_VOLUME: f32 = 0.5;
 
 pub struct VideoConsumer {
     audio_compression_type: UnsafeCell<CompressionType>,
     ffmpeg: SdlFfmpeg,
     ffmpeg_audio: FfMpegAudio,
+    replay: Option<Arc<ReplayBuffer>>,
+    capture: StreamCapture,
+    events: SessionEvents,
+    config: ConsumerConfig,
+    stats: Arc<PipelineStats>,
+    video_active: AtomicBool,
+    audio_active: AtomicBool,
+    session_active: watch::Sender<bool>,
+    session_started: Mutex<Option<Instant>>,
+    session_span: Mutex<Option<Span>>,
+    video_format: Mutex<Option<VideoStreamFormat>>,
+    audio_format: Mutex<Option<AudioStreamFormat>>,
+    /// Output gain as `f32` bits, see `set_volume`.
+    volume: AtomicU32,
+    muted: AtomicBool,
+    power: Option<PowerInhibitor>,
+    hooks: Arc<Hooks>,
+    workers: Workers,
 }
 
 unsafe impl Sync for VideoConsumer {}
 
 impl Default for VideoConsumer {
     fn default() -> Self {
+        VideoConsumerBuilder::new().build()
+    }
+}
+
+pub struct VideoConsumerBuilder {
+    width: u32,
+    height: u32,
+    fps: u32,
+    vsync: bool,
+    idle_screen: Option<IdleScreen>,
+    decoder_options: DecoderOptions,
+    replay: Option<ReplayOptions>,
+    capture: Option<CaptureOptions>,
+    audio_sink: Option<Arc<dyn AudioSink>>,
+    power: Option<PowerOptions>,
+    hooks: HookOptions,
+}
+
+impl Default for VideoConsumerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoConsumerBuilder {
+    pub fn new() -> Self {
         Self {
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            vsync: false,
+            idle_screen: None,
+            decoder_options: DecoderOptions::default(),
+            replay: None,
+            capture: None,
+            audio_sink: None,
+            power: None,
+            hooks: HookOptions::default(),
+        }
+    }
+
+    /// Size of the window, should match the resolution given to `AirPlayConfigBuilder`.
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Maximum frames presented per second, should match `AirPlayConfigBuilder::fps`.
+    /// `0` presents every decoded frame as soon as it arrives.
+    pub fn fps(mut self, fps: u32) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    /// Synchronizes presentation with the display refresh rate.
+    pub fn vsync(mut self, vsync: bool) -> Self {
+        self.vsync = vsync;
+        self
+    }
+
+    /// Keeps a window open between sessions showing `idle_screen`.
+    pub fn idle_screen(mut self, idle_screen: IdleScreen) -> Self {
+        self.idle_screen = Some(idle_screen);
+        self
+    }
+
+    /// Directory and image format of snapshots.
+    pub fn snapshot(mut self, snapshot: SnapshotOptions) -> Self {
+        self.decoder_options.snapshot = snapshot;
+        self
+    }
+
+    /// Saves every new slide of a presentation, see `SlideCaptureOptions`.
+    pub fn slide_capture(mut self, slides: SlideCaptureOptions) -> Self {
+        self.decoder_options.slides = Some(slides);
+        self
+    }
+
+    /// Keeps the last seconds of the stream in memory so they can be saved with
+    /// `VideoConsumer::save_replay` or F11 in the window.
+    pub fn instant_replay(mut self, replay: ReplayOptions) -> Self {
+        self.replay = Some(replay);
+        self
+    }
+
+    /// Writes the raw streams and stream formats of every session to disk, for
+    /// debugging and for the `kircast_replay` tool. Without it
+    /// `VideoConsumer::start_recording` writes to the default `CaptureOptions`.
+    pub fn capture(mut self, capture: CaptureOptions) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Hands decoded frames to `sink` instead of opening a window, for headless
+    /// use and tests.
+    pub fn video_sink(mut self, sink: Arc<dyn VideoSink>) -> Self {
+        self.decoder_options.sink = Some(sink);
+        self
+    }
+
+    /// Hands decoded audio to `sink` instead of the default output device.
+    pub fn audio_sink(mut self, sink: Arc<dyn AudioSink>) -> Self {
+        self.audio_sink = Some(sink);
+        self
+    }
+
+    /// Keeps the display awake while video is mirrored. Sessions shown in the
+    /// window do so with the default `PowerOptions`, sessions with a video sink
+    /// only if this is set.
+    pub fn power(mut self, power: PowerOptions) -> Self {
+        self.power = Some(power);
+        self
+    }
+
+    /// Runs external commands on session start, stream formats, disconnect and errors.
+    pub fn hooks(mut self, hooks: HookOptions) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn build(mut self) -> VideoConsumer {
+        let stats = Arc::new(PipelineStats::default());
+        let workers = Workers::default();
+        let hooks = Arc::new(Hooks::new(self.hooks, workers.clone()));
+        let events = SessionEvents::new(hooks.clone());
+        self.decoder_options.workers = workers.clone();
+        let replay = self
+            .replay
+            .clone()
+            .map(|options| Arc::new(ReplayBuffer::new(options, stats.clone())));
+        self.decoder_options.replay = replay.clone();
+        let config = ConsumerConfig {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            vsync: self.vsync,
+            idle_screen: self.idle_screen.is_some(),
+            snapshot: self.decoder_options.snapshot.clone(),
+            slides: self.decoder_options.slides.clone(),
+            replay: self.replay,
+            capture: self.capture.clone().unwrap_or_default(),
+            capture_enabled: self.capture.is_some(),
+            video_sink: self.decoder_options.sink.is_some(),
+            audio_sink: self.audio_sink.is_some(),
+        };
+        let capture = StreamCapture::new(
+            config.capture.clone(),
+            config.capture_enabled,
+            workers.clone(),
+        );
+        let power = match self.power {
+            Some(options) => Some(PowerInhibitor::spawn(options, &workers)),
+            None if !config.video_sink => {
+                Some(PowerInhibitor::spawn(PowerOptions::default(), &workers))
+            }
+            None => None,
+        };
+        let ffmpeg = SdlFfmpeg::new(
+            WindowOptions {
+                width: self.width,
+                height: self.height,
+                fps: self.fps,
+                vsync: self.vsync,
+                idle_screen: self.idle_screen,
+            },
+            self.decoder_options,
+            stats.clone(),
+            events.clone(),
+        );
+        ffmpeg.show_idle();
+        VideoConsumer {
             audio_compression_type: CompressionType::Alac.into(),
-            ffmpeg: SdlFfmpeg::new(1920, 1080),
-            ffmpeg_audio: FfMpegAudio::default(),
+            ffmpeg,
+            ffmpeg_audio: FfMpegAudio::new(
+                stats.clone(),
+                self.audio_sink,
+                events.clone(),
+                workers.clone(),
+            ),
+            replay,
+            capture,
+            events,
+            config,
+            stats,
+            video_active: AtomicBool::new(false),
+            audio_active: AtomicBool::new(false),
+            session_active: watch::channel(false).0,
+            session_started: Mutex::new(None),
+            session_span: Mutex::new(None),
+            video_format: Mutex::new(None),
+            audio_format: Mutex::new(None),
+            volume: AtomicU32::new(DEFAULT_VOLUME.to_bits()),
+            muted: AtomicBool::new(false),
+            power,
+            hooks,
+            workers,
         }
     }
 }
 
-impl AirPlayConsumer for VideoConsumer {
-    fn on_video(&self, bytes: &[u8]) {
+impl VideoConsumer {
+    pub fn builder() -> VideoConsumerBuilder {
+        VideoConsumerBuilder::new()
+    }
+
+    /// Whether a sender is currently streaming video or audio.
+    pub fn session_active(&self) -> watch::Receiver<bool> {
+        self.session_active.subscribe()
+    }
+
+    fn update_session_active(&self) {
+        let active =
+            self.video_active.load(Ordering::Relaxed) || self.audio_active.load(Ordering::Relaxed);
+        if !active {
+            self.capture.end();
+        }
+        let changed = self.session_active.send_if_modified(|current| {
+            let changed = *current != active;
+            *current = active;
+            changed
+        });
+        match (changed, active) {
+            (true, true) => {
+                // 上一个会话的分辨率，音频会话不会再更新
+                self.stats.stream_width.store(0, Ordering::Relaxed);
+                self.stats.stream_height.store(0, Ordering::Relaxed);
+                *self.session_started.lock().unwrap() = Some(Instant::now());
+                PipelineStats::add(&self.stats.sessions, 1);
+                let session = self.events.begin();
+                let span = tracing::info_span!(parent: None, "session", id = session);
+                span.in_scope(|| self.hooks.run(session, HookEvent::SessionStart));
+                *self.session_span.lock().unwrap() = Some(span);
+            }
+            (true, false) => {
+                let duration = self.session_started.lock().unwrap().take().map(|started| {
+                    let duration = started.elapsed();
+                    self.stats.session_durations.observe(duration);
+                    duration
+                });
+                self.session_span.lock().unwrap().take();
+                // 断开回调已进入会话 span
+                let duration_ms = duration.unwrap_or_default().as_millis() as u64;
+                self.hooks
+                    .run(self.session_id(), HookEvent::Disconnect { duration_ms });
+                self.events.emit(SessionEventKind::Ended);
+            }
+            _ => {}
+        }
+    }
+
+    /// Span of the running session, parent of its pipeline threads' spans.
+    ///
+    /// The per-packet callbacks only enter it to log an error, so streaming
+    /// takes neither its lock nor a reference per packet.
+    fn session_span(&self) -> Span {
+        self.session_span
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(Span::none)
+    }
+
+    /// The options this consumer was built with.
+    pub fn config(&self) -> &ConsumerConfig {
+        &self.config
+    }
+
+    pub fn status(&self) -> ConsumerStatus {
+        let session = self.session_started.lock().unwrap().map(|started| {
+            let width = self.stats.stream_width.load(Ordering::Relaxed);
+            let height = self.stats.stream_height.load(Ordering::Relaxed);
+            SessionStatus {
+                id: self.session_id(),
+                duration_ms: started.elapsed().as_millis() as u64,
+                video: self.video_active.load(Ordering::Relaxed),
+                audio: self.audio_active.load(Ordering::Relaxed),
+                audio_format: *self.audio_format.lock().unwrap(),
+                resolution: (width > 0 && height > 0).then_some((width, height)),
+            }
+        });
+        ConsumerStatus {
+            session,
+            volume: f32::from_bits(self.volume.load(Ordering::Relaxed)),
+            muted: self.muted.load(Ordering::Relaxed),
+            fullscreen: self.ffmpeg.is_fullscreen(),
+            recording: self.capture.is_enabled(),
+            recording_dir: self.capture.dir(),
+            stats: self.stats.snapshot(),
+        }
+    }
+
+    /// Pipeline counters in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut metrics = MetricsWriter::new();
+        metrics.stats(&self.stats);
+        metrics.gauge(
+            "session_active",
+            "Whether a sender is streaming.",
+            *self.session_active.borrow() as u8,
+        );
+        metrics.gauge(
+            "volume",
+            "Output gain from 0 to 1.",
+            f32::from_bits(self.volume.load(Ordering::Relaxed)),
+        );
+        metrics.gauge(
+            "muted",
+            "Whether the output is muted.",
+            self.muted.load(Ordering::Relaxed) as u8,
+        );
+        metrics.finish()
+    }
+
+    /// Sets the output gain from 0.0 to 1.0 until the sender changes its volume.
+    pub fn set_volume(&self, volume: f32) {
+        self.store_volume(volume);
+        self.emit_volume();
+    }
+
+    /// Silences the output without forgetting the volume.
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+        self.apply_volume();
+        self.emit_volume();
+    }
+
+    fn store_volume(&self, volume: f32) {
+        self.volume
+            .store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+        self.apply_volume();
+    }
+
+    /// Announces a volume set here in the sender's scale.
+    fn emit_volume(&self) {
+        let volume = if self.muted.load(Ordering::Relaxed) {
+            -144.0
+        } else {
+            (f32::from_bits(self.volume.load(Ordering::Relaxed)) - 1.0) * 30.0
+        };
+        self.events.emit(SessionEventKind::VolumeChanged(volume));
+    }
+
+    fn apply_volume(&self) {
+        let volume = if self.muted.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            f32::from_bits(self.volume.load(Ordering::Relaxed))
+        };
+        if let Err(err) = self.ffmpeg_audio.set_volume(volume) {
+            tracing::error!("set volume error {err:?}");
+        }
+    }
+
+    fn decoder_error(&self, media: MediaKind, err: &Error) {
+        self.events.emit(SessionEventKind::DecoderError {
+            media,
+            message: err.to_string(),
+        });
+    }
+
+    /// Captures the running and following sessions like `VideoConsumerBuilder::capture`.
+    /// Returns the directory of the running session, `None` if recording starts
+    /// with the next one.
+    pub fn start_recording(&self) -> Option<PathBuf> {
+        let capture = &self.capture;
+        capture.set_enabled(true);
+        if let Some(format) = self.video_format.lock().unwrap().clone() {
+            capture.begin();
+            capture.event(format!("video_format {format}"));
+            capture.resume_video();
+        }
+        if let Some(format) = *self.audio_format.lock().unwrap() {
+            capture.begin();
+            capture.event(format!("audio_format {format}"));
+        }
+        capture.dir()
+    }
+
+    /// Stops capturing and closes the running session's files.
+    pub fn stop_recording(&self) {
+        self.capture.set_enabled(false);
+    }
+
+    /// Switches the window to desktop fullscreen or back.
+    pub fn set_fullscreen(&self, fullscreen: bool) {
+        self.ffmpeg.set_fullscreen(fullscreen);
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.ffmpeg.is_fullscreen()
+    }
+
+    /// Ends the running session locally, as if the sender had disconnected.
+    pub fn end_session(&self) {
+        if self.video_active.load(Ordering::Relaxed) {
+            self.video_disconnect();
+        }
+        if self.audio_active.load(Ordering::Relaxed) {
+            self.audio_disconnect();
+        }
+    }
+
+    /// Typed events of all sessions, see `SessionEvent`.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Id of the running or last session, `0` before the first one.
+    pub fn session_id(&self) -> SessionId {
+        self.events.session()
+    }
+
+    /// Saves the newest decoded frame at stream resolution, also bound to F12 in
+    /// the window. Blocks until the image is written.
+    pub fn snapshot(&self) -> Result<PathBuf> {
+        if !self.video_active.load(Ordering::Relaxed) {
+            return Err(Error::Unavailable("no active video session"));
+        }
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        self.ffmpeg.snapshot(Some(tx));
+        rx.recv_timeout(Duration::from_secs(5))
+            .map_err(|_| Error::ChannelClosed(MediaKind::Video))?
+    }
+
+    /// Writes the instant-replay buffer to an MKV file, also bound to F11 in the window.
+    pub fn save_replay(&self) -> Result<PathBuf> {
+        match &self.replay {
+            Some(replay) => replay.save(),
+            None => Err(Error::Unavailable("instant replay is disabled")),
+        }
+    }
+
+    /// Ends the running session, closes the window and waits up to `timeout` for
+    /// decoder, audio, power and file writer threads. Returns `false` if some
+    /// threads were still running at the deadline. Later sessions keep the
+    /// screensaver enabled.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.end_session();
+        self.ffmpeg.close_window();
+        if let Some(power) = &self.power {
+            power.close();
+        }
+        self.workers.join_all(timeout)
+    }
+
+    /// Names of this consumer's pipeline threads that are still running.
+    pub(crate) fn running_threads(&self) -> Vec<String> {
+        self.workers.running()
+    }
+
+    /// Shows or hides the on-screen statistics overlay.
+    pub fn set_stats_overlay(&self, show: bool) {
+        self.ffmpeg.set_show_stats(show);
+    }
+}
+
+impl StreamConsumer for VideoConsumer {
+    fn video_format(&self, format: VideoStreamFormat) {
+        self.video_active.store(true, Ordering::Relaxed);
+        self.update_session_active();
+        let _session = self.session_span().entered();
+        {
+            let _format = tracing::info_span!("video_format", %format).entered();
+            if let Some(power) = &self.power {
+                power.inhibit("AirPlay 投屏中");
+            }
+            if let Some(replay) = &self.replay {
+                replay.reset_video();
+            }
+            self.capture.begin();
+            self.capture.event(format!("video_format {format}"));
+        }
+        *self.video_format.lock().unwrap() = Some(format.clone());
+        // 解码线程属于会话，不属于格式协商
+        if let Err(err) = self.ffmpeg.start() {
+            tracing::error!("start video error {err}");
+            self.decoder_error(MediaKind::Video, &err);
+        }
+        self.hooks.run(
+            self.session_id(),
+            HookEvent::VideoFormat {
+                format: format.clone(),
+            },
+        );
+        self.events.emit(SessionEventKind::VideoFormat(format));
+    }
+
+    fn video(&self, bytes: &[u8]) {
+        if let Some(replay) = &self.replay {
+            replay.push_video(bytes);
+        }
+        self.capture.video(bytes);
         if let Err(err) = self.ffmpeg.push_buffer(bytes) {
+            let _session = self.session_span().entered();
             tracing::error!("ffmpeg push_buffer error! {:?}", err);
         }
     }
 
+    fn video_disconnect(&self) {
+        let _session = self.session_span().entered();
+        self.events.emit(SessionEventKind::VideoDisconnected);
+        self.capture.event("video_disconnect".to_string());
+        self.video_format.lock().unwrap().take();
+        if let Some(power) = &self.power {
+            power.release();
+        }
+        self.ffmpeg.stop();
+        self.video_active.store(false, Ordering::Relaxed);
+        self.update_session_active();
+    }
+
+    fn audio_format(&self, format: AudioStreamFormat) {
+        self.audio_active.store(true, Ordering::Relaxed);
+        self.update_session_active();
+        let _session = self.session_span().entered();
+        let config = {
+            let _format = tracing::info_span!("audio_format", %format).entered();
+            self.capture.begin();
+            self.capture.event(format!("audio_format {format}"));
+            self.ffmpeg_audio
+                .set_samples_per_frame(format.samples_per_frame);
+            AudioCodecConfig::new(&format)
+        };
+        let result = self.ffmpeg_audio.start(&config);
+        if let Some(replay) = &self.replay {
+            replay.set_audio_config(config);
+        }
+        match result {
+            // 新的音频线程从默认音量开始
+            Ok(()) => self.apply_volume(),
+            // 没有输出设备时继续播放视频
+            Err(err @ Error::Device(_)) => {
+                tracing::warn!("没有可用的音频输出设备，只播放视频： {err}");
+                self.events.emit(SessionEventKind::AudioUnavailable {
+                    message: err.to_string(),
+                });
+            }
+            Err(err) => {
+                tracing::error!("start audio error {err}");
+                self.decoder_error(MediaKind::Audio, &err);
+            }
+        }
+        *self.audio_format.lock().unwrap() = Some(format);
+        self.hooks
+            .run(self.session_id(), HookEvent::AudioFormat { format });
+        self.events.emit(SessionEventKind::AudioFormat(format));
+    }
+
+    fn audio(&self, timestamp: u32, payload: &[u8]) {
+        if let Some(replay) = &self.replay {
+            replay.push_audio(payload);
+        }
+        self.capture.audio(timestamp, payload);
+        if let Err(err) = self.ffmpeg_audio.push_buffer(timestamp, payload) {
+            let _session = self.session_span().entered();
+            tracing::error!("ffmpeg_audio push_buffer error {err:?}");
+        }
+    }
+
+    fn audio_disconnect(&self) {
+        let _session = self.session_span().entered();
+        self.events.emit(SessionEventKind::AudioDisconnected);
+        self.capture.event("audio_disconnect".to_string());
+        self.audio_format.lock().unwrap().take();
+        self.ffmpeg_audio.stop();
+        self.audio_active.store(false, Ordering::Relaxed);
+        self.update_session_active();
+    }
+
+    fn volume(&self, volume: f32) {
+        let _session = self.session_span().entered();
+        self.capture.event(format!("volume {volume}"));
+        self.events.emit(SessionEventKind::VolumeChanged(volume));
+        // -30 到 0 对应 0 到 1，-144 为静音
+        self.store_volume(volume / 30.0 + 1.0);
+    }
+}
+
+impl AirPlayConsumer for VideoConsumer {
+    fn on_video(&self, bytes: &[u8]) {
+        self.video(bytes);
+    }
+
     fn on_video_format(
         &self,
         video_stream_info: airplay2_protocol::airplay::lib::video_stream_info::VideoStreamInfo,
     ) {
-        if cfg!(windows) {
-            unsafe {
-                SetThreadExecutionState(ES_CONTINUOUS | ES_DISPLAY_REQUIRED);
-            }
-        }
-        self.ffmpeg.start().expect("ffmpeg start error");
         tracing::info!(
             "OnVideo Format... {:?}",
             video_stream_info.get_stream_connection_id()
         );
+        self.video_format(VideoStreamFormat::from(&video_stream_info));
+        self.capture
+            .event(format!("video_stream_info {video_stream_info:?}"));
     }
 
     fn on_video_src_disconnect(&self) {
         tracing::info!("OnVideo Disconnect...");
-        if cfg!(windows) {
-            unsafe {
-                SetThreadExecutionState(ES_CONTINUOUS);
-            }
-        }
-        self.ffmpeg.stop();
+        self.video_disconnect();
     }
 
     fn on_audio_format(
@@ -69,30 +700,22 @@ impl AirPlayConsumer for VideoConsumer {
         audio_stream_info: airplay2_protocol::airplay::lib::audio_stream_info::AudioStreamInfo,
     ) {
         tracing::info!("audio_stream_info... = {:#?}", audio_stream_info);
-        self.ffmpeg_audio
-            .set_samples_per_frame(audio_stream_info.samples_per_frame);
-        let result = self.ffmpeg_audio.start(audio_stream_info.audio_format);
+        self.audio_format(AudioStreamFormat::from(&audio_stream_info));
+        self.capture
+            .event(format!("audio_stream_info {audio_stream_info:?}"));
         unsafe { *self.audio_compression_type.get() = audio_stream_info.compression_type };
-        if let Err(err) = result {
-            tracing::error!("start audio error {err:?}");
-        }
     }
 
     fn on_audio(&self, packet: &AudioPacket) {
-        if let Err(err) = self.ffmpeg_audio.push_buffer(packet) {
-            tracing::error!("ffmpeg_audio push_buffer error {err:?}");
-        }
+        self.audio(packet.timestamp(), packet.audio_buf());
     }
 
     fn on_audio_src_disconnect(&self) {
         tracing::info!("OnAudio Disconnect...");
-        self.ffmpeg_audio.stop();
+        self.audio_disconnect();
     }
 
     fn on_volume(&self, volume: f32) {
-        let volume = volume / 30.0 + 1.0;
-        if let Err(err) = self.ffmpeg_audio.set_volume(volume) {
-            tracing::error!("set volume error {err:?}");
-        }
+        self.volume(volume);
     }
 }
@@ -0,0 +1,162 @@
+use std::path::{Path, PathBuf};
+
+use ffmpeg::{codec::Id, format::Pixel, software::scaling, Error, Packet};
+use ffmpeg_next as ffmpeg;
+use serde::Serialize;
+
+/// Decodes the first frame of an image (or video) file and scales it to an
+/// RGB24 frame of `width` x `height`.
+pub(super) fn load_rgb(
+    path: &Path,
+    width: u32,
+    height: u32,
+) -> Result<ffmpeg::frame::Video, Error> {
+    let mut input = ffmpeg::format::input(path)?;
+    let stream = input
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or(Error::StreamNotFound)?;
+    let index = stream.index();
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .video()?;
+    let mut decoded = ffmpeg::frame::Video::empty();
+    let mut got_frame = false;
+    for (stream, packet) in input.packets() {
+        if stream.index() != index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        if decoder.receive_frame(&mut decoded).is_ok() {
+            got_frame = true;
+            break;
+        }
+    }
+    if !got_frame {
+        decoder.send_eof()?;
+        decoder.receive_frame(&mut decoded)?;
+    }
+    let mut scaler = scaling::Context::get(
+        decoded.format(),
+        decoded.width(),
+        decoded.height(),
+        Pixel::RGB24,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?;
+    let mut rgb_frame = ffmpeg::frame::Video::empty();
+    scaler.run(&decoded, &mut rgb_frame)?;
+    Ok(rgb_frame)
+}
+
+/// Still image formats written with ffmpeg's image encoders.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+        }
+    }
+
+    fn codec(self) -> Id {
+        match self {
+            ImageFormat::Png => Id::PNG,
+            ImageFormat::Jpeg => Id::MJPEG,
+        }
+    }
+
+    fn pixel(self) -> Pixel {
+        match self {
+            ImageFormat::Png => Pixel::RGB24,
+            ImageFormat::Jpeg => Pixel::YUVJ420P,
+        }
+    }
+}
+
+/// Encodes `frame` at its own resolution into a single image.
+pub(super) fn encode_image(
+    frame: &ffmpeg::frame::Video,
+    format: ImageFormat,
+) -> Result<Vec<u8>, Error> {
+    let codec = ffmpeg::encoder::find(format.codec()).ok_or(Error::EncoderNotFound)?;
+    let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(frame.width());
+    encoder.set_height(frame.height());
+    encoder.set_format(format.pixel());
+    encoder.set_time_base((1, 25));
+    let mut encoder = encoder.open_as(codec)?;
+
+    let mut converted = ffmpeg::frame::Video::empty();
+    let input = if frame.format() == format.pixel() {
+        frame
+    } else {
+        let mut scaler = scaling::Context::get(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            format.pixel(),
+            frame.width(),
+            frame.height(),
+            scaling::Flags::BILINEAR,
+        )?;
+        scaler.run(frame, &mut converted)?;
+        &converted
+    };
+    encoder.send_frame(input)?;
+    encoder.send_eof()?;
+    let mut packet = Packet::empty();
+    let mut image = Vec::new();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        image.extend_from_slice(packet.data().unwrap_or_default());
+    }
+    if image.is_empty() {
+        return Err(Error::InvalidData);
+    }
+    Ok(image)
+}
+
+/// Writes `frame` to `dir` under a timestamped file name and returns its path.
+pub(super) fn save_image(
+    frame: &ffmpeg::frame::Video,
+    dir: &Path,
+    prefix: &str,
+    format: ImageFormat,
+) -> crate::Result<PathBuf> {
+    let image = encode_image(frame, format).map_err(crate::Error::Encode)?;
+    std::fs::create_dir_all(dir).map_err(crate::Error::Io)?;
+    let path = dir.join(format!(
+        "{}_{}.{}",
+        prefix,
+        chrono::Local::now().format("%Y%m%d_%H%M%S_%3f"),
+        format.extension()
+    ));
+    std::fs::write(&path, image).map_err(crate::Error::Io)?;
+    Ok(path)
+}
+
+/// Where snapshots taken with F12 or `VideoConsumer::snapshot` are saved.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotOptions {
+    pub dir: PathBuf,
+    pub format: ImageFormat,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("snapshots"),
+            format: ImageFormat::Png,
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    io::{self, Read},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use super::{
+    capture::{AUDIO_MAGIC, VIDEO_INDEX_MAGIC},
+    stream::{AudioStreamFormat, StreamConsumer, VideoStreamFormat},
+};
+use crate::{Error, Result};
+
+/// One recorded session callback.
+#[derive(Clone, Debug)]
+pub enum CaptureEvent {
+    VideoFormat(VideoStreamFormat),
+    Video(Vec<u8>),
+    VideoDisconnect,
+    AudioFormat(AudioStreamFormat),
+    Audio { timestamp: u32, payload: Vec<u8> },
+    AudioDisconnect,
+    Volume(f32),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// Waits between events as long as the sender did.
+    #[default]
+    Original,
+    /// Delivers every event as soon as the previous call returned.
+    AsFastAsPossible,
+}
+
+/// A session directory written by `CaptureOptions`, ordered by arrival time.
+pub struct Capture {
+    pub events: Vec<(Duration, CaptureEvent)>,
+}
+
+impl Capture {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut events = read_session_log(&dir.join("session.log"))?;
+        events.extend(read_video(dir)?);
+        events.extend(read_audio(&dir.join("audio.bin"))?);
+        // 稳定排序，同一时刻格式事件排在数据之前
+        events.sort_by_key(|(at, _)| *at);
+        Ok(Self { events })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.events.last().map_or(Duration::ZERO, |(at, _)| *at)
+    }
+
+    /// Calls `consumer` with every recorded event, blocks until the last one
+    /// was delivered.
+    pub fn play(&self, consumer: &dyn StreamConsumer, pacing: Pacing) {
+        let started = Instant::now();
+        for (at, event) in &self.events {
+            if pacing == Pacing::Original {
+                if let Some(wait) = at.checked_sub(started.elapsed()) {
+                    std::thread::sleep(wait);
+                }
+            }
+            match event {
+                CaptureEvent::VideoFormat(format) => consumer.video_format(format.clone()),
+                CaptureEvent::Video(bytes) => consumer.video(bytes),
+                CaptureEvent::VideoDisconnect => consumer.video_disconnect(),
+                CaptureEvent::AudioFormat(format) => consumer.audio_format(*format),
+                CaptureEvent::Audio { timestamp, payload } => consumer.audio(*timestamp, payload),
+                CaptureEvent::AudioDisconnect => consumer.audio_disconnect(),
+                CaptureEvent::Volume(volume) => consumer.volume(*volume),
+            }
+        }
+    }
+}
+
+fn read_error(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |err| Error::InvalidCapture(format!("read {path:?}: {err}"))
+}
+
+fn read_session_log(path: &Path) -> Result<Vec<(Duration, CaptureEvent)>> {
+    let log = std::fs::read_to_string(path).map_err(read_error(path))?;
+    let mut events = Vec::new();
+    for line in log.lines() {
+        let Some((at, record)) = line.split_once(' ') else {
+            continue;
+        };
+        // 没有时间戳的行是说明信息，例如达到大小上限
+        let Ok(at) = at.parse::<u64>() else {
+            continue;
+        };
+        let (kind, args) = record.split_once(' ').unwrap_or((record, ""));
+        let event = match kind {
+            "video_format" => CaptureEvent::VideoFormat(args.parse()?),
+            "video_disconnect" => CaptureEvent::VideoDisconnect,
+            "audio_format" => CaptureEvent::AudioFormat(args.parse()?),
+            "audio_disconnect" => CaptureEvent::AudioDisconnect,
+            "volume" => CaptureEvent::Volume(
+                args.parse()
+                    .map_err(|_| Error::InvalidCapture(format!("volume {args:?}")))?,
+            ),
+            // 发送端原始的流信息，只供排查，回放使用上面的格式记录
+            "video_stream_info" | "audio_stream_info" => continue,
+            _ => {
+                tracing::warn!("unknown capture record {line:?}");
+                continue;
+            }
+        };
+        events.push((Duration::from_micros(at), event));
+    }
+    Ok(events)
+}
+
+fn read_magic(reader: &mut impl Read, magic: &[u8; 8], path: &Path) -> Result<()> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).map_err(read_error(path))?;
+    if &header != magic {
+        return Err(Error::InvalidCapture(format!(
+            "{path:?} is not a capture file"
+        )));
+    }
+    Ok(())
+}
+
+/// Fills `buf`, returns `false` at the end of a file truncated by the size limit.
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<Option<[u8; N]>> {
+    let mut buf = [0; N];
+    Ok(read_exact_or_eof(reader, &mut buf)?.then_some(buf))
+}
+
+fn read_video(dir: &Path) -> Result<Vec<(Duration, CaptureEvent)>> {
+    let index_path = dir.join("video.idx");
+    let stream_path = dir.join("video.h264");
+    let mut index =
+        io::BufReader::new(std::fs::File::open(&index_path).map_err(read_error(&index_path))?);
+    read_magic(&mut index, VIDEO_INDEX_MAGIC, &index_path)?;
+    let stream = std::fs::read(&stream_path).map_err(read_error(&stream_path))?;
+    let mut events = Vec::new();
+    let mut offset = 0;
+    let next = |index: &mut io::BufReader<_>| -> Result<_> {
+        let at = read_array::<8>(index).map_err(read_error(&index_path))?;
+        let len = read_array::<4>(index).map_err(read_error(&index_path))?;
+        Ok((at, len))
+    };
+    while let (Some(at), Some(len)) = next(&mut index)? {
+        let len = u32::from_le_bytes(len) as usize;
+        let Some(bytes) = stream.get(offset..offset + len) else {
+            break;
+        };
+        offset += len;
+        events.push((
+            Duration::from_micros(u64::from_le_bytes(at)),
+            CaptureEvent::Video(bytes.to_vec()),
+        ));
+    }
+    Ok(events)
+}
+
+fn read_audio(path: &Path) -> Result<Vec<(Duration, CaptureEvent)>> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path).map_err(read_error(path))?);
+    read_magic(&mut reader, AUDIO_MAGIC, path)?;
+    let mut events = Vec::new();
+    while let Some(header) = read_array::<16>(&mut reader).map_err(read_error(path))? {
+        let at = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let timestamp = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(header[12..].try_into().unwrap()) as usize;
+        let mut payload = vec![0; len];
+        if !read_exact_or_eof(&mut reader, &mut payload).map_err(read_error(path))? {
+            break;
+        }
+        events.push((
+            Duration::from_micros(at),
+            CaptureEvent::Audio { timestamp, payload },
+        ));
+    }
+    Ok(events)
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ffmpeg::{
+    codec::{Id, Parameters},
+    Packet, Rational,
+};
+use ffmpeg_next as ffmpeg;
+use ffmpeg_sys_next as sys;
+use serde::Serialize;
+
+use super::{ffmpeg_audio::AudioCodecConfig, h264, stats::PipelineStats, MediaKind};
+use crate::{Error, Result};
+
+const TIME_BASE: Rational = Rational(1, 1000);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ReplayOptions {
+    /// How much of the stream is kept in memory.
+    pub duration: Duration,
+    /// Upper bound of the buffered video and audio. AirPlay mirroring rarely
+    /// sends another IDR after the first one, the frames after it are then
+    /// dropped to stay within `duration` and this bound, and a replay shows
+    /// decoding errors for a moment after its first frame.
+    pub max_bytes: u64,
+    pub dir: PathBuf,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(30),
+            max_bytes: 64 * 1024 * 1024,
+            dir: PathBuf::from("replays"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct BufferedPacket {
+    at: Duration,
+    data: Vec<u8>,
+    keyframe: bool,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    video: VecDeque<BufferedPacket>,
+    audio: VecDeque<BufferedPacket>,
+    /// SPS/PPS of the buffered video, written as codec private data.
+    parameter_sets: Vec<u8>,
+    audio_config: Option<AudioCodecConfig>,
+    /// Size of `video` and `audio`.
+    bytes: u64,
+}
+
+impl ReplayState {
+    fn push(&mut self, media: MediaKind, packet: BufferedPacket) {
+        self.bytes += packet.data.len() as u64;
+        match media {
+            MediaKind::Video => self.video.push_back(packet),
+            MediaKind::Audio => self.audio.push_back(packet),
+        }
+    }
+
+    fn remove_video(&mut self, index: usize) {
+        if let Some(packet) = self.video.remove(index) {
+            self.bytes -= packet.data.len() as u64;
+        }
+    }
+
+    /// Drops the first `count` video packets.
+    fn drop_video(&mut self, count: usize) {
+        let dropped: usize = self.video.drain(..count).map(|p| p.data.len()).sum();
+        self.bytes -= dropped as u64;
+    }
+
+    fn pop_audio(&mut self) {
+        if let Some(packet) = self.audio.pop_front() {
+            self.bytes -= packet.data.len() as u64;
+        }
+    }
+
+    fn clear_video(&mut self) {
+        self.drop_video(self.video.len());
+    }
+
+    fn clear_audio(&mut self) {
+        while !self.audio.is_empty() {
+            self.pop_audio();
+        }
+    }
+}
+
+/// Keeps the last `duration` of compressed video and audio so it can be written
+/// to an MKV file without re-encoding. Video always starts at a keyframe.
+pub(super) struct ReplayBuffer {
+    options: ReplayOptions,
+    epoch: Instant,
+    state: Mutex<ReplayState>,
+    stats: Arc<PipelineStats>,
+}
+
+impl ReplayBuffer {
+    pub fn new(options: ReplayOptions, stats: Arc<PipelineStats>) -> Self {
+        Self {
+            options,
+            epoch: Instant::now(),
+            state: Mutex::new(ReplayState::default()),
+            stats,
+        }
+    }
+
+    /// Forgets the buffered video, called when a new video stream starts.
+    pub fn reset_video(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.clear_video();
+        state.parameter_sets.clear();
+        self.update_stats(&state);
+    }
+
+    /// Called when a new audio stream starts.
+    pub fn set_audio_config(&self, config: AudioCodecConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.clear_audio();
+        state.audio_config = Some(config);
+        self.update_stats(&state);
+    }
+
+    pub fn push_video(&self, data: &[u8]) {
+        let at = self.epoch.elapsed();
+        let parameter_sets = h264::parameter_sets(data);
+        let keyframe = h264::is_keyframe(data);
+        let mut state = self.state.lock().unwrap();
+        if !parameter_sets.is_empty() && parameter_sets != state.parameter_sets {
+            // 分辨率等参数变化，之前的数据无法与新参数一起封装
+            state.clear_video();
+            state.parameter_sets = parameter_sets;
+        }
+        if state.video.is_empty() && !keyframe {
+            return;
+        }
+        state.push(
+            MediaKind::Video,
+            BufferedPacket {
+                at,
+                data: data.to_vec(),
+                keyframe,
+            },
+        );
+        self.trim(&mut state, at);
+    }
+
+    pub fn push_audio(&self, data: &[u8]) {
+        let at = self.epoch.elapsed();
+        let mut state = self.state.lock().unwrap();
+        if state.audio_config.is_none() {
+            return;
+        }
+        state.push(
+            MediaKind::Audio,
+            BufferedPacket {
+                at,
+                data: data.to_vec(),
+                keyframe: true,
+            },
+        );
+        self.trim(&mut state, at);
+    }
+
+    fn trim(&self, state: &mut ReplayState, now: Duration) {
+        let cutoff = now.saturating_sub(self.options.duration);
+        // 保留截止时间之前最近的关键帧，保证文件从关键帧开始
+        let start = state
+            .video
+            .iter()
+            .rposition(|packet| packet.keyframe && packet.at <= cutoff);
+        if let Some(start) = start {
+            state.drop_video(start);
+        }
+        if state.bytes > self.options.max_bytes {
+            // 超出上限时从最新的关键帧开始
+            let newest = state.video.iter().rposition(|packet| packet.keyframe);
+            if let Some(newest) = newest.filter(|newest| *newest > 0) {
+                state.drop_video(newest);
+            }
+        }
+        // 投屏很少再发送 IDR，保留唯一的关键帧，丢弃它之后过期或超出上限的帧。
+        // 回放开头会短暂花屏，但不会丢掉全部视频
+        let mut dropped = false;
+        while state.video.len() > 1
+            && (state.video[1].at <= cutoff || state.bytes > self.options.max_bytes)
+        {
+            state.remove_video(1);
+            dropped = true;
+        }
+        if dropped {
+            // 关键帧紧接在保留的帧之前显示
+            if let Some(next) = state.video.get(1).map(|packet| packet.at) {
+                state.video[0].at = next.saturating_sub(Duration::from_millis(1));
+            }
+            tracing::debug!("回放缓冲没有更新的关键帧，已丢弃较早的帧");
+        }
+        let audio_cutoff = state.video.front().map_or(cutoff, |packet| packet.at);
+        while state
+            .audio
+            .front()
+            .is_some_and(|packet| packet.at < audio_cutoff || state.bytes > self.options.max_bytes)
+        {
+            state.pop_audio();
+        }
+        self.update_stats(state);
+    }
+
+    fn update_stats(&self, state: &ReplayState) {
+        self.stats
+            .replay_bytes
+            .store(state.bytes, Ordering::Relaxed);
+    }
+
+    /// Writes the buffered stream to a timestamped MKV file in `dir`, saves in
+    /// the same millisecond get a counter.
+    pub fn save(&self) -> Result<PathBuf> {
+        let (video, audio, parameter_sets, audio_config) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.video.clone(),
+                state.audio.clone(),
+                state.parameter_sets.clone(),
+                state.audio_config.clone(),
+            )
+        };
+        if video.is_empty() && audio.is_empty() {
+            return Err(Error::Unavailable("replay buffer is empty"));
+        }
+        std::fs::create_dir_all(&self.options.dir).map_err(Error::Io)?;
+        let stem = format!(
+            "replay_{}",
+            chrono::Local::now().format("%Y%m%d_%H%M%S_%3f")
+        );
+        // 同一毫秒内多次保存时加序号，不覆盖之前的文件
+        let path = (0..)
+            .map(|n| match n {
+                0 => self.options.dir.join(format!("{stem}.mkv")),
+                n => self.options.dir.join(format!("{stem}_{n}.mkv")),
+            })
+            .find(|path| !path.exists())
+            .unwrap();
+        self.write(
+            &path,
+            &video,
+            &audio,
+            &parameter_sets,
+            audio_config.as_ref(),
+        )
+        .map_err(Error::Encode)?;
+        tracing::info!("replay saved to {path:?}");
+        Ok(path)
+    }
+
+    fn write(
+        &self,
+        path: &Path,
+        video: &VecDeque<BufferedPacket>,
+        audio: &VecDeque<BufferedPacket>,
+        parameter_sets: &[u8],
+        audio_config: Option<&AudioCodecConfig>,
+    ) -> Result<(), ffmpeg::Error> {
+        let mut output = ffmpeg::format::output(path)?;
+        let video_index = if video.is_empty() {
+            None
+        } else {
+            let mut stream = output.add_stream(Id::None)?;
+            stream.set_parameters(self.video_parameters(parameter_sets));
+            stream.set_time_base(TIME_BASE);
+            Some(stream.index())
+        };
+        let audio_index = match (audio_config, audio.is_empty()) {
+            (Some(config), false) => {
+                let mut stream = output.add_stream(Id::None)?;
+                stream.set_parameters(config.parameters());
+                stream.set_time_base(TIME_BASE);
+                Some(stream.index())
+            }
+            _ => None,
+        };
+        output.write_header()?;
+
+        let mut packets: Vec<(usize, BufferedPacket)> = video_index
+            .into_iter()
+            .flat_map(|index| video.iter().map(move |packet| (index, packet.clone())))
+            .chain(
+                audio_index
+                    .into_iter()
+                    .flat_map(|index| audio.iter().map(move |packet| (index, packet.clone()))),
+            )
+            .collect();
+        packets.sort_by_key(|(_, packet)| packet.at);
+        let base = packets
+            .first()
+            .map_or(Duration::ZERO, |(_, packet)| packet.at);
+        for (index, buffered) in packets {
+            let time_base = output.stream(index).unwrap().time_base();
+            let ts = (buffered.at - base).as_millis() as i64;
+            let mut packet = Packet::copy(&buffered.data);
+            packet.set_stream(index);
+            packet.set_pts(Some(ts));
+            packet.set_dts(Some(ts));
+            if buffered.keyframe {
+                packet.set_flags(ffmpeg::packet::Flags::KEY);
+            }
+            packet.rescale_ts(TIME_BASE, time_base);
+            packet.write_interleaved(&mut output)?;
+        }
+        output.write_trailer()
+    }
+
+    fn video_parameters(&self, parameter_sets: &[u8]) -> Parameters {
+        let mut parameters = Parameters::new();
+        unsafe {
+            let par = parameters.as_mut_ptr();
+            (*par).codec_type = sys::AVMediaType::AVMEDIA_TYPE_VIDEO;
+            (*par).codec_id = sys::AVCodecID::AV_CODEC_ID_H264;
+            (*par).width = self.stats.stream_width.load(Ordering::Relaxed) as i32;
+            (*par).height = self.stats.stream_height.load(Ordering::Relaxed) as i32;
+            if !parameter_sets.is_empty() {
+                let extradata = sys::av_mallocz(
+                    parameter_sets.len() + sys::AV_INPUT_BUFFER_PADDING_SIZE as usize,
+                ) as *mut u8;
+                std::ptr::copy_nonoverlapping(
+                    parameter_sets.as_ptr(),
+                    extradata,
+                    parameter_sets.len(),
+                );
+                (*par).extradata = extradata;
+                (*par).extradata_size = parameter_sets.len() as i32;
+            }
+        }
+        parameters
+    }
+}
@@ -0,0 +1,30 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::Result;
+
+/// Receives decoded video instead of the window, see `VideoConsumerBuilder::video_sink`.
+///
+/// Called from the decoder thread, slow implementations delay decoding.
+pub trait VideoSink: Send + Sync {
+    /// A decoded frame in stream resolution and decoder pixel format.
+    fn on_frame(&self, frame: &ffmpeg::frame::Video);
+
+    /// The decoder thread of a session is about to exit.
+    fn on_end(&self) {}
+}
+
+/// Receives decoded audio instead of the output device, see
+/// `VideoConsumerBuilder::audio_sink`.
+pub trait AudioSink: Send + Sync {
+    /// An audio stream is about to start. Fail with `Error::Device` if the output
+    /// is not available, the session then plays video only.
+    fn on_start(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Interleaved PCM of one audio frame with the volume applied.
+    fn on_pcm(&self, samples: &[i16], sample_rate: u32, channels: u16);
+
+    /// The audio thread of a session is about to exit.
+    fn on_end(&self) {}
+}
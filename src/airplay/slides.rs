@@ -0,0 +1,378 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, Sender};
+use ffmpeg_next as ffmpeg;
+use serde::Serialize;
+
+use super::{
+    picture::{self, ImageFormat},
+    workers::Workers,
+};
+use crate::{Error, Result};
+
+const THUMB_WIDTH: usize = 64;
+const THUMB_HEIGHT: usize = 36;
+
+/// How captured slides are kept once the session ends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlideOutput {
+    /// One PDF with a page per slide, the page footer shows when it was captured.
+    /// Each slide is written as a JPEG when it settles and the PDF is built from
+    /// those files at session end.
+    #[default]
+    Pdf,
+    /// Numbered PNG files plus a `slides.txt` index with timestamps.
+    Images,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SlideCaptureOptions {
+    /// Every session writes into its own timestamped directory below `dir`.
+    pub dir: PathBuf,
+    /// Mean luma difference (0-255) from the previous slide for a picture to count as new.
+    pub change_threshold: f32,
+    /// Mean luma difference below which two consecutive samples count as unchanged.
+    pub noise_threshold: f32,
+    /// How long the picture must stay unchanged before it is captured.
+    pub stable_for: Duration,
+    /// Minimum time between two analysed frames.
+    pub sample_interval: Duration,
+    pub output: SlideOutput,
+}
+
+impl Default for SlideCaptureOptions {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("slides"),
+            change_threshold: 8.0,
+            noise_threshold: 1.5,
+            stable_for: Duration::from_millis(1500),
+            sample_interval: Duration::from_millis(200),
+            output: SlideOutput::Pdf,
+        }
+    }
+}
+
+/// Downscales a luma plane to a small grayscale thumbnail by block averaging.
+pub fn luma_thumbnail(luma: &[u8], stride: usize, width: usize, height: usize) -> Vec<u8> {
+    let mut thumb = Vec::with_capacity(THUMB_WIDTH * THUMB_HEIGHT);
+    for ty in 0..THUMB_HEIGHT {
+        let y0 = ty * height / THUMB_HEIGHT;
+        let y1 = ((ty + 1) * height / THUMB_HEIGHT).max(y0 + 1).min(height);
+        for tx in 0..THUMB_WIDTH {
+            let x0 = tx * width / THUMB_WIDTH;
+            let x1 = ((tx + 1) * width / THUMB_WIDTH).max(x0 + 1).min(width);
+            let mut sum = 0u32;
+            let mut count = 0u32;
+            // 隔行隔列采样，足够判断画面变化
+            for y in (y0..y1).step_by(2) {
+                let row = &luma[y * stride..];
+                for x in (x0..x1).step_by(2) {
+                    sum += row[x] as u32;
+                    count += 1;
+                }
+            }
+            thumb.push((sum / count.max(1)) as u8);
+        }
+    }
+    thumb
+}
+
+fn mean_difference(a: &[u8], b: &[u8]) -> f32 {
+    let total: u32 = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs())
+        .sum();
+    total as f32 / a.len().max(1) as f32
+}
+
+/// Detects slides in a sequence of thumbnails: a picture that differs from the
+/// last slide and then stays unchanged for `stable_for`.
+pub struct SlideDetector {
+    change_threshold: f32,
+    noise_threshold: f32,
+    stable_for: Duration,
+    last_slide: Option<Vec<u8>>,
+    candidate: Option<(Vec<u8>, Duration)>,
+}
+
+impl SlideDetector {
+    pub fn new(options: &SlideCaptureOptions) -> Self {
+        Self {
+            change_threshold: options.change_threshold,
+            noise_threshold: options.noise_threshold,
+            stable_for: options.stable_for,
+            last_slide: None,
+            candidate: None,
+        }
+    }
+
+    /// Feeds the thumbnail of the frame shown at `at`, returns `true` when this
+    /// frame completes a new slide.
+    pub fn push(&mut self, at: Duration, thumb: &[u8]) -> bool {
+        match &self.candidate {
+            Some((candidate, _)) if mean_difference(candidate, thumb) < self.noise_threshold => {
+                self.settle(at)
+            }
+            _ => {
+                self.candidate = Some((thumb.to_vec(), at));
+                false
+            }
+        }
+    }
+
+    /// Returns `true` if the last pushed picture, shown unchanged until `at`, is
+    /// a new slide. Senders stop sending frames for a still picture, so call this
+    /// before pushing a changed picture and when the session ends.
+    pub fn settle(&mut self, at: Duration) -> bool {
+        let Some((candidate, since)) = &self.candidate else {
+            return false;
+        };
+        if at.saturating_sub(*since) < self.stable_for {
+            return false;
+        }
+        let is_new = match &self.last_slide {
+            Some(last) => mean_difference(last, candidate) >= self.change_threshold,
+            None => true,
+        };
+        if is_new {
+            self.last_slide = Some(candidate.clone());
+        }
+        is_new
+    }
+}
+
+enum SlideJob {
+    Slide(ffmpeg::frame::Video, Duration),
+    Finish,
+}
+
+/// Runs slide detection for one session in the decoder thread, images are
+/// encoded and written in a worker thread.
+pub(super) struct SlideCapture {
+    detector: SlideDetector,
+    sample_interval: Duration,
+    started: Instant,
+    last_sample: Option<Instant>,
+    /// The last analysed frame, it shows the detector's candidate picture.
+    last_frame: Option<ffmpeg::frame::Video>,
+    jobs: Sender<SlideJob>,
+}
+
+impl SlideCapture {
+    pub fn new(options: &SlideCaptureOptions, workers: &Workers) -> Self {
+        let (jobs, rx) = crossbeam::channel::unbounded();
+        let dir = options
+            .dir
+            .join(chrono::Local::now().format("%Y%m%d_%H%M%S").to_string());
+        let output = options.output;
+        workers.spawn("slide-writer", move || write_slides(rx, dir, output));
+        Self {
+            detector: SlideDetector::new(options),
+            sample_interval: options.sample_interval,
+            started: Instant::now(),
+            last_sample: None,
+            last_frame: None,
+            jobs,
+        }
+    }
+
+    pub fn on_frame(&mut self, frame: &ffmpeg::frame::Video) {
+        if self
+            .last_sample
+            .is_some_and(|last| last.elapsed() < self.sample_interval)
+        {
+            return;
+        }
+        self.last_sample = Some(Instant::now());
+        let thumb = luma_thumbnail(
+            frame.data(0),
+            frame.stride(0),
+            frame.width() as usize,
+            frame.height() as usize,
+        );
+        let at = self.started.elapsed();
+        // 上一张画面可能一直没有变化，直到这一帧才改变
+        self.settle(at);
+        if self.detector.push(at, &thumb) {
+            let _ = self.jobs.send(SlideJob::Slide(frame.clone(), at));
+        }
+        self.last_frame = Some(frame.clone());
+    }
+
+    fn settle(&mut self, at: Duration) {
+        if self.detector.settle(at) {
+            if let Some(frame) = self.last_frame.take() {
+                let _ = self.jobs.send(SlideJob::Slide(frame, at));
+            }
+        }
+    }
+}
+
+impl Drop for SlideCapture {
+    fn drop(&mut self) {
+        // 会话结束时最后一张幻灯片可能还没有判定
+        self.settle(self.started.elapsed());
+        let _ = self.jobs.send(SlideJob::Finish);
+    }
+}
+
+fn format_offset(at: Duration) -> String {
+    let secs = at.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+struct Slide {
+    width: u32,
+    height: u32,
+    path: PathBuf,
+    label: String,
+}
+
+fn write_slides(rx: Receiver<SlideJob>, dir: PathBuf, output: SlideOutput) {
+    let mut slides = Vec::new();
+    let mut index = String::new();
+    let mut number = 0;
+    while let Ok(job) = rx.recv() {
+        match job {
+            SlideJob::Slide(frame, at) => {
+                number += 1;
+                let label = format!("SLIDE {} {}", number, format_offset(at));
+                match output {
+                    // 每张幻灯片确定后立即落盘，会话中途退出也不会丢失
+                    SlideOutput::Pdf => {
+                        match save_numbered(&frame, &dir, number, ImageFormat::Jpeg) {
+                            Ok(name) => slides.push(Slide {
+                                width: frame.width(),
+                                height: frame.height(),
+                                path: dir.join(name),
+                                label,
+                            }),
+                            Err(err) => tracing::error!("save slide error {err:?}"),
+                        }
+                    }
+                    SlideOutput::Images => {
+                        match save_numbered(&frame, &dir, number, ImageFormat::Png) {
+                            Ok(name) => {
+                                let _ = writeln!(index, "{} {}", format_offset(at), name);
+                            }
+                            Err(err) => tracing::error!("save slide error {err:?}"),
+                        }
+                    }
+                }
+            }
+            SlideJob::Finish => break,
+        }
+    }
+    let result = match output {
+        SlideOutput::Pdf if !slides.is_empty() => write_pdf(&dir.join("slides.pdf"), &slides),
+        SlideOutput::Images if !index.is_empty() => std::fs::write(dir.join("slides.txt"), index),
+        _ => return,
+    };
+    match result {
+        Ok(()) => tracing::info!("slides saved to {dir:?}"),
+        Err(err) => tracing::error!("write slides error {err}"),
+    }
+}
+
+fn save_numbered(
+    frame: &ffmpeg::frame::Video,
+    dir: &Path,
+    number: usize,
+    format: ImageFormat,
+) -> Result<String> {
+    let image = picture::encode_image(frame, format).map_err(Error::Encode)?;
+    std::fs::create_dir_all(dir).map_err(Error::Io)?;
+    let name = format!("slide_{number:03}.{}", format.extension());
+    std::fs::write(dir.join(&name), image).map_err(Error::Io)?;
+    Ok(name)
+}
+
+fn pdf_escape(text: &str) -> String {
+    text.chars()
+        .filter(char::is_ascii)
+        .flat_map(|c| match c {
+            '(' | ')' | '\\' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect()
+}
+
+/// Writes a minimal PDF with one JPEG image per page and a caption below it,
+/// reading the images back from the files saved while the session ran.
+fn write_pdf(path: &Path, slides: &[Slide]) -> std::io::Result<()> {
+    const FOOTER: u32 = 32;
+    let mut pdf: Vec<u8> = Vec::new();
+    let mut offsets = Vec::new();
+    let page_ids: Vec<usize> = (0..slides.len()).map(|i| 4 + i * 3).collect();
+
+    pdf.extend_from_slice(b"%PDF-1.4\n");
+    offsets.push(pdf.len());
+    pdf.extend_from_slice(b"1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n");
+    offsets.push(pdf.len());
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{id} 0 R")).collect();
+    write!(
+        pdf,
+        "2 0 obj\n<< /Type /Pages /Kids [{}] /Count {} >>\nendobj\n",
+        kids.join(" "),
+        slides.len()
+    )?;
+    offsets.push(pdf.len());
+    pdf.extend_from_slice(
+        b"3 0 obj\n<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>\nendobj\n",
+    );
+    for (slide, page_id) in slides.iter().zip(&page_ids) {
+        let (image_id, content_id) = (page_id + 1, page_id + 2);
+        let image = std::fs::read(&slide.path)?;
+        let page_height = slide.height + FOOTER;
+        offsets.push(pdf.len());
+        write!(
+            pdf,
+            "{page_id} 0 obj\n<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {page_height}] \
+             /Resources << /Font << /F1 3 0 R >> /XObject << /Im0 {image_id} 0 R >> >> \
+             /Contents {content_id} 0 R >>\nendobj\n",
+            slide.width
+        )?;
+        offsets.push(pdf.len());
+        write!(
+            pdf,
+            "{image_id} 0 obj\n<< /Type /XObject /Subtype /Image /Width {} /Height {} \
+             /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+            slide.width,
+            slide.height,
+            image.len()
+        )?;
+        pdf.extend_from_slice(&image);
+        pdf.extend_from_slice(b"\nendstream\nendobj\n");
+        let content = format!(
+            "q {} 0 0 {} 0 {FOOTER} cm /Im0 Do Q BT /F1 16 Tf 10 10 Td ({}) Tj ET",
+            slide.width,
+            slide.height,
+            pdf_escape(&slide.label)
+        );
+        offsets.push(pdf.len());
+        write!(
+            pdf,
+            "{content_id} 0 obj\n<< /Length {} >>\nstream\n{content}\nendstream\nendobj\n",
+            content.len()
+        )?;
+    }
+    let xref = pdf.len();
+    write!(pdf, "xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1)?;
+    for offset in &offsets {
+        write!(pdf, "{offset:010} 00000 n \n")?;
+    }
+    write!(
+        pdf,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+        offsets.len() + 1
+    )?;
+    std::fs::write(path, pdf)
+}
@@ -0,0 +1,365 @@
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Counters shared between the decoder, audio and render threads.
+///
+/// Every field is written from the thread that owns the data and only read by
+/// the overlay, so relaxed ordering is enough.
+#[derive(Default)]
+pub(crate) struct PipelineStats {
+    pub video_packets: AtomicU64,
+    pub video_bytes: AtomicU64,
+    pub decoded_frames: AtomicU64,
+    pub presented_frames: AtomicU64,
+    pub dropped_frames: AtomicU64,
+    pub corrupt_frames: AtomicU64,
+    /// Time from the end of decoding to `present` of the last shown frame.
+    pub present_latency_us: AtomicU64,
+    pub queue_depth: AtomicUsize,
+    pub stream_width: AtomicU32,
+    pub stream_height: AtomicU32,
+    pub audio_format: Mutex<String>,
+    pub ring_fill: AtomicUsize,
+    pub ring_capacity: AtomicUsize,
+    pub output_rate: AtomicU32,
+    pub audio_buffered_us: AtomicU64,
+    pub video_decoder_errors: AtomicU64,
+    pub audio_decoder_errors: AtomicU64,
+    /// Pipelines rebuilt after an error or panic, see `supervisor::supervise`.
+    pub video_decoder_restarts: AtomicU64,
+    pub audio_decoder_restarts: AtomicU64,
+    /// Video windows reopened after an error or panic.
+    pub renderer_restarts: AtomicU64,
+    /// Output callbacks that found the ring buffer empty before the request was filled.
+    pub audio_underruns: AtomicU64,
+    /// Decoded frames cut short because the ring buffer was full.
+    pub audio_overruns: AtomicU64,
+    /// Compressed video and audio held by the instant-replay buffer.
+    pub replay_bytes: AtomicU64,
+    pub sessions: AtomicU64,
+    pub session_durations: Histogram,
+}
+
+impl PipelineStats {
+    #[inline]
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Counts a received video packet, the overlay derives its bitrate from the bytes.
+    pub fn count_video_packet(&self, len: usize) {
+        Self::add(&self.video_packets, 1);
+        Self::add(&self.video_bytes, len as u64);
+    }
+
+    pub fn set_audio_format(&self, desc: String) {
+        *self.audio_format.lock().unwrap() = desc;
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            video_packets: self.video_packets.load(Ordering::Relaxed),
+            video_bytes: self.video_bytes.load(Ordering::Relaxed),
+            decoded_frames: self.decoded_frames.load(Ordering::Relaxed),
+            presented_frames: self.presented_frames.load(Ordering::Relaxed),
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+            corrupt_frames: self.corrupt_frames.load(Ordering::Relaxed),
+            present_latency_us: self.present_latency_us.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            ring_fill: self.ring_fill.load(Ordering::Relaxed),
+            ring_capacity: self.ring_capacity.load(Ordering::Relaxed),
+            output_rate: self.output_rate.load(Ordering::Relaxed),
+            audio_buffered_us: self.audio_buffered_us.load(Ordering::Relaxed),
+            replay_bytes: self.replay_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Upper bounds of the session duration buckets in seconds.
+const SESSION_BUCKETS: [f64; 8] = [10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0];
+
+/// Lock-free Prometheus histogram over `SESSION_BUCKETS`.
+#[derive(Default)]
+pub(crate) struct Histogram {
+    /// Cumulative counts, the last one is `+Inf`.
+    buckets: [AtomicU64; SESSION_BUCKETS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(SESSION_BUCKETS.iter()) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.buckets[SESSION_BUCKETS.len()].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms
+            .fetch_add(value.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Writes metrics in the Prometheus text exposition format.
+pub(crate) struct MetricsWriter(String);
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        Self(String::new())
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP kircast_{name} {help}");
+        let _ = writeln!(self.0, "# TYPE kircast_{name} {kind}");
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.0, "kircast_{name} {value}");
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl std::fmt::Display) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.0, "kircast_{name} {value}");
+    }
+
+    /// A counter with one sample per `(label value, value)`.
+    pub fn labeled_counter(&mut self, name: &str, help: &str, label: &str, values: &[(&str, u64)]) {
+        self.header(name, "counter", help);
+        for (label_value, value) in values {
+            let _ = writeln!(
+                self.0,
+                "kircast_{name}{{{label}=\"{label_value}\"}} {value}"
+            );
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+        let count = histogram.buckets[SESSION_BUCKETS.len()].load(Ordering::Relaxed);
+        for (bucket, bound) in histogram.buckets.iter().zip(SESSION_BUCKETS.iter()) {
+            let value = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(self.0, "kircast_{name}_bucket{{le=\"{bound}\"}} {value}");
+        }
+        let _ = writeln!(self.0, "kircast_{name}_bucket{{le=\"+Inf\"}} {count}");
+        let sum = histogram.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        let _ = writeln!(self.0, "kircast_{name}_sum {sum}");
+        let _ = writeln!(self.0, "kircast_{name}_count {count}");
+    }
+
+    /// Adds the counters of `stats`.
+    pub fn stats(&mut self, stats: &PipelineStats) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        self.counter("sessions_total", "Sessions started.", load(&stats.sessions));
+        self.histogram(
+            "session_duration_seconds",
+            "Duration of ended sessions.",
+            &stats.session_durations,
+        );
+        self.counter(
+            "video_packets_total",
+            "Video buffers received.",
+            load(&stats.video_packets),
+        );
+        self.counter(
+            "video_bytes_total",
+            "Video bytes received.",
+            load(&stats.video_bytes),
+        );
+        self.counter(
+            "decoded_frames_total",
+            "Video frames decoded.",
+            load(&stats.decoded_frames),
+        );
+        self.counter(
+            "presented_frames_total",
+            "Video frames shown.",
+            load(&stats.presented_frames),
+        );
+        self.counter(
+            "dropped_frames_total",
+            "Decoded frames replaced by newer ones before being shown.",
+            load(&stats.dropped_frames),
+        );
+        self.counter(
+            "corrupt_frames_total",
+            "Video packets or frames the decoder reported as broken.",
+            load(&stats.corrupt_frames),
+        );
+        self.labeled_counter(
+            "decoder_errors_total",
+            "Packets rejected by the decoder.",
+            "media",
+            &[
+                ("video", load(&stats.video_decoder_errors)),
+                ("audio", load(&stats.audio_decoder_errors)),
+            ],
+        );
+        self.labeled_counter(
+            "decoder_restarts_total",
+            "Decoder pipelines rebuilt after an error or panic.",
+            "media",
+            &[
+                ("video", load(&stats.video_decoder_restarts)),
+                ("audio", load(&stats.audio_decoder_restarts)),
+            ],
+        );
+        self.counter(
+            "renderer_restarts_total",
+            "Video windows reopened after an error or panic.",
+            load(&stats.renderer_restarts),
+        );
+        self.counter(
+            "audio_underruns_total",
+            "Audio output callbacks padded with silence.",
+            load(&stats.audio_underruns),
+        );
+        self.counter(
+            "audio_overruns_total",
+            "Decoded audio dropped because the ring buffer was full.",
+            load(&stats.audio_overruns),
+        );
+        self.gauge(
+            "audio_ring_fill_samples",
+            "Samples in the audio ring buffer.",
+            stats.ring_fill.load(Ordering::Relaxed),
+        );
+        self.gauge(
+            "audio_ring_capacity_samples",
+            "Capacity of the audio ring buffer.",
+            stats.ring_capacity.load(Ordering::Relaxed),
+        );
+        self.gauge(
+            "audio_output_rate_hz",
+            "Drift-adjusted sample rate fed to the resampler.",
+            stats.output_rate.load(Ordering::Relaxed),
+        );
+        self.gauge(
+            "video_queue_depth",
+            "Video packets waiting for the decoder.",
+            stats.queue_depth.load(Ordering::Relaxed),
+        );
+        self.gauge(
+            "replay_buffer_bytes",
+            "Compressed video and audio held for instant replay.",
+            load(&stats.replay_bytes),
+        );
+    }
+
+    pub fn finish(self) -> String {
+        self.0
+    }
+}
+
+/// Counters of `PipelineStats` at one instant, totals since startup.
+#[derive(Clone, Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub video_packets: u64,
+    pub video_bytes: u64,
+    pub decoded_frames: u64,
+    pub presented_frames: u64,
+    pub dropped_frames: u64,
+    pub corrupt_frames: u64,
+    pub present_latency_us: u64,
+    pub queue_depth: usize,
+    pub ring_fill: usize,
+    pub ring_capacity: usize,
+    pub output_rate: u32,
+    pub audio_buffered_us: u64,
+    pub replay_bytes: u64,
+}
+
+/// Turns the monotonically increasing counters into per-second rates.
+pub(crate) struct RateMeter {
+    last: Instant,
+    decoded: u64,
+    presented: u64,
+    bytes: u64,
+    pub decoded_fps: f32,
+    pub presented_fps: f32,
+    pub kbps: f32,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            decoded: 0,
+            presented: 0,
+            bytes: 0,
+            decoded_fps: 0.0,
+            presented_fps: 0.0,
+            kbps: 0.0,
+        }
+    }
+
+    /// Refreshes the rates at most once per second, returns whether they changed.
+    pub fn update(&mut self, stats: &PipelineStats) -> bool {
+        let elapsed = self.last.elapsed().as_secs_f32();
+        if elapsed < 1.0 {
+            return false;
+        }
+        let decoded = stats.decoded_frames.load(Ordering::Relaxed);
+        let presented = stats.presented_frames.load(Ordering::Relaxed);
+        let bytes = stats.video_bytes.load(Ordering::Relaxed);
+        self.decoded_fps = decoded.saturating_sub(self.decoded) as f32 / elapsed;
+        self.presented_fps = presented.saturating_sub(self.presented) as f32 / elapsed;
+        self.kbps = bytes.saturating_sub(self.bytes) as f32 * 8.0 / 1000.0 / elapsed;
+        self.decoded = decoded;
+        self.presented = presented;
+        self.bytes = bytes;
+        self.last = Instant::now();
+        true
+    }
+
+    /// Formats the overlay lines from the latest counters.
+    pub fn lines(&self, stats: &PipelineStats) -> Vec<String> {
+        let queue_depth = stats.queue_depth.load(Ordering::Relaxed);
+        let ring_fill = stats.ring_fill.load(Ordering::Relaxed);
+        let ring_capacity = stats.ring_capacity.load(Ordering::Relaxed).max(1);
+        let audio_ms = stats.audio_buffered_us.load(Ordering::Relaxed) as f32 / 1000.0;
+        let video_ms = if self.decoded_fps > 0.0 {
+            queue_depth as f32 * 1000.0 / self.decoded_fps
+        } else {
+            0.0
+        };
+        vec![
+            format!(
+                "FPS {:.1} DECODED / {:.1} PRESENTED",
+                self.decoded_fps, self.presented_fps
+            ),
+            format!("BITRATE {:.0} KBPS  QUEUE {}", self.kbps, queue_depth),
+            format!(
+                "LATENCY {:.1} MS",
+                stats.present_latency_us.load(Ordering::Relaxed) as f32 / 1000.0
+            ),
+            format!(
+                "DROPPED {}  CORRUPT {}",
+                stats.dropped_frames.load(Ordering::Relaxed),
+                stats.corrupt_frames.load(Ordering::Relaxed)
+            ),
+            format!(
+                "STREAM {}X{}",
+                stats.stream_width.load(Ordering::Relaxed),
+                stats.stream_height.load(Ordering::Relaxed)
+            ),
+            format!("AUDIO {}", stats.audio_format.lock().unwrap()),
+            format!(
+                "RING {}/{} ({:.0}%)  RATE {}",
+                ring_fill,
+                ring_capacity,
+                ring_fill as f32 * 100.0 / ring_capacity as f32,
+                stats.output_rate.load(Ordering::Relaxed)
+            ),
+            format!("A/V OFFSET {:+.1} MS", audio_ms - video_ms),
+        ]
+    }
+}
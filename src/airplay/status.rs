@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use super::{
+    capture::CaptureOptions, events::SessionId, picture::SnapshotOptions, replay::ReplayOptions,
+    slides::SlideCaptureOptions, stats::StatsSnapshot, stream::AudioStreamFormat,
+};
+
+/// Options a `VideoConsumer` was built with, see `VideoConsumer::config`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConsumerConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub vsync: bool,
+    pub idle_screen: bool,
+    pub snapshot: SnapshotOptions,
+    pub slides: Option<SlideCaptureOptions>,
+    pub replay: Option<ReplayOptions>,
+    pub capture: CaptureOptions,
+    /// Whether sessions are captured from the start, see `VideoConsumerBuilder::capture`.
+    pub capture_enabled: bool,
+    pub video_sink: bool,
+    pub audio_sink: bool,
+}
+
+/// State of a `VideoConsumer` at one instant, see `VideoConsumer::status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConsumerStatus {
+    /// The running session, `None` while idle.
+    pub session: Option<SessionStatus>,
+    /// Output gain from 0.0 to 1.0, set by the sender or `VideoConsumer::set_volume`.
+    pub volume: f32,
+    pub muted: bool,
+    pub fullscreen: bool,
+    pub recording: bool,
+    /// Directory the running session is recorded to.
+    pub recording_dir: Option<PathBuf>,
+    pub stats: StatsSnapshot,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionStatus {
+    pub id: SessionId,
+    pub duration_ms: u64,
+    pub video: bool,
+    pub audio: bool,
+    pub audio_format: Option<AudioStreamFormat>,
+    /// Size of the latest decoded picture, `None` until the first frame.
+    pub resolution: Option<(u32, u32)>,
+}
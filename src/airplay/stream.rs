@@ -0,0 +1,152 @@
+use std::{fmt, str::FromStr};
+
+use airplay2_protocol::airplay::lib::{
+    audio_stream_info::{AudioFormat, AudioStreamInfo},
+    video_stream_info::VideoStreamInfo,
+};
+use serde::Serialize;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AudioCodec {
+    Alac,
+    AacEld,
+    AacLc,
+}
+
+impl AudioCodec {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            AudioCodec::Alac => "alac",
+            AudioCodec::AacEld => "aac-eld",
+            AudioCodec::AacLc => "aac-lc",
+        }
+    }
+}
+
+/// Video stream parameters the sender announces. Resolution and codec settings
+/// are sent in-band as SPS/PPS.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct VideoStreamFormat {
+    /// Identifies the mirroring connection on the sender, empty for generated streams.
+    pub connection_id: String,
+}
+
+impl From<&VideoStreamInfo> for VideoStreamFormat {
+    fn from(info: &VideoStreamInfo) -> Self {
+        Self {
+            connection_id: info.get_stream_connection_id().to_string(),
+        }
+    }
+}
+
+/// Formats as `<connection id>`, the form stored in capture files.
+impl fmt::Display for VideoStreamFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.connection_id)
+    }
+}
+
+impl FromStr for VideoStreamFormat {
+    type Err = Error;
+
+    /// Captures written before the stream info was recorded have no arguments.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            connection_id: s.trim().to_string(),
+        })
+    }
+}
+
+/// Audio stream parameters, independent of where the stream comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AudioStreamFormat {
+    pub codec: AudioCodec,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub samples_per_frame: u64,
+}
+
+impl From<&AudioStreamInfo> for AudioStreamFormat {
+    fn from(info: &AudioStreamInfo) -> Self {
+        let (sample_rate, channels) = info.audio_format.rate_channel();
+        let codec = match info.audio_format {
+            AudioFormat::Alac44100_16_2 => AudioCodec::Alac,
+            AudioFormat::AacEld44100_2 => AudioCodec::AacEld,
+            _ => AudioCodec::AacLc,
+        };
+        Self {
+            codec,
+            sample_rate: sample_rate as u32,
+            channels: channels as u32,
+            samples_per_frame: info.samples_per_frame,
+        }
+    }
+}
+
+/// Formats as `<codec> <sample rate> <channels> <samples per frame>`, the form
+/// stored in capture files.
+impl fmt::Display for AudioStreamFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.codec.name(),
+            self.sample_rate,
+            self.channels,
+            self.samples_per_frame
+        )
+    }
+}
+
+impl FromStr for AudioStreamFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidCapture(format!("audio format {s:?}"));
+        let mut parts = s.split_whitespace();
+        let codec = match parts.next() {
+            Some("alac") => AudioCodec::Alac,
+            Some("aac-eld") => AudioCodec::AacEld,
+            Some("aac-lc") => AudioCodec::AacLc,
+            _ => return Err(invalid()),
+        };
+        let mut number = || -> Result<u64, Error> {
+            parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or_else(invalid)
+        };
+        Ok(Self {
+            codec,
+            sample_rate: number()? as u32,
+            channels: number()? as u32,
+            samples_per_frame: number()?,
+        })
+    }
+}
+
+/// The session callbacks of the pipeline in protocol independent types.
+///
+/// `VideoConsumer` implements `AirPlayConsumer` by translating into these calls,
+/// captures and generated streams drive the same code path through this trait.
+pub trait StreamConsumer: Send + Sync {
+    fn video_format(&self, format: VideoStreamFormat);
+
+    /// One buffer of the Annex-B H.264 stream.
+    fn video(&self, bytes: &[u8]);
+
+    fn video_disconnect(&self);
+
+    fn audio_format(&self, format: AudioStreamFormat);
+
+    /// One encoded audio frame with its RTP timestamp.
+    fn audio(&self, timestamp: u32, payload: &[u8]);
+
+    fn audio_disconnect(&self);
+
+    /// Volume as sent by the sender, from -30.0 (quiet) to 0.0, -144.0 is mute.
+    fn volume(&self, volume: f32);
+}
@@ -0,0 +1,98 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use super::{
+    events::{MediaKind, SessionEventKind, SessionEvents, SessionId},
+    stats::PipelineStats,
+};
+use crate::{diagnostics::RecoverablePanics, Result};
+
+/// Failures in a row after which a pipeline gives up until the next stream format.
+const MAX_RESTARTS: u32 = 5;
+/// A pipeline that ran this long before failing starts counting failures anew.
+const HEALTHY_AFTER: Duration = Duration::from_secs(10);
+/// Pause before rebuilding, so a missing device doesn't spin the thread.
+const RESTART_DELAY: Duration = Duration::from_millis(200);
+
+/// The thread a supervisor runs, it picks the restart counter and the event.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Pipeline {
+    Decoder(MediaKind),
+    Renderer,
+}
+
+/// Runs a decoder pipeline until it returns `Ok`, rebuilding it after errors and
+/// panics. Returns `false` if it gave up, the caller then drains its channel.
+///
+/// `run` is called again with the state it kept, it rebuilds decoders and
+/// converters from the last known stream format. The renderer runs under it too
+/// and reopens its window, its failures are reported as `RendererFailed`.
+///
+/// Failures are reported for `session`, the session the pipeline was started
+/// for. `None` is for threads that outlive sessions, like the persistent window,
+/// they report for the session running at the time.
+pub(super) fn supervise(
+    pipeline: Pipeline,
+    session: Option<SessionId>,
+    stats: &PipelineStats,
+    events: &SessionEvents,
+    mut run: impl FnMut() -> Result<()>,
+) -> bool {
+    let restarts = match pipeline {
+        Pipeline::Decoder(MediaKind::Video) => &stats.video_decoder_restarts,
+        Pipeline::Decoder(MediaKind::Audio) => &stats.audio_decoder_restarts,
+        Pipeline::Renderer => &stats.renderer_restarts,
+    };
+    let _recoverable = RecoverablePanics::enter();
+    let mut failures = 0;
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let span = (attempt > 0).then(|| tracing::info_span!("decoder_restart", attempt).entered());
+        let result = panic::catch_unwind(AssertUnwindSafe(&mut run));
+        drop(span);
+        let message = match result {
+            Ok(Ok(())) => return true,
+            Ok(Err(err)) => err.to_string(),
+            Err(panic) => format!("panic: {}", panic_message(&*panic)),
+        };
+        if started.elapsed() >= HEALTHY_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        let restart = failures <= MAX_RESTARTS;
+        let kind = match pipeline {
+            Pipeline::Decoder(media) => SessionEventKind::PipelineFailed {
+                media,
+                message: message.clone(),
+                restart,
+            },
+            Pipeline::Renderer => SessionEventKind::RendererFailed {
+                message: message.clone(),
+                restart,
+            },
+        };
+        events.emit_for(session.unwrap_or_else(|| events.session()), kind);
+        if !restart {
+            tracing::error!("{pipeline:?} 连续失败 {MAX_RESTARTS} 次，等待下一次连接： {message}");
+            return false;
+        }
+        tracing::warn!("{pipeline:?} 失败，重建： {message}");
+        PipelineStats::add(restarts, 1);
+        std::thread::sleep(RESTART_DELAY);
+        attempt += 1;
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
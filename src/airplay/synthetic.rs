@@ -0,0 +1,304 @@
+use std::time::Duration;
+
+use ffmpeg::{
+    codec::{self, Id},
+    format::{sample, Pixel, Sample},
+    frame, ChannelLayout, Dictionary, Packet,
+};
+use ffmpeg_next as ffmpeg;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{
+    playback::{Capture, CaptureEvent},
+    stream::{AudioCodec, AudioStreamFormat, VideoStreamFormat},
+};
+use crate::{Error, Result};
+
+/// A stretch of video with a fixed resolution.
+#[derive(Clone, Copy, Debug)]
+pub struct VideoSegment {
+    pub width: u32,
+    pub height: u32,
+    pub duration: Duration,
+}
+
+impl VideoSegment {
+    pub fn new(width: u32, height: u32, duration: Duration) -> Self {
+        Self {
+            width,
+            height,
+            duration,
+        }
+    }
+
+    /// The same segment after the sender rotated between portrait and landscape.
+    pub fn rotated(self) -> Self {
+        Self {
+            width: self.height,
+            height: self.width,
+            ..self
+        }
+    }
+}
+
+/// Generates a session of test patterns and a sine tone, encoded with FFmpeg
+/// into the packet shapes `StreamConsumer::video` and `audio` receive from a
+/// real sender: one Annex-B access unit per video call with SPS/PPS in front of
+/// every IDR, one raw audio frame per audio call.
+///
+/// Resolution changes happen in-stream like in AirPlay mirroring, without a new
+/// video format event.
+#[derive(Clone, Debug)]
+pub struct SyntheticStream {
+    pub segments: Vec<VideoSegment>,
+    pub fps: u32,
+    /// `None` generates a video-only session. AAC-ELD has no FFmpeg encoder.
+    pub audio: Option<AudioCodec>,
+    pub tone_hz: f32,
+    pub volume: Option<f32>,
+    /// Probability of dropping a data packet, 0.0 to 1.0.
+    pub packet_loss: f64,
+    /// Maximum random delay added to the arrival time of a data packet.
+    pub jitter: Duration,
+    /// Seed of packet loss and jitter, equal seeds give equal sessions.
+    pub seed: u64,
+}
+
+impl Default for SyntheticStream {
+    fn default() -> Self {
+        Self {
+            segments: vec![VideoSegment::new(1280, 720, Duration::from_secs(2))],
+            fps: 30,
+            audio: Some(AudioCodec::AacLc),
+            tone_hz: 440.0,
+            volume: None,
+            packet_loss: 0.0,
+            jitter: Duration::ZERO,
+            seed: 0,
+        }
+    }
+}
+
+impl SyntheticStream {
+    pub fn duration(&self) -> Duration {
+        self.segments.iter().map(|segment| segment.duration).sum()
+    }
+
+    /// Encodes the whole session, including format and disconnect events.
+    pub fn generate(&self) -> Result<Capture> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut events = vec![(
+            Duration::ZERO,
+            CaptureEvent::VideoFormat(VideoStreamFormat::default()),
+        )];
+        if let Some(volume) = self.volume {
+            events.push((Duration::ZERO, CaptureEvent::Volume(volume)));
+        }
+
+        let mut video = Vec::new();
+        let mut frame_index = 0;
+        for (number, segment) in self.segments.iter().enumerate() {
+            encode_segment(segment, number, self.fps, &mut frame_index, &mut video)
+                .map_err(Error::Encode)?;
+        }
+        events.extend(self.degrade(video, &mut rng));
+
+        if let Some(codec) = self.audio {
+            if codec == AudioCodec::AacEld {
+                return Err(Error::Unavailable("AAC-ELD can not be encoded with FFmpeg"));
+            }
+            let (format, audio) =
+                encode_tone(codec, self.tone_hz, self.duration()).map_err(Error::Encode)?;
+            events.push((Duration::ZERO, CaptureEvent::AudioFormat(format)));
+            events.extend(self.degrade(audio, &mut rng));
+        }
+
+        let end = self.duration() + self.jitter;
+        events.push((end, CaptureEvent::VideoDisconnect));
+        if self.audio.is_some() {
+            events.push((end, CaptureEvent::AudioDisconnect));
+        }
+        events.sort_by_key(|(at, _)| *at);
+        Ok(Capture { events })
+    }
+
+    /// Applies packet loss and jitter to the packets of one stream, packets
+    /// still arrive in order like on the TCP/RTP connections of a real sender.
+    fn degrade(
+        &self,
+        packets: Vec<(Duration, CaptureEvent)>,
+        rng: &mut StdRng,
+    ) -> Vec<(Duration, CaptureEvent)> {
+        let mut last = Duration::ZERO;
+        let mut degraded = Vec::with_capacity(packets.len());
+        for (at, event) in packets {
+            if self.packet_loss > 0.0 && rng.gen_bool(self.packet_loss.min(1.0)) {
+                continue;
+            }
+            let delay = if self.jitter.is_zero() {
+                Duration::ZERO
+            } else {
+                rng.gen_range(Duration::ZERO..=self.jitter)
+            };
+            last = last.max(at + delay);
+            degraded.push((last, event));
+        }
+        degraded
+    }
+}
+
+fn video_encoder(
+    segment: &VideoSegment,
+    fps: u32,
+) -> Result<ffmpeg::encoder::Video, ffmpeg::Error> {
+    let codec = ffmpeg::encoder::find_by_name("libx264")
+        .or_else(|| ffmpeg::encoder::find(Id::H264))
+        .ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let mut encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(segment.width);
+    encoder.set_height(segment.height);
+    encoder.set_format(Pixel::YUV420P);
+    encoder.set_time_base((1, fps as i32));
+    encoder.set_frame_rate(Some((fps as i32, 1)));
+    encoder.set_gop(fps);
+    encoder.set_max_b_frames(0);
+    let mut options = Dictionary::new();
+    options.set("preset", "ultrafast");
+    options.set("tune", "zerolatency");
+    encoder.open_as_with(codec, options)
+}
+
+/// Color bars tinted per segment with a bar moving one step per frame.
+fn draw_pattern(frame: &mut frame::Video, segment: usize, index: usize) {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let stride = frame.stride(0);
+    let moving = index * 8 % width.max(1);
+    let luma = frame.data_mut(0);
+    for y in 0..height {
+        let row = &mut luma[y * stride..y * stride + width];
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = if x.abs_diff(moving) < 8 {
+                235
+            } else {
+                (16 + x * 8 / width * 27) as u8
+            };
+        }
+    }
+    let tint = [(128, 128), (90, 200), (200, 90), (60, 60)][segment % 4];
+    for (plane, value) in [(1, tint.0), (2, tint.1)] {
+        let stride = frame.stride(plane);
+        let data = frame.data_mut(plane);
+        for y in 0..height.div_ceil(2) {
+            data[y * stride..y * stride + width.div_ceil(2)].fill(value);
+        }
+    }
+}
+
+fn encode_segment(
+    segment: &VideoSegment,
+    number: usize,
+    fps: u32,
+    frame_index: &mut usize,
+    out: &mut Vec<(Duration, CaptureEvent)>,
+) -> Result<(), ffmpeg::Error> {
+    let mut encoder = video_encoder(segment, fps)?;
+    let mut frame = frame::Video::new(Pixel::YUV420P, segment.width, segment.height);
+    let frames = (segment.duration.as_secs_f64() * fps as f64).round() as usize;
+    let first = *frame_index;
+    let at = |index: usize| Duration::from_secs(index as u64) / fps;
+    let mut packet = Packet::empty();
+    let mut drain = |encoder: &mut ffmpeg::encoder::Video, out: &mut Vec<_>| {
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                let pts = packet.pts().unwrap_or(0) as usize;
+                out.push((at(first + pts), CaptureEvent::Video(data.to_vec())));
+            }
+        }
+    };
+    for index in 0..frames {
+        draw_pattern(&mut frame, number, first + index);
+        frame.set_pts(Some(index as i64));
+        encoder.send_frame(&frame)?;
+        drain(&mut encoder, out);
+    }
+    encoder.send_eof()?;
+    drain(&mut encoder, out);
+    *frame_index += frames;
+    Ok(())
+}
+
+fn encode_tone(
+    codec: AudioCodec,
+    tone_hz: f32,
+    duration: Duration,
+) -> Result<(AudioStreamFormat, Vec<(Duration, CaptureEvent)>), ffmpeg::Error> {
+    const SAMPLE_RATE: u32 = 44100;
+    let (id, sample_format) = match codec {
+        AudioCodec::Alac => (Id::ALAC, Sample::I16(sample::Type::Planar)),
+        AudioCodec::AacLc => (Id::AAC, Sample::F32(sample::Type::Planar)),
+        AudioCodec::AacEld => return Err(ffmpeg::Error::EncoderNotFound),
+    };
+    let encoder_codec = ffmpeg::encoder::find(id).ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let mut encoder = codec::context::Context::new_with_codec(encoder_codec)
+        .encoder()
+        .audio()?;
+    encoder.set_rate(SAMPLE_RATE as i32);
+    encoder.set_channel_layout(ChannelLayout::STEREO);
+    encoder.set_format(sample_format);
+    encoder.set_time_base((1, SAMPLE_RATE as i32));
+    let mut encoder = encoder.open_as(encoder_codec)?;
+    let samples_per_frame = encoder.frame_size() as usize;
+    let format = AudioStreamFormat {
+        codec,
+        sample_rate: SAMPLE_RATE,
+        channels: 2,
+        samples_per_frame: samples_per_frame as u64,
+    };
+
+    let total = (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+    let mut frame = frame::Audio::new(sample_format, samples_per_frame, ChannelLayout::STEREO);
+    frame.set_rate(SAMPLE_RATE);
+    let mut packets = Vec::new();
+    let mut packet = Packet::empty();
+    let mut drain = |encoder: &mut ffmpeg::encoder::Audio, packets: &mut Vec<_>| {
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                let pts = packet.pts().unwrap_or(0).max(0) as u64;
+                packets.push((
+                    Duration::from_micros(pts * 1_000_000 / SAMPLE_RATE as u64),
+                    CaptureEvent::Audio {
+                        timestamp: pts as u32,
+                        payload: data.to_vec(),
+                    },
+                ));
+            }
+        }
+    };
+    let tone = |index: usize| {
+        (index as f32 * tone_hz * std::f32::consts::TAU / SAMPLE_RATE as f32).sin() * 0.5
+    };
+    for start in (0..total).step_by(samples_per_frame) {
+        for channel in 0..2 {
+            match codec {
+                AudioCodec::Alac => {
+                    for (offset, sample) in frame.plane_mut::<i16>(channel).iter_mut().enumerate() {
+                        *sample = (tone(start + offset) * i16::MAX as f32) as i16;
+                    }
+                }
+                _ => {
+                    for (offset, sample) in frame.plane_mut::<f32>(channel).iter_mut().enumerate() {
+                        *sample = tone(start + offset);
+                    }
+                }
+            }
+        }
+        frame.set_pts(Some(start as i64));
+        encoder.send_frame(&frame)?;
+        drain(&mut encoder, &mut packets);
+    }
+    encoder.send_eof()?;
+    drain(&mut encoder, &mut packets);
+    Ok((format, packets))
+}
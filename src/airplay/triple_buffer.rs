@@ -0,0 +1,84 @@
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+/// Set on the shared index while it holds a buffer the reader has not taken yet.
+const FRESH: u8 = 0b100;
+const INDEX_MASK: u8 = 0b011;
+
+/// Single-producer single-consumer triple buffer.
+///
+/// The writer and the reader each own one of the three buffers, the third one
+/// is the latest published buffer. Publishing and taking swap buffer indices
+/// with a single atomic operation, so neither side ever waits for the other
+/// and the buffers are reused instead of reallocated for every frame.
+struct TripleBuffer<T> {
+    buffers: [UnsafeCell<T>; 3],
+    shared: AtomicU8,
+}
+
+// Each buffer is only ever accessed by the side currently owning its index.
+unsafe impl<T: Send> Sync for TripleBuffer<T> {}
+
+pub(super) struct BufferWriter<T> {
+    inner: Arc<TripleBuffer<T>>,
+    index: u8,
+}
+
+pub(super) struct BufferReader<T> {
+    inner: Arc<TripleBuffer<T>>,
+    index: u8,
+}
+
+pub(super) fn triple_buffer<T>(init: impl Fn() -> T) -> (BufferWriter<T>, BufferReader<T>) {
+    let inner = Arc::new(TripleBuffer {
+        buffers: [
+            UnsafeCell::new(init()),
+            UnsafeCell::new(init()),
+            UnsafeCell::new(init()),
+        ],
+        shared: AtomicU8::new(1),
+    });
+    (
+        BufferWriter {
+            inner: inner.clone(),
+            index: 0,
+        },
+        BufferReader { inner, index: 2 },
+    )
+}
+
+impl<T> BufferWriter<T> {
+    /// The buffer owned by the writer, free to be overwritten.
+    pub fn back(&mut self) -> &mut T {
+        unsafe { &mut *self.inner.buffers[self.index as usize].get() }
+    }
+
+    /// Makes the back buffer the newest one and takes over the previously shared buffer.
+    pub fn publish(&mut self) {
+        let previous = self.inner.shared.swap(self.index | FRESH, Ordering::AcqRel);
+        self.index = previous & INDEX_MASK;
+    }
+}
+
+impl<T> BufferReader<T> {
+    /// Takes the newest published buffer, returns `false` if nothing was published
+    /// since the last call.
+    pub fn update(&mut self) -> bool {
+        if self.inner.shared.load(Ordering::Acquire) & FRESH == 0 {
+            return false;
+        }
+        let previous = self.inner.shared.swap(self.index, Ordering::AcqRel);
+        self.index = previous & INDEX_MASK;
+        true
+    }
+
+    /// The buffer owned by the reader, the newest one as of the last `update`.
+    pub fn front(&self) -> &T {
+        unsafe { &*self.inner.buffers[self.index as usize].get() }
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Clone)]
+pub(crate) struct Worker {
+    name: Arc<str>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Worker {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+/// Marks the worker finished when its closure returns or unwinds.
+struct FinishGuard(Arc<AtomicBool>);
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// The pipeline threads of one `VideoConsumer`, so its shutdown waits for its
+/// own decoders and file writers only, not for those of other consumers.
+#[derive(Clone, Default)]
+pub(crate) struct Workers(Arc<Mutex<Vec<Worker>>>);
+
+impl Workers {
+    /// Spawns a named thread tracked by `join_all`, running inside the caller's span.
+    pub fn spawn<F>(&self, name: &str, f: F) -> Worker
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let worker = Worker {
+            name: name.into(),
+            finished: Arc::new(AtomicBool::new(false)),
+        };
+        let guard = FinishGuard(worker.finished.clone());
+        let span = tracing::Span::current();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let _guard = guard;
+                let _span = span.entered();
+                f()
+            })
+            .expect("spawn thread error");
+        let mut workers = self.0.lock().unwrap();
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(worker.clone());
+        worker
+    }
+
+    /// Names of the threads still running.
+    pub fn running(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|worker| !worker.is_finished())
+            .map(|worker| worker.name().to_string())
+            .collect()
+    }
+
+    /// Waits until every worker finished, returns `false` if some are still
+    /// running after `timeout`.
+    pub fn join_all(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running = self.running();
+            if running.is_empty() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                tracing::warn!("threads still running after {timeout:?}: {running:?}");
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use kircast_desktop::airplay::{Capture, Pacing, SyntheticStream, VideoConsumer, VideoSegment};
+use kircast_desktop::log_conf::init_tracing_subscriber;
+use tracing::{info, Level};
+
+/// How long the pipeline may take to finish the buffered data after the last event.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "usage: kircast_replay <capture dir | --synthetic> [--fast] [--loop]";
+
+/// Plays a session directory written by `KIRCAST_CAPTURE_DIR` through the same
+/// pipeline as a live AirPlay session, no sender device needed. `--synthetic`
+/// plays a generated test pattern that rotates to portrait and back instead.
+fn main() -> anyhow::Result<()> {
+    let _log_guard =
+        init_tracing_subscriber(&["kircast_desktop", "kircast_replay"], Some(Level::INFO));
+
+    let mut dir = None;
+    let mut pacing = Pacing::Original;
+    let mut repeat = false;
+    let mut synthetic = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--fast" => pacing = Pacing::AsFastAsPossible,
+            "--loop" => repeat = true,
+            "--synthetic" => synthetic = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ if dir.is_none() => dir = Some(arg),
+            _ => anyhow::bail!("{USAGE}"),
+        }
+    }
+    let (name, capture) = match dir {
+        Some(dir) if !synthetic => (dir.clone(), Capture::open(&dir)?),
+        None if synthetic => {
+            let landscape = VideoSegment::new(1280, 720, Duration::from_secs(3));
+            let stream = SyntheticStream {
+                segments: vec![landscape, landscape.rotated(), landscape],
+                ..Default::default()
+            };
+            ("synthetic".to_string(), stream.generate()?)
+        }
+        _ => anyhow::bail!("{USAGE}"),
+    };
+    info!(
+        "回放 {name}，共 {} 个事件，时长 {:?}",
+        capture.events.len(),
+        capture.duration()
+    );
+    let consumer = VideoConsumer::default();
+    loop {
+        capture.play(&consumer, pacing);
+        if !repeat {
+            break;
+        }
+    }
+    // 等待解码线程处理完剩余数据
+    if !consumer.shutdown(SHUTDOWN_TIMEOUT) {
+        anyhow::bail!("解码线程在 {SHUTDOWN_TIMEOUT:?} 内未退出");
+    }
+    info!("回放结束");
+    Ok(())
+}
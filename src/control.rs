@@ -0,0 +1,303 @@
+//! Local HTTP/JSON API to inspect and remote-control a `Receiver`.
+//!
+//! | Method | Path               | Body                              | Response                  |
+//! |--------|--------------------|-----------------------------------|---------------------------|
+//! | GET    | `/status`          |                                   | `ReceiverStatus`          |
+//! | GET    | `/config`          |                                   | `ReceiverConfig`          |
+//! | POST   | `/disconnect`      |                                   | 204                       |
+//! | POST   | `/volume`          | `{"volume": 0.5, "muted": false}` | `{"volume", "muted"}`     |
+//! | POST   | `/snapshot`        |                                   | `{"path"}`                |
+//! | POST   | `/recording/start` |                                   | `{"recording", "dir"}`    |
+//! | POST   | `/recording/stop`  |                                   | 204                       |
+//! | POST   | `/fullscreen`      | optional `{"fullscreen": true}`   | `{"fullscreen"}`          |
+//! | GET    | `/log/filter`      |                                   | `{"directives"}`          |
+//! | PUT    | `/log/filter`      | `{"directives": "info"}`          | `{"directives"}`          |
+//! | POST   | `/diagnostics`     |                                   | `{"path"}`                |
+//! | GET    | `/metrics`         |                                   | Prometheus text format    |
+//!
+//! Both fields of `/volume` are optional. Without a body `/fullscreen` toggles.
+//! `/log/filter` and `/diagnostics` answer 404 unless `ControlOptions::log_filter`
+//! and `ControlOptions::diagnostics` are set.
+//! Errors are answered with `{"error": "<message>"}`. Every route, `/metrics`
+//! included, requires the token if one is set.
+
+use std::{future::Future, io, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics::Diagnostics,
+    http::HttpServer,
+    log_conf::LogFilterHandle,
+    receiver::{ReceiverConfig, ReceiverStatus},
+    Error, Receiver,
+};
+
+#[derive(Clone, Debug)]
+pub struct ControlOptions {
+    pub addr: SocketAddr,
+    /// Requests must send `Authorization: Bearer <token>` if set.
+    pub token: Option<String>,
+    /// Lets `/log/filter` change the log level at runtime.
+    pub log_filter: Option<LogFilterHandle>,
+    /// Lets `/diagnostics` write crash bundles on request.
+    pub diagnostics: Option<Diagnostics>,
+}
+
+impl Default for ControlOptions {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 7100)),
+            token: None,
+            log_filter: None,
+            diagnostics: None,
+        }
+    }
+}
+
+struct ControlState {
+    receiver: Receiver,
+    token: Option<String>,
+    log_filter: Option<LogFilterHandle>,
+    diagnostics: Option<Diagnostics>,
+}
+
+type SharedState = State<Arc<ControlState>>;
+
+/// The control API bound to its address, served on the caller's tokio runtime.
+pub struct ControlServer {
+    server: HttpServer,
+}
+
+impl ControlServer {
+    pub async fn bind(receiver: Receiver, options: ControlOptions) -> io::Result<Self> {
+        let state = Arc::new(ControlState {
+            receiver,
+            token: options.token,
+            log_filter: options.log_filter,
+            diagnostics: options.diagnostics,
+        });
+        let router = Router::new()
+            .route("/status", get(status))
+            .route("/config", get(config))
+            .route("/disconnect", post(disconnect))
+            .route("/volume", post(volume))
+            .route("/snapshot", post(snapshot))
+            .route("/recording/start", post(start_recording))
+            .route("/recording/stop", post(stop_recording))
+            .route("/fullscreen", post(fullscreen))
+            .route("/log/filter", get(log_filter).put(set_log_filter))
+            .route("/diagnostics", post(diagnostics))
+            .route("/metrics", get(metrics))
+            .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+            .with_state(state);
+        Ok(Self {
+            server: HttpServer::bind(options.addr, router).await?,
+        })
+    }
+
+    /// The bound address, useful when binding port `0`.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// Serves requests until `shutdown` resolves.
+    pub async fn run_until(
+        self,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> io::Result<()> {
+        self.server.run_until(shutdown).await
+    }
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({ "error": self.1 }));
+        (self.0, body).into_response()
+    }
+}
+
+async fn authorize(State(state): SharedState, request: Request, next: Next) -> Response {
+    if let Some(token) = &state.token {
+        let authorized = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| token_matches(value, token));
+        if !authorized {
+            return ApiError(StatusCode::UNAUTHORIZED, "invalid token".to_string()).into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Compares in time independent of where the first difference is, so the token
+/// can't be guessed byte by byte from response times. Only the length leaks.
+fn token_matches(given: &str, token: &str) -> bool {
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| std::hint::black_box(diff | (a ^ b)))
+            == 0
+}
+
+async fn status(State(state): SharedState) -> Json<ReceiverStatus> {
+    Json(state.receiver.status())
+}
+
+async fn config(State(state): SharedState) -> Json<ReceiverConfig> {
+    Json(state.receiver.config().clone())
+}
+
+async fn disconnect(State(state): SharedState) -> StatusCode {
+    state.receiver.disconnect();
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize, Serialize)]
+struct Volume {
+    volume: Option<f32>,
+    muted: Option<bool>,
+}
+
+async fn volume(State(state): SharedState, Json(request): Json<Volume>) -> Json<Volume> {
+    let consumer = state.receiver.consumer();
+    if let Some(volume) = request.volume {
+        consumer.set_volume(volume);
+    }
+    if let Some(muted) = request.muted {
+        consumer.set_muted(muted);
+    }
+    let status = consumer.status();
+    Json(Volume {
+        volume: Some(status.volume),
+        muted: Some(status.muted),
+    })
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    path: PathBuf,
+}
+
+async fn snapshot(State(state): SharedState) -> Result<Json<Snapshot>, ApiError> {
+    let consumer = state.receiver.consumer().clone();
+    // 截图会阻塞到图片写完
+    tokio::task::spawn_blocking(move || consumer.snapshot())
+        .await
+        .map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map(|path| Json(Snapshot { path }))
+        .map_err(|err| {
+            let status = match err {
+                Error::Unavailable(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            ApiError(status, err.to_string())
+        })
+}
+
+#[derive(Serialize)]
+struct Recording {
+    recording: bool,
+    /// `None` until the next session starts.
+    dir: Option<PathBuf>,
+}
+
+async fn start_recording(State(state): SharedState) -> Json<Recording> {
+    let dir = state.receiver.consumer().start_recording();
+    Json(Recording {
+        recording: true,
+        dir,
+    })
+}
+
+async fn stop_recording(State(state): SharedState) -> StatusCode {
+    state.receiver.consumer().stop_recording();
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize, Serialize)]
+struct Fullscreen {
+    fullscreen: bool,
+}
+
+async fn fullscreen(
+    State(state): SharedState,
+    request: Option<Json<Fullscreen>>,
+) -> Json<Fullscreen> {
+    let consumer = state.receiver.consumer();
+    let fullscreen = match request {
+        Some(Json(request)) => request.fullscreen,
+        None => !consumer.is_fullscreen(),
+    };
+    consumer.set_fullscreen(fullscreen);
+    Json(Fullscreen { fullscreen })
+}
+
+#[derive(Deserialize, Serialize)]
+struct LogFilter {
+    directives: String,
+}
+
+fn log_filter_handle(state: &ControlState) -> Result<&LogFilterHandle, ApiError> {
+    state.log_filter.as_ref().ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            "log filter is not configurable".to_string(),
+        )
+    })
+}
+
+async fn log_filter(State(state): SharedState) -> Result<Json<LogFilter>, ApiError> {
+    let directives = log_filter_handle(&state)?.current();
+    Ok(Json(LogFilter { directives }))
+}
+
+async fn set_log_filter(
+    State(state): SharedState,
+    Json(request): Json<LogFilter>,
+) -> Result<Json<LogFilter>, ApiError> {
+    let handle = log_filter_handle(&state)?;
+    handle
+        .set(&request.directives)
+        .map_err(|err| ApiError(StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    Ok(Json(LogFilter {
+        directives: handle.current(),
+    }))
+}
+
+#[derive(Serialize)]
+struct Bundle {
+    path: PathBuf,
+}
+
+async fn metrics(State(state): SharedState) -> Response {
+    crate::metrics::response(&state.receiver)
+}
+
+async fn diagnostics(State(state): SharedState) -> Result<Json<Bundle>, ApiError> {
+    let diagnostics = state.diagnostics.clone().ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            "diagnostics are not configured".to_string(),
+        )
+    })?;
+    tokio::task::spawn_blocking(move || diagnostics.write_bundle("requested"))
+        .await
+        .map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map(|path| Json(Bundle { path }))
+        .map_err(|err| ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))
+}
@@ -0,0 +1,177 @@
+//! Crash bundles to attach to bug reports: recent logs, configuration, library
+//! versions, the running session and the pipeline threads in one JSON file.
+
+use std::{
+    cell::Cell, ffi::CStr, fmt, fs::File, io::BufWriter, path::PathBuf, sync::mpsc, time::Duration,
+};
+
+use anyhow::Context;
+use ffmpeg_next as ffmpeg;
+use serde::Serialize;
+
+use crate::{
+    log_conf::LogRing,
+    receiver::{ReceiverConfig, ReceiverStatus},
+    Receiver,
+};
+
+/// How long a report waits for the pipeline state, a panicking thread may hold its locks.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    static RECOVERABLE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks panics on the current thread as caught and recovered from, e.g. by a
+/// pipeline that is rebuilt afterwards, until dropped. Their bundles say so.
+pub(crate) struct RecoverablePanics;
+
+impl RecoverablePanics {
+    pub fn enter() -> Self {
+        RECOVERABLE.with(|recoverable| recoverable.set(true));
+        Self
+    }
+}
+
+impl Drop for RecoverablePanics {
+    fn drop(&mut self) {
+        RECOVERABLE.with(|recoverable| recoverable.set(false));
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Versions {
+    pub kircast: &'static str,
+    pub ffmpeg: String,
+    pub libavcodec: String,
+    pub libavformat: String,
+    pub sdl: String,
+    pub cpal_host: &'static str,
+}
+
+impl Versions {
+    pub fn current() -> Self {
+        // SAFETY: av_version_info 返回静态字符串
+        let ffmpeg = unsafe { CStr::from_ptr(ffmpeg::ffi::av_version_info()) };
+        Self {
+            kircast: env!("CARGO_PKG_VERSION"),
+            ffmpeg: ffmpeg.to_string_lossy().into_owned(),
+            libavcodec: lib_version(ffmpeg::codec::version()),
+            libavformat: lib_version(ffmpeg::format::version()),
+            sdl: sdl2::version::version().to_string(),
+            cpal_host: cpal::default_host().id().name(),
+        }
+    }
+}
+
+/// Formats a packed `AV_VERSION_INT`.
+fn lib_version(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        version >> 16,
+        (version >> 8) & 0xff,
+        version & 0xff
+    )
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CrashReport {
+    pub reason: String,
+    pub time: String,
+    pub versions: Versions,
+    pub config: ReceiverConfig,
+    /// Session, stream formats and counters, `None` if the pipeline didn't answer in time.
+    pub status: Option<ReceiverStatus>,
+    /// Names of the running decoder, render and writer threads.
+    pub threads: Vec<String>,
+    /// Recent log records, oldest first.
+    pub logs: Vec<String>,
+}
+
+/// Writes crash bundles of a `Receiver` to a diagnostics directory.
+#[derive(Clone)]
+pub struct Diagnostics {
+    receiver: Receiver,
+    logs: Option<LogRing>,
+    dir: PathBuf,
+}
+
+impl fmt::Debug for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Diagnostics")
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Diagnostics {
+    /// `logs` is usually `LogGuard::ring`, without it bundles have no log records.
+    pub fn new(receiver: Receiver, logs: Option<LogRing>, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            receiver,
+            logs,
+            dir: dir.into(),
+        }
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// Collects a report, waiting up to a second for the pipeline state.
+    ///
+    /// The state is read on a detached `diagnostics-status` thread. If a lock it
+    /// needs is never released, e.g. held by a thread that deadlocked, that
+    /// thread stays blocked for the rest of the process and `status` is `None`.
+    pub fn report(&self, reason: &str) -> CrashReport {
+        let (tx, rx) = mpsc::channel();
+        let receiver = self.receiver.clone();
+        // 在新线程里读取状态，等待超时就放弃
+        let _ = std::thread::Builder::new()
+            .name("diagnostics-status".to_string())
+            .spawn(move || {
+                let _ = tx.send(receiver.status());
+            });
+        CrashReport {
+            reason: reason.to_string(),
+            time: chrono::Local::now().to_rfc3339(),
+            versions: Versions::current(),
+            config: self.receiver.config().clone(),
+            status: rx.recv_timeout(STATUS_TIMEOUT).ok(),
+            threads: self.receiver.consumer().running_threads(),
+            logs: self.logs.as_ref().map(LogRing::records).unwrap_or_default(),
+        }
+    }
+
+    /// Writes a bundle to `dir/crash-<time>.json` and returns its path.
+    pub fn write_bundle(&self, reason: &str) -> anyhow::Result<PathBuf> {
+        let report = self.report(reason);
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("create diagnostics dir {:?}", self.dir))?;
+        let name = chrono::Local::now().format("crash-%Y%m%d-%H%M%S%.3f.json");
+        let path = self.dir.join(name.to_string());
+        let file = File::create(&path).with_context(|| format!("create {path:?}"))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+        Ok(path)
+    }
+
+    /// Logs panics with their backtrace, then writes a bundle for each. Panics
+    /// the pipeline recovers from are marked `recovered:` in the reason.
+    pub fn install_panic_hook(&self) {
+        log_panics::init();
+        let log_panic = std::panic::take_hook();
+        let diagnostics = self.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            log_panic(info);
+            let thread = std::thread::current();
+            let mut reason = format!("thread '{}' {info}", thread.name().unwrap_or("<unnamed>"));
+            if RECOVERABLE.with(Cell::get) {
+                reason.insert_str(0, "recovered: ");
+            }
+            match diagnostics.write_bundle(&reason) {
+                Ok(path) => tracing::error!("崩溃报告已保存： {path:?}"),
+                Err(err) => tracing::error!("崩溃报告保存失败： {err:?}"),
+            }
+        }));
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::airplay::MediaKind;
+
+/// Failures of the decoding pipeline.
+#[derive(Debug)]
+pub enum Error {
+    /// No decoder, resampler or scaler could be created for the stream format.
+    DecoderInit(ffmpeg::Error),
+    /// A decoder, resampler or scaler failed on a running stream.
+    Decode(ffmpeg::Error),
+    /// The audio output device is missing or refused the stream.
+    Device(String),
+    /// The window, its renderer or a texture failed.
+    Render(String),
+    /// The pipeline thread of this stream no longer takes packets.
+    ChannelClosed(MediaKind),
+    /// Nothing to save, e.g. no video session or an empty replay buffer.
+    Unavailable(&'static str),
+    /// A snapshot or replay could not be encoded or muxed.
+    Encode(ffmpeg::Error),
+    /// A snapshot or replay file could not be written.
+    Io(std::io::Error),
+    /// A capture directory could not be read or has a malformed record.
+    InvalidCapture(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub(crate) fn device(err: impl fmt::Display) -> Self {
+        Self::Device(err.to_string())
+    }
+
+    pub(crate) fn render(err: impl fmt::Display) -> Self {
+        Self::Render(err.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DecoderInit(err) => write!(f, "decoder init error: {err}"),
+            Self::Decode(err) => write!(f, "decode error: {err}"),
+            Self::Device(message) => write!(f, "audio device error: {message}"),
+            Self::Render(message) => write!(f, "render error: {message}"),
+            Self::ChannelClosed(media) => write!(f, "{media:?} pipeline is closed"),
+            Self::Unavailable(message) => f.write_str(message),
+            Self::Encode(err) => write!(f, "encode error: {err}"),
+            Self::Io(err) => write!(f, "write error: {err}"),
+            Self::InvalidCapture(message) => write!(f, "invalid capture: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DecoderInit(err) | Self::Decode(err) | Self::Encode(err) => Some(err),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -2,5 +2,21 @@
 
 pub mod airplay;
 mod audio;
+pub mod control;
+pub mod diagnostics;
+mod error;
 mod ffp;
+mod http;
 pub mod log_conf;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod mpris;
+mod osd;
+pub mod pin;
+pub mod power;
+mod receiver;
+
+pub use error::{Error, Result};
+pub use receiver::{
+    Receiver, ReceiverBuilder, ReceiverConfig, ReceiverEvent, ReceiverStatus, StopOutcome,
+};
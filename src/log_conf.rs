@@ -1,12 +1,24 @@
-use std::io::{stderr, stdout};
+use std::{
+    collections::VecDeque,
+    io::{self, stderr, stdout},
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+};
 
-use tracing::{Level, Metadata};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig as _;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing::{Level, Subscriber};
 use tracing_appender::non_blocking::WorkerGuard;
+pub use tracing_appender::rolling::Rotation;
 use tracing_subscriber::{
-    fmt::time::FormatTime,
-    layer::{self, Filter, SubscriberExt as _},
+    filter::{filter_fn, LevelFilter},
+    fmt::{time::FormatTime, MakeWriter},
+    layer::SubscriberExt as _,
+    registry::LookupSpan,
+    reload,
     util::SubscriberInitExt as _,
-    Layer as _,
+    EnvFilter, Layer, Registry,
 };
 
 struct LocalTimer;
@@ -17,65 +29,323 @@ impl FormatTime for LocalTimer {
     }
 }
 
-type FilterVec<T> = smallvec::SmallVec<[T; 8]>;
+/// Exports spans to an OpenTelemetry collector with OTLP over HTTP.
+#[derive(Clone, Debug)]
+pub struct OtlpOptions {
+    /// Traces URL of the collector, e.g. `http://localhost:4318/v1/traces`.
+    /// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` takes precedence.
+    pub endpoint: String,
+    pub service_name: String,
+}
+
+impl Default for OtlpOptions {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: "kircast".to_string(),
+        }
+    }
+}
+
+/// Writes logs to `dir/prefix.<date>.log`, starting a new file every `rotation`.
+#[derive(Clone, Debug)]
+pub struct FileLogOptions {
+    pub dir: PathBuf,
+    pub prefix: String,
+    pub rotation: Rotation,
+    /// Older files are deleted, `None` keeps all.
+    pub max_files: Option<usize>,
+}
+
+impl Default for FileLogOptions {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("logs"),
+            prefix: "kircast".to_string(),
+            rotation: Rotation::DAILY,
+            max_files: Some(14),
+        }
+    }
+}
 
-struct TargetFilter {
-    targets: FilterVec<&'static str>,
-    level: Level,
+#[derive(Clone, Debug)]
+pub struct LogOptions {
+    /// `RUST_LOG`-style directives such as `kircast_desktop=info,airplay2_protocol=warn`,
+    /// the `RUST_LOG` variable takes precedence.
+    pub directives: String,
+    /// Writes warnings and below to stdout and errors to stderr.
+    pub console: bool,
+    pub file: Option<FileLogOptions>,
+    /// One JSON object per line instead of compact text, for console and file.
+    pub json: bool,
+    /// Exports spans and their events, needs a running tokio runtime.
+    pub otlp: Option<OtlpOptions>,
+    /// Number of recent records kept in memory for crash reports, see `LogGuard::ring`.
+    pub ring: Option<usize>,
 }
 
-impl<S> Filter<S> for TargetFilter {
-    fn enabled(&self, metadata: &Metadata<'_>, _: &layer::Context<'_, S>) -> bool {
-        let level = metadata.level();
-        let target = metadata.target();
-        &self.level >= level
-            && level >= &Level::WARN
-            && self.targets.iter().any(|t| target.starts_with(t))
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            directives: "kircast_desktop=info,tracing_log=info,panic=info".to_string(),
+            console: true,
+            file: None,
+            json: false,
+            otlp: None,
+            ring: Some(1000),
+        }
     }
 }
 
-pub fn init_tracing_subscriber(
-    targets: &'static [&'static str],
-    level: Option<Level>,
-) -> (WorkerGuard, WorkerGuard) {
-    let (non_blocking_out, guard_out) = tracing_appender::non_blocking(stdout());
-    let (non_blocking_err, guard_err) = tracing_appender::non_blocking(stderr());
-    let log_level = level.unwrap_or(Level::DEBUG);
-    let default_targets = &["tracing_log", "panic"];
-    let out_filter = {
-        let mut filter_targets: FilterVec<&'static str> = FilterVec::new(); //targets.into_iter().collect();
-        filter_targets.extend_from_slice(targets);
-        filter_targets.extend_from_slice(default_targets);
-        TargetFilter {
-            targets: filter_targets,
-            level: log_level,
+/// The last formatted log records, oldest dropped first.
+#[derive(Clone)]
+pub struct LogRing {
+    records: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// The kept records, oldest first.
+    pub fn records(&self) -> Vec<String> {
+        // 崩溃报告也要能读到日志，忽略锁中毒
+        let records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        records.iter().cloned().collect()
+    }
+
+    fn push(&self, record: String) {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        if records.len() == self.capacity {
+            records.pop_front();
         }
+        records.push_back(record);
+    }
+}
+
+/// Collects one formatted record and adds it to the ring when dropped.
+pub struct RingWriter {
+    ring: LogRing,
+    record: Vec<u8>,
+}
+
+impl io::Write for RingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RingWriter {
+    fn drop(&mut self) {
+        if self.ring.capacity > 0 && !self.record.is_empty() {
+            let record = String::from_utf8_lossy(&self.record);
+            self.ring.push(record.trim_end().to_string());
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogRing {
+    type Writer = RingWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RingWriter {
+            ring: self.clone(),
+            record: Vec::new(),
+        }
+    }
+}
+
+/// Changes the filter of the installed subscriber at runtime.
+#[derive(Clone, Debug)]
+pub struct LogFilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilterHandle {
+    /// Replaces the filter with `directives`, the old one stays if they don't parse.
+    pub fn set(&self, directives: &str) -> anyhow::Result<()> {
+        let filter = EnvFilter::builder().parse(directives)?;
+        self.0.reload(filter)?;
+        tracing::info!("log filter set to {directives}");
+        Ok(())
+    }
+
+    pub fn current(&self) -> String {
+        self.0
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Flushes buffered logs and exported spans when dropped.
+pub struct LogGuard {
+    _writers: Vec<WorkerGuard>,
+    tracer_provider: Option<TracerProvider>,
+    filter: LogFilterHandle,
+    ring: Option<LogRing>,
+}
+
+impl LogGuard {
+    pub fn filter(&self) -> LogFilterHandle {
+        self.filter.clone()
+    }
+
+    /// Recent records, `None` unless `LogOptions::ring` is set.
+    pub fn ring(&self) -> Option<LogRing> {
+        self.ring.clone()
+    }
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            // 订阅者和写入线程在 guard 的字段释放前仍然可用
+            if let Err(err) = provider.shutdown() {
+                tracing::error!("otlp shutdown error {err}");
+            }
+        }
+    }
+}
+
+fn otlp_provider(options: &OtlpOptions) -> anyhow::Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&options.endpoint)
+        .build()?;
+    let resource = Resource::new([KeyValue::new("service.name", options.service_name.clone())]);
+    Ok(TracerProvider::builder()
+        .with_resource(resource)
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .build())
+}
+
+fn file_writer(
+    options: &FileLogOptions,
+) -> anyhow::Result<tracing_appender::rolling::RollingFileAppender> {
+    let mut builder = tracing_appender::rolling::Builder::new()
+        .rotation(options.rotation.clone())
+        .filename_prefix(&options.prefix)
+        .filename_suffix("log");
+    if let Some(max_files) = options.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    Ok(builder.build(&options.dir)?)
+}
+
+type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+
+fn fmt_layer<S, W>(writer: W, json: bool, ansi: bool) -> BoxedLayer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_timer(LocalTimer)
+        .with_line_number(true)
+        .with_target(true)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    if json {
+        layer.json().boxed()
+    } else {
+        layer.compact().boxed()
+    }
+}
+
+/// Logs `targets` up to `level` (`DEBUG` if unset) to the console.
+pub fn init_tracing_subscriber(targets: &[&str], level: Option<Level>) -> LogGuard {
+    let level = level.unwrap_or(Level::DEBUG);
+    let directives = targets
+        .iter()
+        .chain(&["tracing_log", "panic"])
+        .map(|target| format!("{target}={level}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    init_tracing(LogOptions {
+        directives,
+        ..Default::default()
+    })
+}
+
+pub fn init_tracing(options: LogOptions) -> LogGuard {
+    // 配置错误不能阻止启动，日志系统就绪后再报告
+    let mut errors = Vec::new();
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::builder().parse(&directives),
+        Err(_) => EnvFilter::builder().parse(&options.directives),
+    }
+    .unwrap_or_else(|err| {
+        errors.push(anyhow::anyhow!("invalid log directives: {err}"));
+        EnvFilter::new(LogOptions::default().directives)
+    });
+    let (filter, filter_handle) = reload::Layer::new(filter);
+
+    let mut writers = Vec::new();
+    let mut layers = Vec::new();
+    if options.console {
+        let (out, guard) = tracing_appender::non_blocking(stdout());
+        writers.push(guard);
+        layers.push(
+            fmt_layer(out, options.json, true)
+                .with_filter(filter_fn(|metadata| *metadata.level() != Level::ERROR))
+                .boxed(),
+        );
+        let (err, guard) = tracing_appender::non_blocking(stderr());
+        writers.push(guard);
+        layers.push(
+            fmt_layer(err, options.json, true)
+                .with_filter(LevelFilter::ERROR)
+                .boxed(),
+        );
+    }
+    if let Some(file) = &options.file {
+        match file_writer(file) {
+            Ok(appender) => {
+                let (file, guard) = tracing_appender::non_blocking(appender);
+                writers.push(guard);
+                layers.push(fmt_layer(file, options.json, false));
+            }
+            Err(err) => errors.push(err.context(format!("open log dir {:?}", file.dir))),
+        }
+    }
+    let ring = options.ring.map(LogRing::new);
+    if let Some(ring) = &ring {
+        layers.push(fmt_layer(ring.clone(), false, false));
+    }
+    let tracer_provider = match options.otlp.as_ref().map(otlp_provider) {
+        Some(Ok(provider)) => Some(provider),
+        Some(Err(err)) => {
+            errors.push(err.context("otlp exporter"));
+            None
+        }
+        None => None,
     };
-    let err_filter = tracing_subscriber::filter::Targets::new().with_targets(
-        targets
-            .iter()
-            .chain(default_targets)
-            .map(|target| (*target, Level::ERROR)),
-    );
+    if let Some(provider) = &tracer_provider {
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("kircast_desktop"))
+                .boxed(),
+        );
+    }
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_timer(LocalTimer)
-                .with_line_number(true)
-                .with_target(true)
-                .with_writer(non_blocking_out)
-                .compact()
-                .with_filter(out_filter),
-        )
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_timer(LocalTimer)
-                .with_line_number(true)
-                .with_target(true)
-                .with_writer(non_blocking_err)
-                .compact()
-                .with_filter(err_filter),
-        )
+        .with(filter)
+        .with(layers)
         .init();
-    (guard_out, guard_err)
+    for err in errors {
+        tracing::error!("{err:?}");
+    }
+    LogGuard {
+        _writers: writers,
+        tracer_provider,
+        filter: LogFilterHandle(filter_handle),
+        ring,
+    }
 }
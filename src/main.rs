@@ -1,44 +1,253 @@
-use airplay2_protocol::airplay::airplay_consumer::ArcAirPlayConsumer;
-use airplay2_protocol::airplay::AirPlayConfigBuilder;
-use airplay2_protocol::airplay_bonjour::AirPlayBonjour;
-use airplay2_protocol::control_handle::ControlHandle;
-use airplay2_protocol::net::server::Server as AirServer;
-use kircast_desktop::airplay::VideoConsumer;
-use kircast_desktop::log_conf::init_tracing_subscriber;
-use std::sync::Arc;
-use tracing::{info, Level};
+use kircast_desktop::airplay::{
+    CaptureOptions, HookCommand, HookInput, HookOptions, IdleScreen, ReplayOptions,
+    SlideCaptureOptions, SnapshotOptions, VideoConsumer,
+};
+use kircast_desktop::control::{ControlOptions, ControlServer};
+use kircast_desktop::diagnostics::Diagnostics;
+use kircast_desktop::log_conf::{
+    init_tracing, FileLogOptions, LogGuard, LogOptions, OtlpOptions, Rotation,
+};
+use kircast_desktop::metrics::MetricsServer;
+#[cfg(target_os = "linux")]
+use kircast_desktop::mpris::{MprisOptions, MprisServer};
+use kircast_desktop::pin::PinPolicy;
+use kircast_desktop::{Receiver, StopOutcome};
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Resolves with the name of the first SIGINT/SIGTERM (Ctrl+C on Windows).
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
 
 #[tokio::main]
-async fn main() -> tokio::io::Result<()> {
-    let (_out, _err) = init_tracing_subscriber(&["kircast_desktop"], Some(Level::INFO));
+async fn main() -> ExitCode {
+    // KIRCAST_OTLP_ENDPOINT 将会话链路导出到 OpenTelemetry 采集器
+    let otlp = std::env::var("KIRCAST_OTLP_ENDPOINT")
+        .ok()
+        .map(|endpoint| OtlpOptions {
+            endpoint,
+            ..Default::default()
+        });
+    // KIRCAST_LOG_DIR 写入滚动日志文件，KIRCAST_LOG_ROTATION: hourly | daily | never
+    let file = std::env::var_os("KIRCAST_LOG_DIR").map(|dir| FileLogOptions {
+        dir: dir.into(),
+        rotation: match std::env::var("KIRCAST_LOG_ROTATION").as_deref() {
+            Ok("hourly") => Rotation::HOURLY,
+            Ok("never") => Rotation::NEVER,
+            _ => Rotation::DAILY,
+        },
+        ..Default::default()
+    });
+    // guard 在 main 返回时释放，保证日志写完，日志级别可用 RUST_LOG 覆盖
+    let log_guard = init_tracing(LogOptions {
+        file,
+        json: std::env::var_os("KIRCAST_LOG_JSON").is_some(),
+        otlp,
+        ..Default::default()
+    });
+    match run(&log_guard).await {
+        Ok(code) => code,
+        Err(err) => {
+            error!("投屏服务异常退出： {err:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn run(log_guard: &LogGuard) -> tokio::io::Result<ExitCode> {
     let name = "RustAirplay";
-    let volume = 0.5;
-    let pin_pwd = "1234";
-
-    let airplay_config = AirPlayConfigBuilder::new(name.to_string())
-        .width(1920)
-        .height(1080)
+    // KIRCAST_PIN_POLICY: random | session | every:<minutes> | fixed:<pin>
+    let pin_policy = match std::env::var("KIRCAST_PIN_POLICY") {
+        Ok(policy) => PinPolicy::parse(&policy).unwrap_or_else(|| {
+            warn!("无效的 KIRCAST_PIN_POLICY: {}，使用随机密码", policy);
+            PinPolicy::Random
+        }),
+        Err(_) => PinPolicy::Fixed("1234".to_string()),
+    };
+    let mut receiver_builder = Receiver::builder(name)
+        .resolution(1920, 1080)
         .fps(60)
-        .volume(volume)
+        .volume(0.5)
         .audio_buffer_size(24)
-        .pin_pwd(pin_pwd)
-        .build();
-    let video_consumer: ArcAirPlayConsumer = Arc::new(VideoConsumer::default());
-    let mserver = AirServer::bind_default(ControlHandle::new(
-        airplay_config,
-        video_consumer.clone(),
-        video_consumer,
-    ))
-    .await;
-
-    let _air = AirPlayBonjour::new(name, mserver.port, true);
+        .pin_policy(pin_policy);
 
-    info!(
-        "Airplay 投屏服务开启成功，投屏名称： {}，投屏密码： {}",
-        name, pin_pwd
+    let mut consumer_builder =
+        VideoConsumer::builder().vsync(std::env::var_os("KIRCAST_VSYNC").is_some());
+    if let Some(dir) = std::env::var_os("KIRCAST_SNAPSHOT_DIR") {
+        consumer_builder = consumer_builder.snapshot(SnapshotOptions {
+            dir: dir.into(),
+            ..Default::default()
+        });
+    }
+    if let Some(dir) = std::env::var_os("KIRCAST_SLIDES_DIR") {
+        consumer_builder = consumer_builder.slide_capture(SlideCaptureOptions {
+            dir: dir.into(),
+            ..Default::default()
+        });
+    }
+    // KIRCAST_REPLAY_SECONDS 开启即时回放，F11 保存到 KIRCAST_REPLAY_DIR，
+    // KIRCAST_REPLAY_MAX_MB 限制缓冲大小
+    if let Some(seconds) = std::env::var("KIRCAST_REPLAY_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+    {
+        let mut replay = ReplayOptions {
+            duration: Duration::from_secs(seconds),
+            ..Default::default()
+        };
+        if let Some(dir) = std::env::var_os("KIRCAST_REPLAY_DIR") {
+            replay.dir = dir.into();
+        }
+        if let Some(max_mb) = std::env::var("KIRCAST_REPLAY_MAX_MB")
+            .ok()
+            .and_then(|max_mb| max_mb.parse::<u64>().ok())
+        {
+            replay.max_bytes = max_mb * 1024 * 1024;
+        }
+        consumer_builder = consumer_builder.instant_replay(replay);
+    }
+    // KIRCAST_CAPTURE_DIR 保存原始音视频数据，KIRCAST_CAPTURE_MAX_MB 限制单个会话大小
+    if let Some(dir) = std::env::var_os("KIRCAST_CAPTURE_DIR") {
+        let mut capture = CaptureOptions {
+            dir: dir.into(),
+            ..Default::default()
+        };
+        if let Some(max_mb) = std::env::var("KIRCAST_CAPTURE_MAX_MB")
+            .ok()
+            .and_then(|max_mb| max_mb.parse::<u64>().ok())
+        {
+            capture.max_session_bytes = max_mb * 1024 * 1024;
+        }
+        consumer_builder = consumer_builder.capture(capture);
+    }
+    // KIRCAST_HOOK_<事件> 在会话事件时执行 shell 命令，事件为 SESSION_START、VIDEO_FORMAT、
+    // AUDIO_FORMAT、DISCONNECT、ERROR；KIRCAST_HOOK_JSON=1 改为从 stdin 传入 JSON，
+    // KIRCAST_HOOK_TIMEOUT 为超时秒数，KIRCAST_HOOK_CONCURRENCY 为同时执行的命令数
+    let input = match std::env::var_os("KIRCAST_HOOK_JSON") {
+        Some(_) => HookInput::Json,
+        None => HookInput::Env,
+    };
+    let hook = |event: &str| {
+        std::env::var_os(format!("KIRCAST_HOOK_{event}"))
+            .map(|command| HookCommand::shell(command).input(input))
+    };
+    let mut hooks = HookOptions {
+        session_start: hook("SESSION_START"),
+        video_format: hook("VIDEO_FORMAT"),
+        audio_format: hook("AUDIO_FORMAT"),
+        disconnect: hook("DISCONNECT"),
+        error: hook("ERROR"),
+        ..Default::default()
+    };
+    if let Some(seconds) = std::env::var("KIRCAST_HOOK_TIMEOUT")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+    {
+        hooks.timeout = Duration::from_secs(seconds);
+    }
+    if let Some(concurrency) = std::env::var("KIRCAST_HOOK_CONCURRENCY")
+        .ok()
+        .and_then(|concurrency| concurrency.parse().ok())
+    {
+        hooks.max_concurrent = concurrency;
+    }
+    consumer_builder = consumer_builder.hooks(hooks);
+    // KIRCAST_IDLE_SCREEN=1 开启常驻窗口，KIRCAST_IDLE_BACKGROUND 指定背景图片
+    if std::env::var_os("KIRCAST_IDLE_SCREEN").is_some() {
+        let mut idle_screen = IdleScreen::new(name).show_clock(true);
+        if let Some(background) = std::env::var_os("KIRCAST_IDLE_BACKGROUND") {
+            idle_screen = idle_screen.background(background);
+        }
+        receiver_builder = receiver_builder.idle_screen(idle_screen);
+    }
+    let receiver = receiver_builder.consumer(consumer_builder).build();
+    // 崩溃时把最近日志和运行状态写到 KIRCAST_DIAGNOSTICS_DIR（默认 diagnostics）
+    let diagnostics = Diagnostics::new(
+        receiver.clone(),
+        log_guard.ring(),
+        std::env::var_os("KIRCAST_DIAGNOSTICS_DIR").unwrap_or_else(|| "diagnostics".into()),
     );
-
-    mserver.run().await?;
-    Ok(())
+    diagnostics.install_panic_hook();
+    let (stop_servers, servers_stopped) = tokio::sync::watch::channel(false);
+    let stopped = move || {
+        let mut servers_stopped = servers_stopped.clone();
+        async move {
+            let _ = servers_stopped.wait_for(|stopped| *stopped).await;
+        }
+    };
+    // KIRCAST_CONTROL_ADDR 开启 HTTP 控制接口，KIRCAST_CONTROL_TOKEN 设置访问令牌
+    let mut control_addr = None;
+    if let Ok(addr) = std::env::var("KIRCAST_CONTROL_ADDR") {
+        let addr = addr.parse().map_err(tokio::io::Error::other)?;
+        control_addr = Some(addr);
+        let options = ControlOptions {
+            addr,
+            token: std::env::var("KIRCAST_CONTROL_TOKEN").ok(),
+            log_filter: Some(log_guard.filter()),
+            diagnostics: Some(diagnostics),
+        };
+        let server = ControlServer::bind(receiver.clone(), options).await?;
+        info!("控制接口已开启： http://{}", server.local_addr()?);
+        let stopped = stopped();
+        tokio::spawn(async move {
+            if let Err(err) = server.run_until(stopped).await {
+                error!("控制接口异常退出： {err:?}");
+            }
+        });
+    }
+    // KIRCAST_METRICS_ADDR 在单独的地址上开启不需要令牌的 Prometheus 指标接口，
+    // 与控制接口地址相同时由控制接口提供，需要令牌
+    if let Ok(addr) = std::env::var("KIRCAST_METRICS_ADDR") {
+        let addr = addr.parse().map_err(tokio::io::Error::other)?;
+        if control_addr == Some(addr) {
+            info!("指标接口由控制接口提供： http://{addr}/metrics");
+        } else {
+            let server = MetricsServer::bind(receiver.clone(), addr).await?;
+            info!("指标接口已开启： http://{}/metrics", server.local_addr()?);
+            let stopped = stopped();
+            tokio::spawn(async move {
+                if let Err(err) = server.run_until(stopped).await {
+                    error!("指标接口异常退出： {err:?}");
+                }
+            });
+        }
+    }
+    // KIRCAST_MPRIS=1 在会话总线上注册 MPRIS 播放器，桌面媒体控件可以调节音量和断开投屏
+    #[cfg(target_os = "linux")]
+    if std::env::var_os("KIRCAST_MPRIS").is_some() {
+        let server = MprisServer::new(receiver.clone(), MprisOptions::default());
+        tokio::spawn(server.run_until(stopped()));
+    }
+    let outcome = receiver
+        .run_until(async {
+            let signal = shutdown_signal().await;
+            info!("收到 {signal}，停止投屏服务");
+        })
+        .await?;
+    let _ = stop_servers.send(true);
+    Ok(match outcome {
+        StopOutcome::Clean => {
+            info!("投屏服务已停止");
+            ExitCode::SUCCESS
+        }
+        StopOutcome::TimedOut => {
+            warn!("部分线程未能按时退出");
+            ExitCode::from(2)
+        }
+    })
 }
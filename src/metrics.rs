@@ -0,0 +1,56 @@
+//! Prometheus metrics of a `Receiver`, served as `GET /metrics` by the control
+//! API or, without its token, by a `MetricsServer` on an address of its own.
+
+use std::{future::Future, io, net::SocketAddr};
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use crate::{http::HttpServer, Receiver};
+
+/// The metrics endpoint bound to its address, served on the caller's tokio runtime.
+pub struct MetricsServer {
+    server: HttpServer,
+}
+
+impl MetricsServer {
+    pub async fn bind(receiver: Receiver, addr: SocketAddr) -> io::Result<Self> {
+        let router = Router::new()
+            .route("/metrics", get(metrics))
+            .with_state(receiver);
+        Ok(Self {
+            server: HttpServer::bind(addr, router).await?,
+        })
+    }
+
+    /// The bound address, useful when binding port `0`.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// Serves requests until `shutdown` resolves.
+    pub async fn run_until(
+        self,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> io::Result<()> {
+        self.server.run_until(shutdown).await
+    }
+}
+
+async fn metrics(State(receiver): State<Receiver>) -> Response {
+    response(&receiver)
+}
+
+/// The metrics in the Prometheus text exposition format.
+pub(crate) fn response(receiver: &Receiver) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        receiver.consumer().metrics(),
+    )
+        .into_response()
+}
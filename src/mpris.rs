@@ -0,0 +1,308 @@
+//! MPRIS2 media player on the D-Bus session bus, Linux only.
+//!
+//! While a sender is connected `MprisServer` owns `org.mpris.MediaPlayer2.<name>`
+//! with the `org.mpris.MediaPlayer2` and `org.mpris.MediaPlayer2.Player`
+//! interfaces at `/org/mpris/MediaPlayer2`, so desktop widgets, media keys and
+//! `playerctl` see the session. `Volume` is the output gain of the consumer and
+//! `Stop` disconnects the sender like `Receiver::disconnect`. The connection is
+//! closed when the session ends, which releases the name.
+
+use std::{collections::HashMap, future::Future};
+
+use tokio::sync::{broadcast::error::RecvError, watch};
+use tracing::{info, warn};
+use zbus::{
+    connection, fdo, interface,
+    zvariant::{ObjectPath, Value},
+    Connection,
+};
+
+use crate::{airplay::SessionEventKind, Receiver};
+
+const PATH: &str = "/org/mpris/MediaPlayer2";
+
+#[derive(Clone, Debug)]
+pub struct MprisOptions {
+    /// Suffix of the bus name `org.mpris.MediaPlayer2.<name>`.
+    pub name: String,
+    /// D-Bus address used instead of the session bus.
+    pub bus: Option<String>,
+}
+
+impl Default for MprisOptions {
+    fn default() -> Self {
+        Self {
+            name: "kircast".to_string(),
+            bus: None,
+        }
+    }
+}
+
+/// Publishes a `Receiver`'s sessions as an MPRIS2 player, served on the caller's
+/// tokio runtime.
+pub struct MprisServer {
+    receiver: Receiver,
+    options: MprisOptions,
+}
+
+impl MprisServer {
+    pub fn new(receiver: Receiver, options: MprisOptions) -> Self {
+        Self { receiver, options }
+    }
+
+    /// Registers the player for every session until `shutdown` resolves. A bus
+    /// that cannot be reached is logged and tried again with the next session.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        let mut session_active = self.receiver.consumer().session_active();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = session_active.wait_for(|active| *active) => {}
+                _ = &mut shutdown => return,
+            }
+            let session = async {
+                match self.connect().await {
+                    Ok(connection) => {
+                        info!("MPRIS 播放器已注册");
+                        if let Err(err) = self.serve(&connection, &mut session_active).await {
+                            warn!("MPRIS 播放器异常： {err}");
+                        }
+                    }
+                    Err(err) => warn!("无法注册 MPRIS 播放器： {err}"),
+                }
+                // 会话结束前不再重试
+                let _ = session_active.wait_for(|active| !*active).await;
+            };
+            tokio::select! {
+                _ = session => {}
+                _ = &mut shutdown => return,
+            }
+        }
+    }
+
+    async fn connect(&self) -> zbus::Result<Connection> {
+        let builder = match &self.options.bus {
+            Some(address) => connection::Builder::address(address.as_str())?,
+            None => connection::Builder::session()?,
+        };
+        builder
+            .name(format!("org.mpris.MediaPlayer2.{}", self.options.name))?
+            .serve_at(
+                PATH,
+                Root {
+                    identity: self.receiver.config().name.clone(),
+                },
+            )?
+            .serve_at(
+                PATH,
+                Player {
+                    receiver: self.receiver.clone(),
+                },
+            )?
+            .build()
+            .await
+    }
+
+    /// Emits `PropertiesChanged` for the player until the session ends.
+    ///
+    /// The player is registered once the session has started, so its initial
+    /// status and metadata are announced right away instead of on `Started`.
+    async fn serve(
+        &self,
+        connection: &Connection,
+        session_active: &mut watch::Receiver<bool>,
+    ) -> zbus::Result<()> {
+        let player = connection
+            .object_server()
+            .interface::<_, Player>(PATH)
+            .await?;
+        let mut events = self.receiver.subscribe_sessions();
+        {
+            let iface = player.get().await;
+            iface
+                .playback_status_changed(player.signal_context())
+                .await?;
+            iface.metadata_changed(player.signal_context()).await?;
+        }
+        loop {
+            tokio::select! {
+                _ = session_active.wait_for(|active| !*active) => return Ok(()),
+                event = events.recv() => match event.map(|event| event.kind) {
+                    // 发送端、控制接口和 MPRIS 自身调整的音量
+                    Ok(SessionEventKind::VolumeChanged(_)) => {
+                        player
+                            .get()
+                            .await
+                            .volume_changed(player.signal_context())
+                            .await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                    _ => {}
+                },
+            }
+        }
+    }
+}
+
+struct Root {
+    identity: String,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        self.identity.clone()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Only `Stop` and `Volume` control the session, the sender decides what plays.
+struct Player {
+    receiver: Receiver,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {}
+
+    fn previous(&self) {}
+
+    fn pause(&self) {}
+
+    fn play_pause(&self) {}
+
+    fn play(&self) {}
+
+    fn stop(&self) {
+        info!("MPRIS 请求断开投屏");
+        self.receiver.disconnect();
+    }
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) {}
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("OpenUri".to_string()))
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        let playing = self.receiver.status().consumer.session.is_some();
+        if playing { "Playing" } else { "Stopped" }.to_string()
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&'static str, Value<'static>> {
+        let track = match self.receiver.status().consumer.session {
+            Some(session) => format!("/org/kircast/session/{}", session.id),
+            None => "/org/mpris/MediaPlayer2/TrackList/NoTrack".to_string(),
+        };
+        HashMap::from([
+            (
+                "mpris:trackid",
+                Value::from(ObjectPath::from_string_unchecked(track)),
+            ),
+            ("xesam:title", Value::from("AirPlay")),
+        ])
+    }
+
+    /// The muted output reports 0.
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        let status = self.receiver.status().consumer;
+        if status.muted {
+            0.0
+        } else {
+            status.volume as f64
+        }
+    }
+
+    #[zbus(property)]
+    fn set_volume(&self, volume: f64) {
+        let consumer = self.receiver.consumer();
+        consumer.set_volume(volume as f32);
+        if consumer.status().muted {
+            consumer.set_muted(false);
+        }
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,83 @@
+mod font;
+
+use sdl2::{
+    pixels::Color,
+    rect::Rect,
+    render::{BlendMode, Canvas, RenderTarget},
+};
+
+use self::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+/// Pixel size of one line of text drawn with `scale`.
+pub fn line_height(scale: u32) -> u32 {
+    (GLYPH_HEIGHT + 2) * scale
+}
+
+/// Pixel width of `text` drawn with `scale`.
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    text.chars().count() as u32 * (GLYPH_WIDTH + 1) * scale
+}
+
+/// Draws `text` with the built-in bitmap font, top-left corner at `(x, y)`.
+pub fn draw_text<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    x: i32,
+    y: i32,
+    scale: u32,
+    color: Color,
+    text: &str,
+) -> Result<(), String> {
+    let mut rects = Vec::new();
+    for (i, c) in text.chars().enumerate() {
+        let origin_x = x + (i as u32 * (GLYPH_WIDTH + 1) * scale) as i32;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> col) != 0 {
+                    rects.push(Rect::new(
+                        origin_x + (col * scale) as i32,
+                        y + (row as u32 * scale) as i32,
+                        scale,
+                        scale,
+                    ));
+                }
+            }
+        }
+    }
+    if rects.is_empty() {
+        return Ok(());
+    }
+    canvas.set_draw_color(color);
+    canvas.fill_rects(&rects)
+}
+
+/// Draws a block of lines on a translucent panel, used by the stats overlay.
+pub fn draw_panel<T: RenderTarget>(
+    canvas: &mut Canvas<T>,
+    x: i32,
+    y: i32,
+    scale: u32,
+    lines: &[String],
+) -> Result<(), String> {
+    let padding = 4 * scale;
+    let width = lines
+        .iter()
+        .map(|line| text_width(line, scale))
+        .max()
+        .unwrap_or(0);
+    let height = lines.len() as u32 * line_height(scale);
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(Rect::new(x, y, width + padding * 2, height + padding * 2))?;
+    canvas.set_blend_mode(BlendMode::None);
+    for (i, line) in lines.iter().enumerate() {
+        draw_text(
+            canvas,
+            x + padding as i32,
+            y + padding as i32 + (i as u32 * line_height(scale)) as i32,
+            scale,
+            Color::RGB(0, 255, 0),
+            line,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::Rng;
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// How the pairing PIN handed to `AirPlayConfigBuilder::pin_pwd` is chosen.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinPolicy {
+    /// The same PIN for every session.
+    Fixed(String),
+    /// A random PIN chosen once at startup.
+    Random,
+    /// A new random PIN after every session.
+    PerSession,
+    /// A new random PIN every interval, applied once no session is active.
+    Every(Duration),
+}
+
+impl PinPolicy {
+    /// Parses `random`, `session`, `every:<minutes>` or `fixed:<pin>`.
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "random" => Some(Self::Random),
+            "session" => Some(Self::PerSession),
+            _ => {
+                if let Some(minutes) = policy.strip_prefix("every:") {
+                    let minutes = minutes.parse::<u64>().ok().filter(|m| *m > 0)?;
+                    Some(Self::Every(Duration::from_secs(minutes * 60)))
+                } else {
+                    policy
+                        .strip_prefix("fixed:")
+                        .filter(|pin| !pin.is_empty())
+                        .map(|pin| Self::Fixed(pin.to_string()))
+                }
+            }
+        }
+    }
+}
+
+/// The PIN currently in use, shared with the idle screen.
+#[derive(Clone, Debug, Default)]
+pub struct CurrentPin(Arc<Mutex<String>>);
+
+impl CurrentPin {
+    pub fn new(pin: impl Into<String>) -> Self {
+        Self(Arc::new(Mutex::new(pin.into())))
+    }
+
+    pub fn get(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, pin: String) {
+        *self.0.lock().unwrap() = pin;
+    }
+}
+
+pub struct PinManager {
+    policy: PinPolicy,
+    current: CurrentPin,
+}
+
+impl PinManager {
+    pub fn new(policy: PinPolicy) -> Self {
+        let pin = match &policy {
+            PinPolicy::Fixed(pin) => pin.clone(),
+            _ => random_pin(),
+        };
+        Self {
+            policy,
+            current: CurrentPin::new(pin),
+        }
+    }
+
+    pub fn pin(&self) -> String {
+        self.current.get()
+    }
+
+    pub fn current(&self) -> CurrentPin {
+        self.current.clone()
+    }
+
+    /// Resolves once the policy asks for a new PIN and no session is active,
+    /// never resolves for `Fixed` and `Random`.
+    pub async fn rotation_due(&self, session_active: &mut watch::Receiver<bool>) {
+        match self.policy {
+            PinPolicy::Fixed(_) | PinPolicy::Random => std::future::pending().await,
+            PinPolicy::PerSession => {
+                if session_active.wait_for(|active| *active).await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            }
+            PinPolicy::Every(interval) => tokio::time::sleep(interval).await,
+        }
+        if session_active.wait_for(|active| !*active).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Picks a new random PIN, different from the current one.
+    pub fn rotate(&self) -> String {
+        let old = self.current.get();
+        let pin = loop {
+            let pin = random_pin();
+            if pin != old {
+                break pin;
+            }
+        };
+        self.current.set(pin.clone());
+        pin
+    }
+}
+
+fn random_pin() -> String {
+    format!("{:04}", rand::thread_rng().gen_range(0..10000))
+}
@@ -0,0 +1,110 @@
+use anyhow::Context as _;
+use zbus::{
+    blocking::{connection, Connection},
+    zvariant::OwnedFd,
+};
+
+use super::PowerOptions;
+
+const SCREENSAVER: &str = "org.freedesktop.ScreenSaver";
+const SCREENSAVER_PATH: &str = "/org/freedesktop/ScreenSaver";
+const LOGIN: &str = "org.freedesktop.login1";
+const LOGIN_PATH: &str = "/org/freedesktop/login1";
+const LOGIN_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// Inhibitions of the desktop screensaver and of logind, either one is enough.
+pub(super) struct Backend {
+    options: PowerOptions,
+    /// The cookie is only valid on the connection that took it.
+    screensaver: Option<(Connection, u32)>,
+    /// logind keeps the inhibition until this descriptor is closed.
+    login: Option<OwnedFd>,
+}
+
+impl Backend {
+    pub fn new(options: PowerOptions) -> Self {
+        Self {
+            options,
+            screensaver: None,
+            login: None,
+        }
+    }
+
+    pub fn inhibit(&mut self, reason: &str) -> anyhow::Result<()> {
+        let screensaver = self.inhibit_screensaver(reason);
+        let login = self.inhibit_login(reason);
+        match (screensaver, login) {
+            (Err(screensaver), Err(login)) => {
+                anyhow::bail!("{screensaver:#}, {login:#}")
+            }
+            (Err(err), Ok(())) | (Ok(()), Err(err)) => {
+                tracing::debug!("{err:#}");
+                Ok(())
+            }
+            (Ok(()), Ok(())) => Ok(()),
+        }
+    }
+
+    pub fn release(&mut self) {
+        if let Some((connection, cookie)) = self.screensaver.take() {
+            let result = connection.call_method(
+                Some(SCREENSAVER),
+                SCREENSAVER_PATH,
+                Some(SCREENSAVER),
+                "UnInhibit",
+                &(cookie,),
+            );
+            // 连接关闭时桌面也会撤销
+            if let Err(err) = result {
+                tracing::debug!("screensaver UnInhibit error {err}");
+            }
+        }
+        self.login.take();
+    }
+
+    fn inhibit_screensaver(&mut self, reason: &str) -> anyhow::Result<()> {
+        let connection = connect(self.options.session_bus.as_deref(), false)
+            .context("connect to session bus")?;
+        let reply = connection
+            .call_method(
+                Some(SCREENSAVER),
+                SCREENSAVER_PATH,
+                Some(SCREENSAVER),
+                "Inhibit",
+                &(self.options.app_name.as_str(), reason),
+            )
+            .context("screensaver Inhibit")?;
+        let cookie: u32 = reply.body().deserialize()?;
+        self.screensaver = Some((connection, cookie));
+        Ok(())
+    }
+
+    fn inhibit_login(&mut self, reason: &str) -> anyhow::Result<()> {
+        let connection =
+            connect(self.options.system_bus.as_deref(), true).context("connect to system bus")?;
+        let reply = connection
+            .call_method(
+                Some(LOGIN),
+                LOGIN_PATH,
+                Some(LOGIN_MANAGER),
+                "Inhibit",
+                &(
+                    "idle:sleep",
+                    self.options.app_name.as_str(),
+                    reason,
+                    "block",
+                ),
+            )
+            .context("login1 Inhibit")?;
+        self.login = Some(reply.body().deserialize()?);
+        Ok(())
+    }
+}
+
+fn connect(address: Option<&str>, system: bool) -> zbus::Result<Connection> {
+    match address {
+        Some(address) => connection::Builder::address(address)?.build(),
+        None if system => Connection::system(),
+        None => Connection::session(),
+    }
+}
@@ -0,0 +1,159 @@
+//! Keeps the display on and the system awake while a sender mirrors video.
+//!
+//! Linux asks the desktop through `org.freedesktop.ScreenSaver` and logind
+//! through `org.freedesktop.login1`, Windows uses `SetThreadExecutionState`.
+//! Both kinds of inhibition belong to the D-Bus connection or the thread that
+//! took them, so all requests run on one thread owned by `PowerInhibitor`.
+//! Dropping or closing the inhibitor ends the thread after releasing.
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(windows)]
+mod windows;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::airplay::workers::Workers;
+
+#[cfg(target_os = "linux")]
+use self::linux::Backend;
+#[cfg(windows)]
+use self::windows::Backend;
+
+#[derive(Clone, Debug)]
+pub struct PowerOptions {
+    /// Application name shown by the desktop as holding the inhibition.
+    pub app_name: String,
+    /// D-Bus address used instead of the session bus for the screensaver, Linux only.
+    pub session_bus: Option<String>,
+    /// D-Bus address used instead of the system bus for logind, Linux only.
+    pub system_bus: Option<String>,
+}
+
+impl Default for PowerOptions {
+    fn default() -> Self {
+        Self {
+            app_name: "kircast".to_string(),
+            session_bus: None,
+            system_bus: None,
+        }
+    }
+}
+
+enum Command {
+    Inhibit(String),
+    Release,
+    /// Answered once the earlier commands are done.
+    Flush(Sender<()>),
+    /// Releases and ends the thread, later commands are dropped.
+    Close,
+}
+
+/// Inhibits screen blanking and idle sleep between `inhibit` and `release`.
+/// Dropping it releases a held inhibition and waits for the release.
+pub struct PowerInhibitor {
+    tx: Sender<Command>,
+    inhibited: Arc<AtomicBool>,
+}
+
+impl PowerInhibitor {
+    pub fn new(options: PowerOptions) -> Self {
+        Self::spawn(options, &Workers::default())
+    }
+
+    /// Runs the power thread as one of `workers`, so a consumer's shutdown
+    /// waits for the release after `close`.
+    pub(crate) fn spawn(options: PowerOptions, workers: &Workers) -> Self {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let inhibited = Arc::new(AtomicBool::new(false));
+        let state = inhibited.clone();
+        workers.spawn("power", move || run(Backend::new(options), rx, state));
+        Self { tx, inhibited }
+    }
+
+    /// Keeps the display on until `release`, does nothing while already inhibited.
+    pub fn inhibit(&self, reason: &str) {
+        let _ = self.tx.send(Command::Inhibit(reason.to_string()));
+    }
+
+    pub fn release(&self) {
+        let _ = self.tx.send(Command::Release);
+    }
+
+    /// Whether an inhibition is held, updated once requests are done, see `flush`.
+    pub fn is_inhibited(&self) -> bool {
+        self.inhibited.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the earlier requests are done, after `close` until the
+    /// thread has ended.
+    pub fn flush(&self) {
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        if self.tx.send(Command::Flush(tx)).is_ok() {
+            // 线程退出时丢弃未处理的请求，recv 随之返回
+            let _ = rx.recv();
+        }
+    }
+
+    /// Releases a held inhibition and ends the thread, later requests do nothing.
+    pub fn close(&self) {
+        let _ = self.tx.send(Command::Close);
+    }
+}
+
+impl Drop for PowerInhibitor {
+    fn drop(&mut self) {
+        self.close();
+        self.flush();
+    }
+}
+
+fn run(mut backend: Backend, rx: Receiver<Command>, inhibited: Arc<AtomicBool>) {
+    while let Ok(command) = rx.recv() {
+        match command {
+            Command::Inhibit(_) if inhibited.load(Ordering::Relaxed) => {}
+            Command::Inhibit(reason) => match backend.inhibit(&reason) {
+                Ok(()) => {
+                    tracing::debug!("阻止屏幕休眠： {reason}");
+                    inhibited.store(true, Ordering::Relaxed);
+                }
+                Err(err) => tracing::warn!("无法阻止屏幕休眠： {err:#}"),
+            },
+            Command::Release => {
+                if inhibited.swap(false, Ordering::Relaxed) {
+                    backend.release();
+                    tracing::debug!("恢复屏幕休眠");
+                }
+            }
+            Command::Flush(done) => {
+                let _ = done.send(());
+            }
+            Command::Close => break,
+        }
+    }
+    if inhibited.load(Ordering::Relaxed) {
+        backend.release();
+    }
+}
+
+/// Platforms without a power management API.
+#[cfg(not(any(target_os = "linux", windows)))]
+struct Backend;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl Backend {
+    fn new(_options: PowerOptions) -> Self {
+        Self
+    }
+
+    fn inhibit(&mut self, _reason: &str) -> anyhow::Result<()> {
+        anyhow::bail!("not supported on this platform")
+    }
+
+    fn release(&mut self) {}
+}
@@ -0,0 +1,28 @@
+use windows_sys::Win32::System::Power::{
+    SetThreadExecutionState, ES_CONTINUOUS, ES_DISPLAY_REQUIRED, ES_SYSTEM_REQUIRED,
+};
+
+use super::PowerOptions;
+
+/// The execution state of the power thread, it applies while that thread lives.
+pub(super) struct Backend;
+
+impl Backend {
+    pub fn new(_options: PowerOptions) -> Self {
+        Self
+    }
+
+    pub fn inhibit(&mut self, _reason: &str) -> anyhow::Result<()> {
+        let state = ES_CONTINUOUS | ES_DISPLAY_REQUIRED | ES_SYSTEM_REQUIRED;
+        if unsafe { SetThreadExecutionState(state) } == 0 {
+            anyhow::bail!("SetThreadExecutionState failed");
+        }
+        Ok(())
+    }
+
+    pub fn release(&mut self) {
+        unsafe {
+            SetThreadExecutionState(ES_CONTINUOUS);
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use airplay2_protocol::{
+    airplay::{airplay_consumer::ArcAirPlayConsumer, AirPlayConfigBuilder},
+    airplay_bonjour::AirPlayBonjour,
+    control_handle::ControlHandle,
+    net::server::Server as AirServer,
+};
+use serde::Serialize;
+use tokio::{
+    runtime::Runtime,
+    sync::{broadcast, oneshot, Mutex, Notify},
+    task::JoinHandle,
+};
+
+use crate::{
+    airplay::{
+        AudioSink, ConsumerConfig, ConsumerStatus, IdleScreen, SessionEvent, VideoConsumer,
+        VideoConsumerBuilder, VideoSink,
+    },
+    pin::{PinManager, PinPolicy},
+};
+
+/// Lifecycle of the receiver, see `Receiver::subscribe`.
+#[derive(Clone, Debug)]
+pub enum ReceiverEvent {
+    /// The server is bound and advertised, again after every PIN change and
+    /// `Receiver::disconnect`.
+    Listening {
+        port: u16,
+        pin: String,
+    },
+    PinChanged(String),
+    /// The server stopped, `clean` is `false` if threads outlived the shutdown timeout.
+    Stopped {
+        clean: bool,
+    },
+}
+
+/// How `Receiver::run_until` ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopOutcome {
+    Clean,
+    /// Some decoder or writer threads were still running after the shutdown timeout.
+    TimedOut,
+}
+
+/// Options a `Receiver` was built with, see `Receiver::config`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReceiverConfig {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub volume: f32,
+    pub audio_buffer_size: usize,
+    pub pin_policy: PinPolicy,
+    pub shutdown_timeout: Duration,
+    pub consumer: ConsumerConfig,
+}
+
+/// State of a `Receiver` at one instant, see `Receiver::status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReceiverStatus {
+    pub name: String,
+    pub pin: String,
+    pub uptime_ms: u64,
+    #[serde(flatten)]
+    pub consumer: ConsumerStatus,
+}
+
+pub struct ReceiverBuilder {
+    name: String,
+    width: u32,
+    height: u32,
+    fps: u32,
+    volume: f32,
+    audio_buffer_size: usize,
+    pin_policy: PinPolicy,
+    idle_screen: Option<IdleScreen>,
+    consumer: VideoConsumerBuilder,
+    shutdown_timeout: Duration,
+}
+
+impl ReceiverBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            volume: 0.5,
+            audio_buffer_size: 24,
+            pin_policy: PinPolicy::Fixed("1234".to_string()),
+            idle_screen: None,
+            consumer: VideoConsumerBuilder::new(),
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Resolution requested from senders and size of the window.
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn fps(mut self, fps: u32) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    /// Initial volume reported to senders, 0.0 to 1.0.
+    pub fn volume(mut self, volume: f32) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub fn audio_buffer_size(mut self, audio_buffer_size: usize) -> Self {
+        self.audio_buffer_size = audio_buffer_size;
+        self
+    }
+
+    pub fn pin_policy(mut self, pin_policy: PinPolicy) -> Self {
+        self.pin_policy = pin_policy;
+        self
+    }
+
+    /// Keeps a window open between sessions, showing the current PIN unless
+    /// `idle_screen` has its own.
+    pub fn idle_screen(mut self, idle_screen: IdleScreen) -> Self {
+        self.idle_screen = Some(idle_screen);
+        self
+    }
+
+    /// Pipeline options such as snapshots, slides, replay and capture. Resolution,
+    /// fps and idle screen are taken from this builder.
+    pub fn consumer(mut self, consumer: VideoConsumerBuilder) -> Self {
+        self.consumer = consumer;
+        self
+    }
+
+    /// Hands decoded frames to `sink` instead of opening a window.
+    pub fn video_sink(mut self, sink: Arc<dyn VideoSink>) -> Self {
+        self.consumer = self.consumer.video_sink(sink);
+        self
+    }
+
+    /// Hands decoded audio to `sink` instead of the default output device.
+    pub fn audio_sink(mut self, sink: Arc<dyn AudioSink>) -> Self {
+        self.consumer = self.consumer.audio_sink(sink);
+        self
+    }
+
+    /// How long `stop` waits for decoder and file writer threads.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> Receiver {
+        let pins = PinManager::new(self.pin_policy.clone());
+        let mut consumer = self
+            .consumer
+            .resolution(self.width, self.height)
+            .fps(self.fps);
+        if let Some(mut idle_screen) = self.idle_screen {
+            if idle_screen.pin.is_none() {
+                idle_screen = idle_screen.current_pin(pins.current());
+            }
+            consumer = consumer.idle_screen(idle_screen);
+        }
+        let consumer = consumer.build();
+        Receiver {
+            inner: Arc::new(ReceiverInner {
+                config: ReceiverConfig {
+                    name: self.name,
+                    width: self.width,
+                    height: self.height,
+                    fps: self.fps,
+                    volume: self.volume,
+                    audio_buffer_size: self.audio_buffer_size,
+                    pin_policy: self.pin_policy,
+                    shutdown_timeout: self.shutdown_timeout,
+                    consumer: consumer.config().clone(),
+                },
+                pins,
+                consumer: Arc::new(consumer),
+                events: broadcast::channel(64).0,
+                started: Instant::now(),
+                stop: Notify::new(),
+                disconnect: Notify::new(),
+                serving: AtomicBool::new(false),
+                task: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+struct ReceiverInner {
+    config: ReceiverConfig,
+    pins: PinManager,
+    consumer: Arc<VideoConsumer>,
+    events: broadcast::Sender<ReceiverEvent>,
+    started: Instant,
+    stop: Notify,
+    disconnect: Notify,
+    /// Whether `run_until` is serving, `disconnect` is ignored otherwise.
+    serving: AtomicBool,
+    task: Mutex<Option<JoinHandle<io::Result<StopOutcome>>>>,
+}
+
+/// The AirPlay server on a runtime of its own. The tasks it spawns for sender
+/// connections belong to that runtime, so dropping the server closes them
+/// too, where cancelling `run` alone would only close the listener.
+struct AirPlayServer {
+    runtime: Option<Runtime>,
+    port: u16,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl AirPlayServer {
+    async fn bind(handle: ControlHandle) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("airplay-server")
+            .enable_all()
+            .build()?;
+        let (port_tx, port_rx) = oneshot::channel();
+        let task = runtime.spawn(async move {
+            let server = AirServer::bind_default(handle).await;
+            let _ = port_tx.send(server.port);
+            server.run().await
+        });
+        let Ok(port) = port_rx.await else {
+            runtime.shutdown_background();
+            return Err(io::Error::other("AirPlay server failed to bind"));
+        };
+        Ok(Self {
+            runtime: Some(runtime),
+            port,
+            task,
+        })
+    }
+
+    /// Resolves when the server fails.
+    async fn run(&mut self) -> io::Result<()> {
+        (&mut self.task)
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)))
+    }
+}
+
+impl Drop for AirPlayServer {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            // 在异步上下文中不能阻塞等待，后台结束所有连接任务
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// An AirPlay receiver: the server, its Bonjour advertisement and the decoding
+/// pipeline. Cloning gives another handle to the same receiver.
+#[derive(Clone)]
+pub struct Receiver {
+    inner: Arc<ReceiverInner>,
+}
+
+impl Receiver {
+    pub fn builder(name: impl Into<String>) -> ReceiverBuilder {
+        ReceiverBuilder::new(name)
+    }
+
+    pub fn consumer(&self) -> &Arc<VideoConsumer> {
+        &self.inner.consumer
+    }
+
+    pub fn pin(&self) -> String {
+        self.inner.pins.pin()
+    }
+
+    pub fn config(&self) -> &ReceiverConfig {
+        &self.inner.config
+    }
+
+    pub fn status(&self) -> ReceiverStatus {
+        ReceiverStatus {
+            name: self.inner.config.name.clone(),
+            pin: self.pin(),
+            uptime_ms: self.inner.started.elapsed().as_millis() as u64,
+            consumer: self.inner.consumer.status(),
+        }
+    }
+
+    /// Drops the connected sender by binding the server anew and ends its session.
+    /// Does nothing unless the receiver is running.
+    pub fn disconnect(&self) {
+        if self.inner.serving.load(Ordering::Relaxed) {
+            // notify_one 保留许可，运行循环正在重新绑定时也不会丢失请求
+            self.inner.disconnect.notify_one();
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ReceiverEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Events of the casting sessions, see `SessionEvent`.
+    pub fn subscribe_sessions(&self) -> broadcast::Receiver<SessionEvent> {
+        self.inner.consumer.subscribe()
+    }
+
+    /// Runs the receiver in a background task until `stop` is called.
+    pub async fn start(&self) {
+        let mut task = self.inner.task.lock().await;
+        if task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+        let receiver = self.clone();
+        *task = Some(tokio::spawn(async move {
+            receiver.run_until(receiver.inner.stop.notified()).await
+        }));
+    }
+
+    /// Stops a receiver started with `start` and waits for its shutdown.
+    pub async fn stop(&self) -> io::Result<StopOutcome> {
+        let Some(task) = self.inner.task.lock().await.take() else {
+            return Ok(StopOutcome::Clean);
+        };
+        self.inner.stop.notify_one();
+        task.await.map_err(io::Error::other)?
+    }
+
+    /// Serves senders until `shutdown` resolves or the server fails, then ends
+    /// the running session and waits for the pipeline threads.
+    pub async fn run_until(&self, shutdown: impl Future<Output = ()>) -> io::Result<StopOutcome> {
+        let inner = &self.inner;
+        let config = &inner.config;
+        let airplay_consumer: ArcAirPlayConsumer = inner.consumer.clone();
+        let mut session_active = inner.consumer.session_active();
+        tokio::pin!(shutdown);
+        inner.serving.store(true, Ordering::Relaxed);
+
+        let server_result = loop {
+            let pin_pwd = inner.pins.pin();
+            // 密码写在配置里，更换密码需要重新绑定服务
+            let airplay_config = AirPlayConfigBuilder::new(config.name.clone())
+                .width(config.width)
+                .height(config.height)
+                .fps(config.fps)
+                .volume(config.volume)
+                .audio_buffer_size(config.audio_buffer_size)
+                .pin_pwd(&pin_pwd)
+                .build();
+            let mut server = match AirPlayServer::bind(ControlHandle::new(
+                airplay_config,
+                airplay_consumer.clone(),
+                airplay_consumer.clone(),
+            ))
+            .await
+            {
+                Ok(server) => server,
+                Err(err) => break Err(err),
+            };
+
+            let air = AirPlayBonjour::new(&config.name, server.port, true);
+            tracing::info!(
+                "Airplay 投屏服务开启成功，投屏名称： {}，投屏密码： {}",
+                config.name,
+                pin_pwd
+            );
+            let _ = inner.events.send(ReceiverEvent::Listening {
+                port: server.port,
+                pin: pin_pwd,
+            });
+
+            tokio::select! {
+                result = server.run() => {
+                    break result;
+                }
+                _ = inner.pins.rotation_due(&mut session_active) => {
+                    let pin_pwd = inner.pins.rotate();
+                    tracing::info!("投屏密码已更换： {}", pin_pwd);
+                    let _ = inner.events.send(ReceiverEvent::PinChanged(pin_pwd));
+                }
+                _ = inner.disconnect.notified() => {
+                    tracing::info!("断开当前投屏连接");
+                    // 关闭服务的运行时会断开发送端的连接
+                    drop(server);
+                    inner.consumer.end_session();
+                }
+                _ = &mut shutdown => {
+                    tracing::info!("停止投屏服务");
+                    // 先关闭服务不再接受新连接，再注销 Bonjour 广播
+                    drop(server);
+                    drop(air);
+                    break Ok(());
+                }
+            }
+        };
+        inner.serving.store(false, Ordering::Relaxed);
+
+        let consumer = inner.consumer.clone();
+        let timeout = config.shutdown_timeout;
+        let clean = tokio::task::spawn_blocking(move || consumer.shutdown(timeout))
+            .await
+            .unwrap_or(false);
+        let _ = inner.events.send(ReceiverEvent::Stopped { clean });
+        server_result?;
+        Ok(if clean {
+            StopOutcome::Clean
+        } else {
+            StopOutcome::TimedOut
+        })
+    }
+}
@@ -0,0 +1,124 @@
+//! Fixtures shared by the integration tests, every test crate uses a part of them.
+#![allow(dead_code)]
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use ffmpeg_next as ffmpeg;
+use kircast_desktop::{
+    airplay::{AudioSink, Capture, CaptureEvent, SyntheticStream, VideoSegment, VideoSink},
+    Receiver, ReceiverEvent,
+};
+use tokio::{io::AsyncReadExt, net::TcpStream, sync::broadcast, time::timeout};
+
+/// Discards decoded frames and samples, so sessions run without display or audio device.
+pub struct NullSink;
+
+impl VideoSink for NullSink {
+    fn on_frame(&self, _frame: &ffmpeg::frame::Video) {}
+}
+
+impl AudioSink for NullSink {
+    fn on_pcm(&self, _samples: &[i16], _sample_rate: u32, _channels: u16) {}
+}
+
+/// A receiver decoding into `NullSink`s, not serving senders until started.
+pub fn headless_receiver(name: &str) -> Receiver {
+    Receiver::builder(name)
+        .video_sink(Arc::new(NullSink))
+        .audio_sink(Arc::new(NullSink))
+        .build()
+}
+
+/// A generated session of 320x240 video and AAC-LC audio, ending with both disconnects.
+pub fn short_session(duration: Duration) -> Capture {
+    SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, duration)],
+        ..Default::default()
+    }
+    .generate()
+    .expect("generate synthetic session")
+}
+
+/// Like `short_session` without the disconnects, so the session keeps running.
+pub fn open_session(duration: Duration) -> Capture {
+    let mut capture = short_session(duration);
+    capture.events.retain(|(_, event)| {
+        !matches!(
+            event,
+            CaptureEvent::VideoDisconnect | CaptureEvent::AudioDisconnect
+        )
+    });
+    capture
+}
+
+/// An empty directory under the system temp dir, unique per test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kircast-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Waits for the AirPlay server to be bound and returns its port.
+pub async fn listening(events: &mut broadcast::Receiver<ReceiverEvent>) -> u16 {
+    loop {
+        let event = timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("receiver is listening")
+            .unwrap();
+        if let ReceiverEvent::Listening { port, .. } = event {
+            return port;
+        }
+    }
+}
+
+/// Whether the receiver closes `sender`'s connection within five seconds.
+pub async fn disconnected(sender: &mut TcpStream) -> bool {
+    let mut buf = [0; 1024];
+    timeout(Duration::from_secs(5), async {
+        while let Ok(read) = sender.read(&mut buf).await {
+            if read == 0 {
+                break;
+            }
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// A private bus for D-Bus tests, killed on drop. Fails the test if `dbus-daemon`
+/// is not installed rather than skipping it.
+#[cfg(target_os = "linux")]
+pub struct DbusDaemon {
+    child: std::process::Child,
+    pub address: String,
+}
+
+#[cfg(target_os = "linux")]
+impl DbusDaemon {
+    pub fn start() -> Self {
+        use std::io::{BufRead, BufReader};
+
+        let mut child = std::process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .expect("dbus-daemon is required, install dbus");
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            child,
+            address: address.trim().to_string(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DbusDaemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,278 @@
+//! The HTTP control API and the metrics endpoint against a headless receiver,
+//! sessions are played from generated captures. Only the disconnect test runs
+//! the AirPlay server.
+
+mod common;
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use common::{
+    disconnected, headless_receiver, listening, open_session, short_session, temp_dir, NullSink,
+};
+use kircast_desktop::{
+    airplay::{Capture, CaptureEvent, CaptureOptions, Pacing, VideoConsumer},
+    control::{ControlOptions, ControlServer},
+    metrics::MetricsServer,
+    Receiver,
+};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const TOKEN: &str = "secret";
+
+async fn serve(token: Option<&str>) -> (Receiver, SocketAddr) {
+    serve_receiver(headless_receiver("control-test"), token).await
+}
+
+async fn serve_receiver(receiver: Receiver, token: Option<&str>) -> (Receiver, SocketAddr) {
+    let server = ControlServer::bind(
+        receiver.clone(),
+        ControlOptions {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            token: token.map(str::to_string),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run_until(std::future::pending()));
+    (receiver, addr)
+}
+
+/// Sends one request and returns the status code and the JSON body, if any.
+async fn request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> (u16, Value) {
+    let (status, body) = send(addr, method, path, token, body).await;
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+async fn send(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> (u16, String) {
+    let mut head = format!("{method} {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n");
+    if let Some(token) = token {
+        head += &format!("Authorization: Bearer {token}\r\n");
+    }
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    if !body.is_empty() {
+        head += "Content-Type: application/json\r\n";
+    }
+    head += &format!("Content-Length: {}\r\n\r\n{body}", body.len());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(head.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+async fn play(receiver: &Receiver, capture: Capture) {
+    let consumer = receiver.consumer().clone();
+    tokio::task::spawn_blocking(move || capture.play(&*consumer, Pacing::AsFastAsPossible))
+        .await
+        .unwrap();
+}
+
+/// Value of the sample `name` in a Prometheus text exposition.
+fn sample(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no sample {name}"))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn requires_token() {
+    let (_receiver, addr) = serve(Some(TOKEN)).await;
+    let (status, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"], "invalid token");
+    let (status, _) = request(addr, "GET", "/status", Some("wrong"), None).await;
+    assert_eq!(status, 401);
+    // same length, differs in the last byte only
+    let mut guess = TOKEN[..TOKEN.len() - 1].to_string();
+    guess.push(if TOKEN.ends_with('x') { 'y' } else { 'x' });
+    let (status, _) = request(addr, "GET", "/status", Some(&guess), None).await;
+    assert_eq!(status, 401);
+
+    let (status, body) = request(addr, "GET", "/status", Some(TOKEN), None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["name"], "control-test");
+    assert_eq!(body["session"], Value::Null);
+    // the metrics route is behind the same token
+    let (status, _) = send(addr, "GET", "/metrics", None, None).await;
+    assert_eq!(status, 401);
+    let (status, metrics) = send(addr, "GET", "/metrics", Some(TOKEN), None).await;
+    assert_eq!(status, 200);
+    assert_eq!(sample(&metrics, "kircast_sessions_total"), 0.0);
+}
+
+#[tokio::test]
+async fn config_is_inspectable() {
+    let (_receiver, addr) = serve(None).await;
+    let (status, body) = request(addr, "GET", "/config", None, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["name"], "control-test");
+    assert_eq!(body["width"], 1920);
+    assert_eq!(body["consumer"]["video_sink"], true);
+    assert_eq!(body["consumer"]["capture_enabled"], false);
+}
+
+#[tokio::test]
+async fn controls_running_session() {
+    let (receiver, addr) = serve(None).await;
+    play(&receiver, open_session(Duration::from_millis(500))).await;
+
+    let (status, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(status, 200);
+    let session = &body["session"];
+    assert_eq!(session["id"], 1);
+    assert_eq!(session["video"], true);
+    assert_eq!(session["audio_format"]["codec"], "aac-lc");
+
+    let mute = serde_json::json!({ "muted": true });
+    let (status, body) = request(addr, "POST", "/volume", None, Some(mute)).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["muted"], true);
+    let volume = serde_json::json!({ "volume": 2.0 });
+    let (_, body) = request(addr, "POST", "/volume", None, Some(volume)).await;
+    assert_eq!(body["volume"], 1.0);
+
+    let (_, body) = request(addr, "POST", "/fullscreen", None, None).await;
+    assert_eq!(body["fullscreen"], true);
+    let (_, body) = request(addr, "POST", "/fullscreen", None, None).await;
+    assert_eq!(body["fullscreen"], false);
+
+    receiver.consumer().end_session();
+    let (_, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(body["session"], Value::Null);
+    let (status, body) = request(addr, "POST", "/snapshot", None, None).await;
+    assert_eq!(status, 409);
+    assert_eq!(body["error"], "no active video session");
+}
+
+#[tokio::test]
+async fn metrics_count_sessions() {
+    let (receiver, _) = serve(None).await;
+    let server = MetricsServer::bind(receiver.clone(), SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run_until(std::future::pending()));
+
+    let capture = short_session(Duration::from_millis(500));
+    let packets = capture
+        .events
+        .iter()
+        .filter(|(_, event)| matches!(event, CaptureEvent::Video(_)))
+        .count();
+    play(&receiver, capture).await;
+
+    let (status, metrics) = send(addr, "GET", "/metrics", None, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(sample(&metrics, "kircast_sessions_total"), 1.0);
+    assert_eq!(sample(&metrics, "kircast_session_active"), 0.0);
+    assert_eq!(
+        sample(&metrics, "kircast_session_duration_seconds_count"),
+        1.0
+    );
+    assert_eq!(
+        sample(&metrics, "kircast_video_packets_total"),
+        packets as f64
+    );
+    assert_eq!(
+        sample(&metrics, "kircast_decoder_errors_total{media=\"video\"}"),
+        0.0
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn disconnect_drops_sender() {
+    let (receiver, addr) = serve(None).await;
+    let mut events = receiver.subscribe();
+    receiver.start().await;
+    let port = listening(&mut events).await;
+
+    // a sender connected to the AirPlay server while its session is playing
+    let mut sender = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    play(&receiver, open_session(Duration::from_millis(500))).await;
+    let mut buf = [0; 1024];
+    assert!(
+        timeout(Duration::from_millis(200), sender.read(&mut buf))
+            .await
+            .is_err(),
+        "the connection stays open"
+    );
+
+    let (status, _) = request(addr, "POST", "/disconnect", None, None).await;
+    assert_eq!(status, 204);
+    assert!(
+        disconnected(&mut sender).await,
+        "the sender is still connected"
+    );
+    // the server is bound anew after the session ended
+    listening(&mut events).await;
+    let (_, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(body["session"], Value::Null);
+
+    receiver.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn records_on_request() {
+    let dir = temp_dir("control-recording");
+    let receiver = Receiver::builder("control-test")
+        .consumer(VideoConsumer::builder().capture(CaptureOptions {
+            dir: dir.clone(),
+            ..Default::default()
+        }))
+        .video_sink(Arc::new(NullSink))
+        .audio_sink(Arc::new(NullSink))
+        .build();
+    let (receiver, addr) = serve_receiver(receiver, None).await;
+    let (status, _) = request(addr, "POST", "/recording/stop", None, None).await;
+    assert_eq!(status, 204);
+    play(&receiver, open_session(Duration::from_millis(500))).await;
+    let (_, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(body["recording"], false);
+
+    // recording starts with the running session
+    let (status, body) = request(addr, "POST", "/recording/start", None, None).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["recording"], true);
+    let session_dir = PathBuf::from(body["dir"].as_str().expect("session directory"));
+    assert!(session_dir.starts_with(&dir));
+    assert!(session_dir.join("video.h264").is_file());
+    let (_, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(body["recording"], true);
+    assert_eq!(body["recording_dir"], session_dir.to_str().unwrap());
+
+    let (status, _) = request(addr, "POST", "/recording/stop", None, None).await;
+    assert_eq!(status, 204);
+    let (_, body) = request(addr, "GET", "/status", None, None).await;
+    assert_eq!(body["recording"], false);
+    assert_eq!(body["recording_dir"], Value::Null);
+    assert!(receiver.consumer().shutdown(Duration::from_secs(10)));
+    let log = std::fs::read_to_string(session_dir.join("session.log")).unwrap();
+    assert!(log.contains(" video_format"), "{log}");
+    assert!(log.contains(" audio_format aac-lc"), "{log}");
+    let _ = std::fs::remove_dir_all(dir);
+}
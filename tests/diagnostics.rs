@@ -0,0 +1,109 @@
+//! Crash bundles written on request and from the panic hook.
+
+mod common;
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::{headless_receiver, short_session, temp_dir, NullSink};
+use ffmpeg_next as ffmpeg;
+use kircast_desktop::{
+    airplay::{Pacing, VideoConsumer, VideoSink},
+    diagnostics::Diagnostics,
+    log_conf::{init_tracing, LogOptions},
+};
+use serde_json::Value;
+
+fn read(path: &Path) -> Value {
+    serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+}
+
+/// Panics on the first frame, the supervisor rebuilds the decoder.
+#[derive(Default)]
+struct PanicOnce(AtomicBool);
+
+impl VideoSink for PanicOnce {
+    fn on_frame(&self, _frame: &ffmpeg::frame::Video) {
+        if !self.0.swap(true, Ordering::Relaxed) {
+            panic!("recovered fault");
+        }
+    }
+}
+
+fn bundles(dir: &Path) -> Vec<Value> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    paths.iter().map(|path| read(path)).collect()
+}
+
+// The log subscriber and the panic hook are process-wide, so one test covers both.
+#[test]
+fn bundles_on_request_and_on_panic() {
+    let guard = init_tracing(LogOptions {
+        directives: "diagnostics=info,panic=error".to_string(),
+        console: false,
+        ring: Some(8),
+        ..Default::default()
+    });
+    for i in 0..10 {
+        tracing::info!("record {i}");
+    }
+    let receiver = headless_receiver("diagnostics-test");
+    let dir = temp_dir("diagnostics");
+    let diagnostics = Diagnostics::new(receiver, guard.ring(), &dir);
+
+    let report = read(&diagnostics.write_bundle("requested").unwrap());
+    assert_eq!(report["reason"], "requested");
+    assert_eq!(report["config"]["name"], "diagnostics-test");
+    assert!(report["status"]["session"].is_null());
+    assert!(!report["versions"]["ffmpeg"].as_str().unwrap().is_empty());
+    assert!(!report["versions"]["sdl"].as_str().unwrap().is_empty());
+    let logs = report["logs"].as_array().unwrap();
+    assert_eq!(logs.len(), 8, "the ring keeps the last records");
+    assert!(logs[0].as_str().unwrap().ends_with("record 2"));
+    assert!(logs[7].as_str().unwrap().ends_with("record 9"));
+
+    diagnostics.install_panic_hook();
+    let panicked = std::thread::Builder::new()
+        .name("doomed".to_string())
+        .spawn(|| panic!("decoder exploded"))
+        .unwrap()
+        .join();
+    assert!(panicked.is_err());
+
+    // a decoder panic the supervisor recovers from is marked as such
+    let consumer = VideoConsumer::builder()
+        .video_sink(Arc::new(PanicOnce::default()))
+        .audio_sink(Arc::new(NullSink))
+        .build();
+    short_session(Duration::from_millis(300)).play(&consumer, Pacing::AsFastAsPossible);
+    assert!(consumer.shutdown(Duration::from_secs(10)));
+    let _ = std::panic::take_hook();
+
+    let bundles = bundles(&dir);
+    assert_eq!(bundles.len(), 3);
+    let reason = bundles[1]["reason"].as_str().unwrap();
+    assert!(reason.starts_with("thread 'doomed'"), "{reason}");
+    assert!(reason.contains("decoder exploded"), "{reason}");
+    // log-panics logged the panic before the bundle was written
+    let logs = bundles[1]["logs"].as_array().unwrap();
+    assert!(logs
+        .iter()
+        .any(|record| record.as_str().unwrap().contains("decoder exploded")));
+    let reason = bundles[2]["reason"].as_str().unwrap();
+    assert!(
+        reason.starts_with("recovered: thread 'video-decoder'"),
+        "{reason}"
+    );
+    assert!(reason.contains("recovered fault"), "{reason}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,228 @@
+//! External commands on session events, run through `sh` against sessions played
+//! from generated captures.
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use common::{short_session, temp_dir, NullSink};
+use ffmpeg_next as ffmpeg;
+use kircast_desktop::airplay::{
+    Capture, CaptureEvent, HookCommand, HookInput, HookOptions, Pacing, SessionEventKind,
+    VideoConsumer, VideoSink, VideoStreamFormat,
+};
+use serde_json::Value;
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Appends the line `echo` prints to `file`.
+fn append(echo: &str, file: &Path) -> HookCommand {
+    HookCommand::shell(format!("echo \"{echo}\" >> {}", file.display()))
+}
+
+/// Plays a short session and waits for the pipeline and hook threads.
+fn play_session(hooks: HookOptions) {
+    play_capture(hooks, short_session(Duration::from_millis(300)));
+}
+
+fn play_capture(hooks: HookOptions, capture: Capture) {
+    let consumer = VideoConsumer::builder()
+        .video_sink(Arc::new(NullSink))
+        .audio_sink(Arc::new(NullSink))
+        .hooks(hooks)
+        .build();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+    assert!(consumer.shutdown(SHUTDOWN_TIMEOUT));
+}
+
+#[test]
+fn commands_receive_event_details() {
+    let dir = temp_dir("hooks");
+    let events = dir.join("events");
+    let audio = dir.join("audio.json");
+    let mut capture = short_session(Duration::from_millis(300));
+    capture.events[0].1 = CaptureEvent::VideoFormat(VideoStreamFormat {
+        connection_id: "4711".to_string(),
+    });
+    play_capture(
+        HookOptions {
+            session_start: Some(append("$KIRCAST_EVENT $KIRCAST_SESSION", &events)),
+            video_format: Some(append(
+                "$KIRCAST_EVENT $KIRCAST_SESSION $KIRCAST_VIDEO_CONNECTION",
+                &events,
+            )),
+            audio_format: Some(
+                HookCommand::shell(format!("cat > {}", audio.display())).input(HookInput::Json),
+            ),
+            disconnect: Some(append(
+                "$KIRCAST_EVENT $KIRCAST_SESSION $KIRCAST_DURATION_MS",
+                &events,
+            )),
+            ..Default::default()
+        },
+        capture,
+    );
+
+    let events = std::fs::read_to_string(events).unwrap();
+    let lines: Vec<_> = events.lines().collect();
+    assert_eq!(lines.len(), 3, "{events}");
+    assert_eq!(lines[0], "session_start 1");
+    assert_eq!(lines[1], "video_format 1 4711");
+    let duration: u64 = lines[2]
+        .strip_prefix("disconnect 1 ")
+        .and_then(|duration| duration.parse().ok())
+        .unwrap_or_else(|| panic!("{events}"));
+    assert!(duration < SHUTDOWN_TIMEOUT.as_millis() as u64);
+
+    let audio: Value = serde_json::from_slice(&std::fs::read(audio).unwrap()).unwrap();
+    assert_eq!(audio["event"], "audio_format");
+    assert_eq!(audio["session"], 1);
+    assert_eq!(audio["format"]["codec"], "aac-lc");
+    assert_eq!(audio["format"]["sample_rate"], 44100);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn slow_commands_are_killed_at_timeout() {
+    let dir = temp_dir("hooks-timeout");
+    let finished = dir.join("finished");
+    let orphan = dir.join("orphan");
+    let disconnected = dir.join("disconnected");
+    let started = Instant::now();
+    play_session(HookOptions {
+        // the background shell would outlive a kill of `sh` alone
+        session_start: Some(HookCommand::shell(format!(
+            "sh -c 'sleep 1; touch {}' & sleep 5; touch {}",
+            orphan.display(),
+            finished.display()
+        ))),
+        disconnect: Some(HookCommand::shell(format!(
+            "touch {}",
+            disconnected.display()
+        ))),
+        timeout: Duration::from_millis(300),
+        max_concurrent: 1,
+        ..Default::default()
+    });
+
+    assert!(started.elapsed() < Duration::from_secs(4));
+    assert!(!finished.exists(), "the slow command was killed");
+    assert!(disconnected.exists(), "the next command ran afterwards");
+    std::thread::sleep(Duration::from_secs(2).saturating_sub(started.elapsed()));
+    assert!(!orphan.exists(), "the commands it started were killed");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+/// Panics on the first frame, the supervisor rebuilds the decoder.
+#[derive(Default)]
+struct PanicOnce(AtomicBool);
+
+impl VideoSink for PanicOnce {
+    fn on_frame(&self, _frame: &ffmpeg::frame::Video) {
+        if !self.0.swap(true, Ordering::Relaxed) {
+            panic!("injected fault");
+        }
+    }
+}
+
+#[test]
+fn error_command_runs_for_pipeline_failures() {
+    let dir = temp_dir("hooks-error");
+    let errors = dir.join("errors");
+    let consumer = VideoConsumer::builder()
+        .video_sink(Arc::new(PanicOnce::default()))
+        .audio_sink(Arc::new(NullSink))
+        .hooks(HookOptions {
+            error: Some(append(
+                "$KIRCAST_EVENT $KIRCAST_SESSION $KIRCAST_MEDIA $KIRCAST_STAGE $KIRCAST_MESSAGE",
+                &errors,
+            )),
+            ..Default::default()
+        })
+        .build();
+    short_session(Duration::from_millis(300)).play(&consumer, Pacing::AsFastAsPossible);
+    assert!(consumer.shutdown(SHUTDOWN_TIMEOUT));
+
+    // packets before the next keyframe may add decoder errors
+    let errors = std::fs::read_to_string(errors).unwrap();
+    assert!(
+        errors
+            .lines()
+            .any(|line| line == "error 1 video decoder panic: injected fault"),
+        "{errors}"
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+/// Panics on every frame, the supervisor gives up after a few restarts.
+struct PanicAlways;
+
+impl VideoSink for PanicAlways {
+    fn on_frame(&self, _frame: &ffmpeg::frame::Video) {
+        panic!("injected fault");
+    }
+}
+
+#[test]
+fn error_command_is_rate_limited() {
+    let dir = temp_dir("hooks-error-limit");
+    let errors = dir.join("errors");
+    let consumer = VideoConsumer::builder()
+        .video_sink(Arc::new(PanicAlways))
+        .audio_sink(Arc::new(NullSink))
+        .hooks(HookOptions {
+            error: Some(append(
+                "$KIRCAST_EVENT $KIRCAST_SESSION $KIRCAST_MEDIA $KIRCAST_MESSAGE",
+                &errors,
+            )),
+            ..Default::default()
+        })
+        .build();
+    let mut session_events = consumer.subscribe();
+    short_session(Duration::from_millis(1500)).play(&consumer, Pacing::AsFastAsPossible);
+    assert!(consumer.shutdown(SHUTDOWN_TIMEOUT));
+
+    let mut failures = 0;
+    while let Ok(event) = session_events.try_recv() {
+        failures += matches!(event.kind, SessionEventKind::PipelineFailed { .. }) as usize;
+    }
+    assert!(failures > 1, "the decoder was restarted");
+    let errors = std::fs::read_to_string(errors).unwrap();
+    let panics = errors
+        .lines()
+        .filter(|line| line.starts_with("error 1 video panic"))
+        .count();
+    assert_eq!(panics, 1, "{errors}");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn shutdown_waits_for_own_threads_only() {
+    let consumer = |hooks: HookOptions| {
+        VideoConsumer::builder()
+            .video_sink(Arc::new(NullSink))
+            .audio_sink(Arc::new(NullSink))
+            .hooks(hooks)
+            .build()
+    };
+    let busy = consumer(HookOptions {
+        session_start: Some(HookCommand::shell("sleep 3")),
+        ..Default::default()
+    });
+    let idle = consumer(HookOptions::default());
+    short_session(Duration::from_millis(300)).play(&busy, Pacing::AsFastAsPossible);
+    short_session(Duration::from_millis(300)).play(&idle, Pacing::AsFastAsPossible);
+
+    // the other consumer's hook is still running
+    assert!(idle.shutdown(Duration::from_secs(2)));
+    assert!(!busy.shutdown(Duration::from_millis(100)));
+    assert!(busy.shutdown(SHUTDOWN_TIMEOUT));
+}
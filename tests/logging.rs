@@ -0,0 +1,41 @@
+//! File logging with JSON lines and a filter changed at runtime.
+
+use kircast_desktop::log_conf::{init_tracing, FileLogOptions, LogOptions, Rotation};
+
+#[test]
+fn json_file_with_reloaded_filter() {
+    let dir = std::env::temp_dir().join(format!("kircast-logs-{}", std::process::id()));
+    let guard = init_tracing(LogOptions {
+        directives: "logging=info".to_string(),
+        console: false,
+        file: Some(FileLogOptions {
+            dir: dir.clone(),
+            prefix: "test".to_string(),
+            rotation: Rotation::NEVER,
+            max_files: None,
+        }),
+        json: true,
+        otlp: None,
+        ring: None,
+    });
+    tracing::info!(session = 7, "before reload");
+    tracing::debug!("filtered");
+    let filter = guard.filter();
+    assert!(filter.set("not a [valid filter").is_err());
+    filter.set("logging=warn").unwrap();
+    assert_eq!(filter.current(), "logging=warn");
+    tracing::info!("after reload");
+    tracing::warn!("still logged");
+    drop(guard);
+
+    let content = std::fs::read_to_string(dir.join("test.log")).unwrap();
+    let messages: Vec<String> = content
+        .lines()
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            record["fields"]["message"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(messages, ["before reload", "still logged"]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
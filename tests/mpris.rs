@@ -0,0 +1,128 @@
+//! The MPRIS2 player on a private dbus-daemon, which has to be installed.
+//! Sessions are played from generated captures to a running receiver.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{disconnected, headless_receiver, listening, open_session, DbusDaemon};
+use kircast_desktop::{
+    airplay::Pacing,
+    mpris::{MprisOptions, MprisServer},
+};
+use tokio::net::TcpStream;
+use zbus::{connection, fdo::DBusProxy, names::BusName, Connection};
+
+const NAME: &str = "org.mpris.MediaPlayer2.kircast_test";
+
+#[zbus::proxy(
+    interface = "org.mpris.MediaPlayer2.Player",
+    default_service = "org.mpris.MediaPlayer2.kircast_test",
+    default_path = "/org/mpris/MediaPlayer2"
+)]
+trait Player {
+    fn stop(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn volume(&self) -> zbus::Result<f64>;
+
+    #[zbus(property)]
+    fn set_volume(&self, volume: f64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn can_control(&self) -> zbus::Result<bool>;
+}
+
+#[zbus::proxy(
+    interface = "org.mpris.MediaPlayer2",
+    default_service = "org.mpris.MediaPlayer2.kircast_test",
+    default_path = "/org/mpris/MediaPlayer2"
+)]
+trait Root {
+    #[zbus(property)]
+    fn identity(&self) -> zbus::Result<String>;
+}
+
+/// Polls `condition` for up to five seconds.
+async fn eventually<F: std::future::Future<Output = bool>>(mut condition: impl FnMut() -> F) {
+    for _ in 0..100 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("condition not met");
+}
+
+async fn has_player(bus: &DBusProxy<'_>) -> bool {
+    bus.name_has_owner(BusName::try_from(NAME).unwrap())
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn player_follows_session() {
+    let daemon = DbusDaemon::start();
+    let receiver = headless_receiver("mpris-test");
+    let server = MprisServer::new(
+        receiver.clone(),
+        MprisOptions {
+            name: "kircast_test".to_string(),
+            bus: Some(daemon.address.clone()),
+        },
+    );
+    tokio::spawn(server.run_until(std::future::pending()));
+    let mut receiver_events = receiver.subscribe();
+    receiver.start().await;
+    let port = listening(&mut receiver_events).await;
+
+    let client: Connection = connection::Builder::address(daemon.address.as_str())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let bus = DBusProxy::new(&client).await.unwrap();
+    assert!(!has_player(&bus).await);
+
+    // a sender connected to the AirPlay server while its session is playing
+    let mut sender = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let capture = open_session(Duration::from_millis(500));
+    let consumer = receiver.consumer().clone();
+    tokio::task::spawn_blocking(move || capture.play(&*consumer, Pacing::AsFastAsPossible))
+        .await
+        .unwrap();
+    eventually(|| has_player(&bus)).await;
+
+    let root = RootProxy::new(&client).await.unwrap();
+    assert_eq!(root.identity().await.unwrap(), "mpris-test");
+    let player = PlayerProxy::new(&client).await.unwrap();
+    assert_eq!(player.playback_status().await.unwrap(), "Playing");
+    assert!(player.can_control().await.unwrap());
+
+    // desktop volume controls the output gain and unmutes
+    receiver.consumer().set_muted(true);
+    player.set_volume(0.25).await.unwrap();
+    let status = receiver.status().consumer;
+    assert_eq!(status.volume, 0.25);
+    assert!(!status.muted);
+
+    // changes from elsewhere reach the cached property through PropertiesChanged
+    receiver.consumer().set_volume(0.75);
+    eventually(|| async { player.cached_volume().unwrap() == Some(0.75) }).await;
+    receiver.consumer().set_muted(true);
+    eventually(|| async { player.cached_volume().unwrap() == Some(0.0) }).await;
+
+    // Stop drops the sender, which ends the session and releases the name
+    player.stop().await.unwrap();
+    assert!(
+        disconnected(&mut sender).await,
+        "the sender is still connected"
+    );
+    eventually(|| async { !has_player(&bus).await }).await;
+    assert!(receiver.status().consumer.session.is_none());
+    receiver.stop().await.unwrap();
+}
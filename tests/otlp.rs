@@ -0,0 +1,72 @@
+//! Span export to a local stand-in for an OpenTelemetry collector, which
+//! accepts OTLP/HTTP requests and keeps their protobuf bodies.
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{body::Bytes, http::StatusCode, routing::post, Router};
+use common::{short_session, NullSink};
+use kircast_desktop::{
+    airplay::{Pacing, VideoConsumer},
+    log_conf::{init_tracing, LogOptions, OtlpOptions},
+};
+use tokio::{net::TcpListener, sync::mpsc};
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn exports_session_spans() {
+    let (exported_tx, mut exported) = mpsc::unbounded_channel();
+    let collector = Router::new().route(
+        "/v1/traces",
+        post(move |body: Bytes| {
+            let _ = exported_tx.send(body);
+            async { StatusCode::OK }
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, collector).await });
+
+    let log_guard = init_tracing(LogOptions {
+        directives: "kircast_desktop=info".to_string(),
+        otlp: Some(OtlpOptions {
+            endpoint: format!("http://{addr}/v1/traces"),
+            service_name: "kircast-test".to_string(),
+        }),
+        ..Default::default()
+    });
+    let capture = short_session(Duration::from_millis(500));
+    let consumer = VideoConsumer::builder()
+        .video_sink(Arc::new(NullSink))
+        .audio_sink(Arc::new(NullSink))
+        .build();
+    // spans end once the session's threads exit, dropping the guard flushes them
+    tokio::task::spawn_blocking(move || {
+        capture.play(&consumer, Pacing::AsFastAsPossible);
+        assert!(consumer.shutdown(Duration::from_secs(10)));
+        drop(log_guard);
+    })
+    .await
+    .unwrap();
+
+    let mut body = Vec::new();
+    while let Ok(request) = exported.try_recv() {
+        body.extend_from_slice(&request);
+    }
+    for name in [
+        "kircast-test",
+        "session",
+        "video_format",
+        "audio_format",
+        "video_decoder",
+        "audio_decoder",
+    ] {
+        assert!(contains(&body, name), "{name} not exported");
+    }
+}
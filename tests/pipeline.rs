@@ -0,0 +1,590 @@
+//! End-to-end tests of the decoding pipeline, driven by generated sessions and
+//! observed through sinks, so they need neither a sender device, a display nor
+//! audio hardware.
+
+mod common;
+
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use common::{short_session, temp_dir, NullSink};
+use ffmpeg_next as ffmpeg;
+use kircast_desktop::{
+    airplay::{
+        AudioCodec, AudioSink, AudioStreamFormat, Capture, CaptureEvent, CaptureOptions, MediaKind,
+        Pacing, ReplayOptions, SessionEventKind, StreamConsumer, SyntheticStream, VideoConsumer,
+        VideoSegment, VideoSink, VideoStreamFormat,
+    },
+    Error,
+};
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Recorded {
+    frames: usize,
+    sizes: BTreeSet<(u32, u32)>,
+    pcm_samples: usize,
+    sample_rate: u32,
+    channels: u16,
+    peak: i16,
+    ended: usize,
+}
+
+/// Video and audio sink recording what reached them.
+#[derive(Default)]
+struct RecordingSink {
+    recorded: Mutex<Recorded>,
+    ended: Condvar,
+}
+
+impl RecordingSink {
+    /// Waits until `threads` decoder threads have exited.
+    fn wait_for_end(&self, threads: usize) -> std::sync::MutexGuard<'_, Recorded> {
+        let (recorded, timeout) = self
+            .ended
+            .wait_timeout_while(self.recorded.lock().unwrap(), SHUTDOWN_TIMEOUT, |r| {
+                r.ended < threads
+            })
+            .unwrap();
+        assert!(
+            !timeout.timed_out(),
+            "{} of {threads} threads exited",
+            recorded.ended
+        );
+        recorded
+    }
+
+    fn end(&self) {
+        self.recorded.lock().unwrap().ended += 1;
+        self.ended.notify_all();
+    }
+}
+
+impl VideoSink for RecordingSink {
+    fn on_frame(&self, frame: &ffmpeg::frame::Video) {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.frames += 1;
+        recorded.sizes.insert((frame.width(), frame.height()));
+    }
+
+    fn on_end(&self) {
+        self.end();
+    }
+}
+
+impl AudioSink for RecordingSink {
+    fn on_pcm(&self, samples: &[i16], sample_rate: u32, channels: u16) {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.pcm_samples += samples.len() / channels as usize;
+        recorded.sample_rate = sample_rate;
+        recorded.channels = channels;
+        let peak = samples
+            .iter()
+            .map(|s| s.saturating_abs())
+            .max()
+            .unwrap_or(0);
+        recorded.peak = recorded.peak.max(peak);
+    }
+
+    fn on_end(&self) {
+        self.end();
+    }
+}
+
+/// Panics once at the `panic_at`th frame or PCM block, then forwards to `sink`.
+struct FaultySink {
+    sink: Arc<RecordingSink>,
+    panic_at: usize,
+    seen: AtomicUsize,
+}
+
+impl FaultySink {
+    fn new(sink: Arc<RecordingSink>, panic_at: usize) -> Arc<Self> {
+        Arc::new(Self {
+            sink,
+            panic_at,
+            seen: AtomicUsize::new(0),
+        })
+    }
+
+    fn fault(&self) {
+        if self.seen.fetch_add(1, Ordering::Relaxed) == self.panic_at {
+            panic!("injected fault");
+        }
+    }
+}
+
+impl VideoSink for FaultySink {
+    fn on_frame(&self, frame: &ffmpeg::frame::Video) {
+        self.fault();
+        self.sink.on_frame(frame);
+    }
+
+    fn on_end(&self) {
+        VideoSink::on_end(self.sink.as_ref());
+    }
+}
+
+impl AudioSink for FaultySink {
+    fn on_pcm(&self, samples: &[i16], sample_rate: u32, channels: u16) {
+        self.fault();
+        self.sink.on_pcm(samples, sample_rate, channels);
+    }
+
+    fn on_end(&self) {
+        AudioSink::on_end(self.sink.as_ref());
+    }
+}
+
+fn headless_consumer() -> (VideoConsumer, Arc<RecordingSink>, Arc<RecordingSink>) {
+    let video = Arc::new(RecordingSink::default());
+    let audio = Arc::new(RecordingSink::default());
+    let consumer = VideoConsumer::builder()
+        .video_sink(video.clone())
+        .audio_sink(audio.clone())
+        .build();
+    (consumer, video, audio)
+}
+
+/// An audio output that is never available, like a machine without a sound card.
+struct NoAudioDevice {
+    pcm: AtomicUsize,
+}
+
+impl AudioSink for NoAudioDevice {
+    fn on_start(&self) -> kircast_desktop::Result<()> {
+        Err(Error::Device("no default output device".to_string()))
+    }
+
+    fn on_pcm(&self, _samples: &[i16], _sample_rate: u32, _channels: u16) {
+        self.pcm.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn generate(stream: SyntheticStream) -> Capture {
+    stream.generate().expect("generate synthetic session")
+}
+
+#[test]
+fn decodes_every_frame_across_rotation() {
+    let landscape = VideoSegment::new(640, 360, Duration::from_secs(1));
+    let capture = generate(SyntheticStream {
+        segments: vec![landscape, landscape.rotated()],
+        audio: None,
+        ..Default::default()
+    });
+    let (consumer, video, _) = headless_consumer();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+
+    let recorded = video.wait_for_end(1);
+    assert_eq!(recorded.frames, 60);
+    assert_eq!(
+        recorded.sizes,
+        BTreeSet::from([(360, 640), (640, 360)]),
+        "both orientations decoded"
+    );
+}
+
+#[test]
+fn aac_duration_and_volume() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(2))],
+        audio: Some(AudioCodec::AacLc),
+        // -15 dB maps to half amplitude, the tone itself peaks at half scale
+        volume: Some(-15.0),
+        ..Default::default()
+    });
+    let (consumer, _, audio) = headless_consumer();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+
+    let recorded = audio.wait_for_end(1);
+    assert_eq!((recorded.sample_rate, recorded.channels), (44100, 2));
+    let seconds = recorded.pcm_samples as f64 / recorded.sample_rate as f64;
+    assert!(
+        (1.9..=2.1).contains(&seconds),
+        "decoded {seconds}s of audio"
+    );
+    let expected_peak = i16::MAX as f64 * 0.25;
+    let peak = recorded.peak as f64;
+    assert!(
+        (expected_peak * 0.8..=expected_peak * 1.2).contains(&peak),
+        "peak {peak}, expected about {expected_peak}"
+    );
+}
+
+#[test]
+fn alac_session() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(1))],
+        audio: Some(AudioCodec::Alac),
+        volume: Some(0.0),
+        ..Default::default()
+    });
+    let (consumer, video, audio) = headless_consumer();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+
+    let recorded = audio.wait_for_end(1);
+    let seconds = recorded.pcm_samples as f64 / recorded.sample_rate as f64;
+    assert!(
+        (0.9..=1.1).contains(&seconds),
+        "decoded {seconds}s of audio"
+    );
+    // ALAC is lossless, at full volume the tone keeps its half scale peak
+    assert!((i16::MAX / 2 - 2..=i16::MAX / 2 + 2).contains(&recorded.peak));
+    assert_eq!(video.wait_for_end(1).frames, 30);
+}
+
+#[test]
+fn counts_received_video_for_bitrate() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(1))],
+        audio: None,
+        ..Default::default()
+    });
+    let (packets, bytes) = capture
+        .events
+        .iter()
+        .filter_map(|(_, event)| match event {
+            CaptureEvent::Video(packet) => Some(packet.len() as u64),
+            _ => None,
+        })
+        .fold((0, 0), |(packets, bytes), len| (packets + 1, bytes + len));
+    let (consumer, video, _) = headless_consumer();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+    video.wait_for_end(1);
+
+    let stats = consumer.status().stats;
+    assert_eq!(stats.video_packets, packets);
+    assert_eq!(stats.video_bytes, bytes);
+    assert!(bytes > 0);
+}
+
+/// Whether an Annex-B access unit contains an IDR slice.
+fn is_idr(packet: &[u8]) -> bool {
+    packet
+        .windows(4)
+        .any(|window| window[..3] == [0, 0, 1] && window[3] & 0x1f == 5)
+}
+
+/// Like mirroring: one IDR, then only P-frames for a long time, then a new IDR
+/// and a few more P-frames if `idr_again`.
+fn mirroring(idr_again: bool) -> Capture {
+    let generated = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(2))],
+        audio: None,
+        ..Default::default()
+    });
+    let packets: Vec<_> = generated
+        .events
+        .iter()
+        .filter_map(|(_, event)| match event {
+            CaptureEvent::Video(packet) => Some(packet.clone()),
+            _ => None,
+        })
+        .collect();
+    let idr = packets[0].clone();
+    assert!(is_idr(&idr));
+    let inter: Vec<_> = packets.into_iter().filter(|p| !is_idr(p)).collect();
+
+    let mut stream = vec![
+        CaptureEvent::VideoFormat(VideoStreamFormat::default()),
+        CaptureEvent::Video(idr.clone()),
+    ];
+    stream.extend((0..40).flat_map(|_| inter.iter().cloned().map(CaptureEvent::Video)));
+    if idr_again {
+        stream.push(CaptureEvent::Video(idr));
+        stream.extend(inter.iter().take(10).cloned().map(CaptureEvent::Video));
+    }
+    stream.push(CaptureEvent::VideoDisconnect);
+    Capture {
+        events: stream
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| (Duration::from_millis(index as u64 * 33), event))
+            .collect(),
+    }
+}
+
+fn replay_consumer(max_bytes: u64, dir: PathBuf) -> (VideoConsumer, Arc<RecordingSink>) {
+    let video = Arc::new(RecordingSink::default());
+    let consumer = VideoConsumer::builder()
+        .video_sink(video.clone())
+        .instant_replay(ReplayOptions {
+            duration: Duration::from_secs(3600),
+            max_bytes,
+            dir,
+        })
+        .build();
+    (consumer, video)
+}
+
+#[test]
+fn replay_buffer_stays_bounded_without_keyframes() {
+    let max_bytes = 64 * 1024;
+    let (consumer, video) = replay_consumer(max_bytes, PathBuf::from("replays"));
+    mirroring(true).play(&consumer, Pacing::AsFastAsPossible);
+    video.wait_for_end(1);
+
+    let stats = consumer.status().stats;
+    assert!(
+        stats.video_bytes > max_bytes * 10,
+        "{} bytes",
+        stats.video_bytes
+    );
+    // the buffer restarted at the last IDR and holds it with the frames after it
+    assert!(
+        (1..=max_bytes).contains(&stats.replay_bytes),
+        "{} bytes buffered",
+        stats.replay_bytes
+    );
+    assert!(consumer.metrics().contains(&format!(
+        "kircast_replay_buffer_bytes {}",
+        stats.replay_bytes
+    )));
+}
+
+#[test]
+fn replay_saves_video_before_the_next_keyframe() {
+    let dir = temp_dir("replay-overflow");
+    let max_bytes = 64 * 1024;
+    let (consumer, video) = replay_consumer(max_bytes, dir.clone());
+    mirroring(false).play(&consumer, Pacing::AsFastAsPossible);
+    video.wait_for_end(1);
+    assert!(consumer.status().stats.replay_bytes <= max_bytes);
+
+    // the only IDR is kept with the newest frames, saved twice in a row
+    let first = consumer.save_replay().unwrap();
+    let second = consumer.save_replay().unwrap();
+    assert_ne!(first, second);
+    let mut input = ffmpeg::format::input(&first).unwrap();
+    let stream = input.streams().best(ffmpeg::media::Type::Video).unwrap();
+    let index = stream.index();
+    let video_packets: Vec<bool> = input
+        .packets()
+        .filter(|(stream, _)| stream.index() == index)
+        .map(|(_, packet)| packet.is_key())
+        .collect();
+    assert!(video_packets.len() > 10, "{} packets", video_packets.len());
+    assert!(video_packets[0], "starts at the keyframe");
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn capture_replays_stream_formats() {
+    let mut session = short_session(Duration::from_millis(500));
+    let format = VideoStreamFormat {
+        connection_id: "6149398412467593029".to_string(),
+    };
+    session.events[0].1 = CaptureEvent::VideoFormat(format.clone());
+    let dir = temp_dir("capture");
+    let consumer = VideoConsumer::builder()
+        .video_sink(Arc::new(NullSink))
+        .audio_sink(Arc::new(NullSink))
+        .capture(CaptureOptions {
+            dir: dir.clone(),
+            ..Default::default()
+        })
+        .build();
+    session.play(&consumer, Pacing::AsFastAsPossible);
+    assert!(consumer.shutdown(SHUTDOWN_TIMEOUT));
+
+    let sessions: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(sessions.len(), 1);
+    let captured = Capture::open(sessions[0].as_ref().unwrap().path()).unwrap();
+    let formats = |capture: &Capture| -> Vec<String> {
+        capture
+            .events
+            .iter()
+            .filter_map(|(_, event)| match event {
+                CaptureEvent::VideoFormat(format) => Some(format!("video {format}")),
+                CaptureEvent::AudioFormat(format) => Some(format!("audio {format}")),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(formats(&captured), formats(&session));
+    assert!(formats(&captured).contains(&format!("video {}", format.connection_id)));
+    let video = |capture: &Capture| {
+        capture
+            .events
+            .iter()
+            .filter(|(_, event)| matches!(event, CaptureEvent::Video(_)))
+            .count()
+    };
+    assert_eq!(video(&captured), video(&session));
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn reconnect_starts_fresh_threads() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_millis(500))],
+        ..Default::default()
+    });
+    let (consumer, video, audio) = headless_consumer();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+    video.wait_for_end(1);
+    audio.wait_for_end(1);
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+
+    assert_eq!(video.wait_for_end(2).frames, 30);
+    assert!(audio.wait_for_end(2).pcm_samples > 0);
+}
+
+#[test]
+fn survives_packet_loss_and_jitter() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(1))],
+        packet_loss: 0.1,
+        jitter: Duration::from_millis(40),
+        seed: 7,
+        ..Default::default()
+    });
+    let (consumer, video, audio) = headless_consumer();
+    capture.play(&consumer, Pacing::Original);
+
+    assert!(video.wait_for_end(1).frames > 0);
+    assert!(audio.wait_for_end(1).pcm_samples > 0);
+}
+
+#[test]
+fn session_events_in_order() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_millis(500))],
+        volume: Some(-6.0),
+        ..Default::default()
+    });
+    let (consumer, video, audio) = headless_consumer();
+    let mut events = consumer.subscribe();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+    video.wait_for_end(1);
+    audio.wait_for_end(1);
+
+    let mut kinds = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        assert_eq!(event.session, 1);
+        kinds.push(event.kind);
+    }
+    assert_eq!(kinds.first(), Some(&SessionEventKind::Started));
+    // decoder threads may still report frames after the sender disconnected
+    assert!(kinds.contains(&SessionEventKind::Ended));
+    assert!(kinds.contains(&SessionEventKind::VideoFormat(VideoStreamFormat::default())));
+    assert!(kinds.contains(&SessionEventKind::VolumeChanged(-6.0)));
+    assert!(kinds.contains(&SessionEventKind::ResolutionChanged {
+        width: 320,
+        height: 240
+    }));
+    assert_eq!(consumer.session_id(), 1);
+}
+
+#[test]
+fn resolution_is_reset_for_the_next_session() {
+    let (consumer, video, audio) = headless_consumer();
+    short_session(Duration::from_millis(300)).play(&consumer, Pacing::AsFastAsPossible);
+    video.wait_for_end(1);
+    audio.wait_for_end(1);
+
+    // an audio-only session decodes no picture
+    consumer.audio_format(AudioStreamFormat {
+        codec: AudioCodec::AacLc,
+        sample_rate: 44100,
+        channels: 2,
+        samples_per_frame: 1024,
+    });
+    let session = consumer.status().session.expect("session running");
+    assert_eq!(session.id, 2);
+    assert_eq!(session.resolution, None);
+    consumer.audio_disconnect();
+    assert!(consumer.shutdown(SHUTDOWN_TIMEOUT));
+}
+
+#[test]
+fn plays_video_without_audio_device() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(1))],
+        audio: Some(AudioCodec::AacLc),
+        ..Default::default()
+    });
+    let video = Arc::new(RecordingSink::default());
+    let audio = Arc::new(NoAudioDevice {
+        pcm: AtomicUsize::new(0),
+    });
+    let consumer = VideoConsumer::builder()
+        .video_sink(video.clone())
+        .audio_sink(audio.clone())
+        .build();
+    let mut events = consumer.subscribe();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+
+    assert_eq!(
+        video.wait_for_end(1).frames,
+        30,
+        "every frame without audio"
+    );
+    assert_eq!(audio.pcm.load(Ordering::Relaxed), 0);
+    let mut kinds = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        kinds.push(event.kind);
+    }
+    assert!(kinds.contains(&SessionEventKind::AudioUnavailable {
+        message: "audio device error: no default output device".to_string()
+    }));
+    assert!(
+        !kinds
+            .iter()
+            .any(|kind| matches!(kind, SessionEventKind::DecoderError { .. })),
+        "{kinds:?}"
+    );
+    assert!(consumer.shutdown(SHUTDOWN_TIMEOUT));
+}
+
+#[test]
+fn pipelines_restart_after_panic() {
+    let capture = generate(SyntheticStream {
+        segments: vec![VideoSegment::new(320, 240, Duration::from_secs(2))],
+        audio: Some(AudioCodec::AacLc),
+        ..Default::default()
+    });
+    let video = Arc::new(RecordingSink::default());
+    let audio = Arc::new(RecordingSink::default());
+    let consumer = VideoConsumer::builder()
+        .video_sink(FaultySink::new(video.clone(), 5))
+        .audio_sink(FaultySink::new(audio.clone(), 3))
+        .build();
+    let mut events = consumer.subscribe();
+    capture.play(&consumer, Pacing::AsFastAsPossible);
+
+    // the rebuilt decoder picks up again at the latest at the next keyframe
+    let frames = video.wait_for_end(1).frames;
+    assert!(frames >= 35, "{frames} frames after the restart");
+    let recorded = audio.wait_for_end(1);
+    let seconds = recorded.pcm_samples as f64 / recorded.sample_rate as f64;
+    assert!(seconds > 1.5, "decoded {seconds}s of audio");
+
+    let mut failed = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        if let SessionEventKind::PipelineFailed {
+            media,
+            message,
+            restart,
+        } = event.kind
+        {
+            assert!(message.contains("injected fault"), "{message}");
+            assert!(restart);
+            failed.push(media);
+        }
+    }
+    failed.sort_by_key(|media| *media == MediaKind::Audio);
+    assert_eq!(failed, [MediaKind::Video, MediaKind::Audio]);
+    assert!(consumer
+        .metrics()
+        .contains("kircast_decoder_restarts_total{media=\"video\"} 1"));
+}
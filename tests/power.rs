@@ -0,0 +1,132 @@
+//! Display inhibition against mock screensaver and logind services on a private
+//! dbus-daemon, which has to be installed.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read},
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+};
+
+use common::DbusDaemon;
+use kircast_desktop::power::{PowerInhibitor, PowerOptions};
+use zbus::{blocking::connection, zvariant::OwnedFd};
+
+const COOKIE: u32 = 42;
+
+#[derive(Clone, Default)]
+struct ScreenSaver {
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+#[zbus::interface(name = "org.freedesktop.ScreenSaver")]
+impl ScreenSaver {
+    fn inhibit(&self, application_name: String, reason: String) -> u32 {
+        let call = format!("Inhibit {application_name} {reason}");
+        self.calls.lock().unwrap().push(call);
+        COOKIE
+    }
+
+    fn un_inhibit(&self, cookie: u32) {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("UnInhibit {cookie}"));
+    }
+}
+
+/// Hands out one end of a socket pair, the other end sees EOF once it is closed.
+#[derive(Clone, Default)]
+struct LoginManager {
+    calls: Arc<Mutex<Vec<String>>>,
+    peer: Arc<Mutex<Option<UnixStream>>>,
+}
+
+#[zbus::interface(name = "org.freedesktop.login1.Manager")]
+impl LoginManager {
+    fn inhibit(&self, what: String, who: String, why: String, mode: String) -> OwnedFd {
+        let call = format!("Inhibit {what} {who} {why} {mode}");
+        self.calls.lock().unwrap().push(call);
+        let (fd, peer) = UnixStream::pair().unwrap();
+        peer.set_nonblocking(true).unwrap();
+        *self.peer.lock().unwrap() = Some(peer);
+        std::os::fd::OwnedFd::from(fd).into()
+    }
+}
+
+impl LoginManager {
+    /// Whether the inhibitor still holds the descriptor.
+    fn held(&self) -> bool {
+        let mut peer = self.peer.lock().unwrap();
+        let mut buf = [0; 1];
+        match peer.as_mut().unwrap().read(&mut buf) {
+            Ok(0) => false,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => true,
+            other => panic!("unexpected read {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn inhibits_screensaver_and_logind() {
+    let daemon = DbusDaemon::start();
+    let screensaver = ScreenSaver::default();
+    let _screensaver = connection::Builder::address(daemon.address.as_str())
+        .unwrap()
+        .name("org.freedesktop.ScreenSaver")
+        .unwrap()
+        .serve_at("/org/freedesktop/ScreenSaver", screensaver.clone())
+        .unwrap()
+        .build()
+        .unwrap();
+    let login = LoginManager::default();
+    let _login = connection::Builder::address(daemon.address.as_str())
+        .unwrap()
+        .name("org.freedesktop.login1")
+        .unwrap()
+        .serve_at("/org/freedesktop/login1", login.clone())
+        .unwrap()
+        .build()
+        .unwrap();
+
+    let inhibitor = PowerInhibitor::new(PowerOptions {
+        app_name: "kircast-test".to_string(),
+        session_bus: Some(daemon.address.clone()),
+        system_bus: Some(daemon.address.clone()),
+    });
+    inhibitor.inhibit("presenting");
+    inhibitor.inhibit("presenting");
+    inhibitor.flush();
+    assert!(inhibitor.is_inhibited());
+    assert_eq!(
+        *screensaver.calls.lock().unwrap(),
+        ["Inhibit kircast-test presenting"]
+    );
+    assert_eq!(
+        *login.calls.lock().unwrap(),
+        ["Inhibit idle:sleep kircast-test presenting block"]
+    );
+    assert!(login.held());
+
+    inhibitor.release();
+    inhibitor.flush();
+    assert!(!inhibitor.is_inhibited());
+    assert_eq!(
+        screensaver.calls.lock().unwrap().last().unwrap(),
+        &format!("UnInhibit {COOKIE}")
+    );
+    assert!(!login.held());
+
+    // a second session takes new inhibitions, dropping releases them
+    inhibitor.inhibit("presenting");
+    inhibitor.flush();
+    assert!(login.held());
+    drop(inhibitor);
+    assert!(!login.held());
+    assert_eq!(
+        screensaver.calls.lock().unwrap().last().unwrap(),
+        &format!("UnInhibit {COOKIE}")
+    );
+}
@@ -0,0 +1,104 @@
+//! Slide detection on synthetic frame sequences, fed as luma planes through
+//! `luma_thumbnail` the way the decoder thread does.
+
+use std::time::Duration;
+
+use kircast_desktop::airplay::{luma_thumbnail, SlideCaptureOptions, SlideDetector};
+
+const WIDTH: usize = 320;
+const HEIGHT: usize = 180;
+/// Rows are padded like decoder output.
+const STRIDE: usize = 384;
+
+/// A luma plane of a slide: `shade` background with a bright box at `slide`'s position.
+fn plane(shade: u8, slide: usize) -> Vec<u8> {
+    let mut luma = vec![shade; STRIDE * HEIGHT];
+    let x0 = 20 + slide * 60;
+    for row in luma.chunks_mut(STRIDE).skip(40).take(60) {
+        row[x0..x0 + 50].fill(235);
+    }
+    luma
+}
+
+fn thumb(luma: &[u8]) -> Vec<u8> {
+    luma_thumbnail(luma, STRIDE, WIDTH, HEIGHT)
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn detector() -> SlideDetector {
+    SlideDetector::new(&SlideCaptureOptions::default())
+}
+
+/// Pushes `thumb` every 200 ms in `from..to` and returns when slides completed.
+fn show(detector: &mut SlideDetector, thumb: &[u8], from: u64, to: u64) -> Vec<u64> {
+    (from..to)
+        .step_by(200)
+        .filter(|at| detector.push(ms(*at), thumb))
+        .collect()
+}
+
+#[test]
+fn thumbnail_averages_blocks() {
+    let thumb = thumb(&vec![100; STRIDE * HEIGHT]);
+    assert_eq!(thumb.len(), 64 * 36);
+    assert!(thumb.iter().all(|luma| *luma == 100));
+
+    // padding beyond the width is ignored
+    let mut padded = vec![50; STRIDE * HEIGHT];
+    for row in padded.chunks_mut(STRIDE) {
+        row[WIDTH..].fill(255);
+    }
+    assert!(luma_thumbnail(&padded, STRIDE, WIDTH, HEIGHT)
+        .iter()
+        .all(|luma| *luma == 50));
+}
+
+#[test]
+fn scene_change_then_stability() {
+    let mut detector = detector();
+    let first = thumb(&plane(16, 0));
+    let second = thumb(&plane(16, 2));
+
+    // the first slide completes once it was unchanged for `stable_for`
+    assert_eq!(show(&mut detector, &first, 0, 3000), [1600]);
+    // sensor noise below `noise_threshold` neither resets nor repeats it
+    let noisy = thumb(&plane(17, 0));
+    assert_eq!(show(&mut detector, &noisy, 3000, 5000), [] as [u64; 0]);
+
+    // a transition keeps changing, nothing is captured until it settles
+    for (i, at) in (5000..6000).step_by(200).enumerate() {
+        let fading = thumb(&plane(16 + 30 * i as u8, 1));
+        assert!(!detector.push(ms(at), &fading));
+    }
+    assert_eq!(show(&mut detector, &second, 6000, 9000), [7600]);
+
+    // going back to an earlier picture that barely differs is no new slide
+    let mut detector = self::detector();
+    assert_eq!(show(&mut detector, &first, 0, 2000), [1600]);
+    let dimmed = thumb(&plane(20, 0));
+    assert_eq!(show(&mut detector, &dimmed, 2000, 5000), [] as [u64; 0]);
+}
+
+#[test]
+fn slide_held_without_frames() {
+    let mut detector = detector();
+    let first = thumb(&plane(16, 0));
+    let second = thumb(&plane(16, 1));
+    let third = thumb(&plane(16, 2));
+
+    // a still picture sends a single frame, the next change completes it
+    assert!(!detector.push(ms(0), &first));
+    assert!(detector.settle(ms(4000)));
+    assert!(!detector.push(ms(4000), &second));
+    // a change too soon after the last one is a transition
+    assert!(!detector.settle(ms(4500)));
+    assert!(!detector.push(ms(4500), &third));
+
+    // the last slide is held until the session ends
+    assert!(!detector.settle(ms(5000)));
+    assert!(detector.settle(ms(60_000)));
+    assert!(!detector.settle(ms(70_000)), "captured once");
+}